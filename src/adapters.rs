@@ -0,0 +1,227 @@
+//! Adapters between [`CommandRunner`] and [`Env`], so code written against either can be
+//! combined.
+//!
+//! The two traits overlap, but differ in a few ways:
+//! - [`Env::execute`] takes a slice of `OsStr`s and has no working directory or options,
+//!   while [`CommandRunner`] takes a [`CommandLine`], a working directory, and [`CommandOpts`].
+//! - [`CommandLine`] only holds UTF-8, so arguments that are not valid UTF-8 cannot be passed
+//!   through [`AsEnv`].
+//! - Neither [`Env::execute`] nor [`CommandRunner::run`] fail just because the command exited
+//!   unsuccessfully.
+
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::path::Path;
+
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::Env;
+use crate::ExecutionResult;
+
+/// Use a [`CommandRunner`] where an [`Env`] is expected.
+///
+/// Every command is run in `cwd` with the default [`CommandOpts`], via [`CommandRunner::run`].
+#[derive(Debug)]
+pub struct AsEnv<R: CommandRunner> {
+    inner: R,
+    cwd: AbsolutePathBuf,
+}
+
+impl<R: CommandRunner> AsEnv<R> {
+    pub fn new<P: Into<AbsolutePathBuf>>(inner: R, cwd: P) -> Self {
+        Self {
+            inner,
+            cwd: cwd.into(),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+/// Convert `cmd` for a [`CommandRunner`], failing if any argument is not UTF-8.
+fn to_command_line<T: AsRef<OsStr>>(cmd: &[T]) -> anyhow::Result<CommandLine> {
+    let args = cmd
+        .iter()
+        .map(|arg| {
+            let arg = arg.as_ref();
+            arg.to_str()
+                .ok_or_else(|| anyhow::anyhow!("Argument `{:?}` is not valid UTF-8", arg))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(CommandLine::from(args))
+}
+
+impl<R: CommandRunner> Env for AsEnv<R> {
+    fn execute<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<ExecutionResult> {
+        self.inner.run(to_command_line(cmd)?, &self.cwd)
+    }
+
+    fn exec<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<()> {
+        self.inner.exec(to_command_line(cmd)?)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+}
+
+/// Use an [`Env`] where a [`CommandRunner`] is expected.
+///
+/// [`Env`] has no notion of a working directory or of options, so this only runs commands in
+/// the current directory of this process, and fails for options that would change what the
+/// command sees, i.e. [`CommandOpts::stdin`] and [`CommandOpts::env`]. Other options are
+/// ignored, and output is captured however the [`Env`] captures it.
+#[derive(Debug)]
+pub struct AsCommandRunner<E: Env> {
+    inner: E,
+}
+
+impl<E: Env> AsCommandRunner<E> {
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+}
+
+/// Fail if `command_line` cannot be run in `cwd` with `opts` through an [`Env`].
+fn check_supported(
+    command_line: &CommandLine,
+    cwd: &AbsolutePath,
+    opts: &CommandOpts,
+) -> anyhow::Result<()> {
+    let cwd: &Path = cwd.as_ref();
+    if cwd != std::env::current_dir()? {
+        return Err(anyhow::anyhow!(
+            "Cannot run `{}` in `{}`, as an Env only runs commands in the current directory",
+            command_line,
+            cwd.display()
+        ));
+    }
+    if opts.stdin.is_some() || opts.stdin_file.is_some() || !opts.env.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Cannot run `{}` with stdin or extra environment variables through an Env",
+            command_line
+        ));
+    }
+    Ok(())
+}
+
+impl<E: Env + Debug + Send + Sync> CommandRunner for AsCommandRunner<E> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        check_supported(&command_line, cwd, &opts)?;
+        let args: &[String] = &command_line;
+        self.inner.execute(args)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        check_supported(&command_line, cwd, &opts)?;
+        let args: &[String] = &command_line;
+        self.inner.exec(args)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use maplit::hashmap;
+    use paths::AbsolutePathBuf;
+
+    use crate::test::Invocation;
+    use crate::test::TestCommandRunner;
+    use crate::AsCommandRunner;
+    use crate::AsEnv;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::Env;
+
+    #[test]
+    fn round_trips() -> anyhow::Result<()> {
+        let cwd = AbsolutePathBuf::current_dir();
+        let inner =
+            TestCommandRunner::with_results([(0, "first"), (0, "second")])?.with_var("FOO", "bar");
+        let env = AsEnv::new(inner, cwd.clone());
+        assert_eq!("first", env.execute(&["git", "status"])?.stdout()?);
+        assert_eq!("local.example.com", env.hostname()?);
+        assert_eq!("user", env.username()?);
+        assert_eq!(Some("bar".to_owned()), env.var("FOO"));
+
+        let runner = AsCommandRunner::new(env);
+        assert_eq!("second", runner.run(["ls"], &cwd)?.stdout()?);
+        assert_eq!(Some("bar".to_owned()), runner.var("FOO"));
+        assert_eq!("user", runner.username()?);
+        assert!(runner.run(["ls"], AbsolutePathBuf::try_new("/")?).is_err());
+        let opts = CommandOpts {
+            env: hashmap! {"FOO".to_owned() => "bar".to_owned()},
+            ..Default::default()
+        };
+        assert!(runner.run_with_opts(["ls"], &cwd, opts).is_err());
+
+        let issued = runner.inner().inner().issued_commands.read().unwrap();
+        assert_eq!(
+            vec![
+                Invocation::new(CommandLine::from(["git", "status"]), cwd.clone()),
+                Invocation::new(CommandLine::from(["ls"]), cwd.clone()),
+            ],
+            *issued
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::process::Stdio;
+use std::time::Instant;
+
+use anyhow::Context;
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Child;
+use tokio::process::ChildStdin;
+use tokio::sync::mpsc::Sender;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+use crate::CommandEvent;
+use crate::CommandFailed;
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::DefaultCommandRunner;
+use crate::ExecutionResult;
+use crate::Stream as OutputStream;
+use crate::StreamingOpts;
+
+/// The async equivalent of [`crate::CommandRunner`], for use from async code without
+/// blocking the executor.
+///
+/// It takes the same [`CommandLine`] and [`CommandOpts`], and returns the same
+/// [`ExecutionResult`], so commands can be built the same way for both.
+pub trait AsyncCommandRunner: Debug + Send + Sync {
+    /// Run a command, failing with [`CommandFailed`] if it exits unsuccessfully.
+    fn run_checked<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+    ) -> impl Future<Output = anyhow::Result<ExecutionResult>> + Send {
+        self.run_checked_with_opts(command_line, cwd, CommandOpts::default())
+    }
+
+    /// Like [`Self::run_checked`], but with `opts`.
+    fn run_checked_with_opts<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> impl Future<Output = anyhow::Result<ExecutionResult>> + Send {
+        let command_line = command_line.into();
+        let cwd = AbsolutePathBuf::from(cwd.as_ref());
+        async move {
+            let program_name = command_line.program()?.to_owned();
+            let ret = self.run_with_opts(command_line, cwd, opts).await?;
+            match ret.status.success() {
+                true => Ok(ret),
+                false => Err(CommandFailed::new(program_name, ret).into()),
+            }
+        }
+    }
+
+    fn run<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+    ) -> impl Future<Output = anyhow::Result<ExecutionResult>> + Send {
+        self.run_with_opts(command_line, cwd, CommandOpts::default())
+    }
+
+    fn run_with_opts<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> impl Future<Output = anyhow::Result<ExecutionResult>> + Send {
+        let command_line = command_line.into();
+        let cwd = AbsolutePathBuf::from(cwd.as_ref());
+        async move {
+            let cwd: &AbsolutePath = cwd.as_ref();
+            let program_name = command_line.program()?.to_owned();
+            let label = opts.log_label();
+            let completed_level = opts.log_level(log::Level::Debug);
+            log::log!(
+                opts.log_level(log::Level::Info),
+                "Running {}`{}` in `{}`",
+                label,
+                command_line,
+                cwd
+            );
+            let start = Instant::now();
+            let mut res = self.run_inner(command_line, cwd, opts).await?;
+            if res.duration.is_zero() {
+                res.duration = start.elapsed();
+            }
+            log::log!(
+                completed_level,
+                "Completed {}`{}` with exit status `{}`",
+                label,
+                program_name,
+                res.status
+            );
+            res.program = program_name;
+            Ok(res)
+        }
+    }
+
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> impl Future<Output = anyhow::Result<ExecutionResult>> + Send;
+}
+
+/// Runs commands with [`tokio::process::Command`], with the same environment as a
+/// [`DefaultCommandRunner`] would give them.
+///
+/// The process is set up like [`DefaultCommandRunner`] does, so per-command environment,
+/// groups, merged stderr, and signal forwarding's process group all behave the same. Output
+/// is either captured or passed through to this process, per [`CommandOpts::capture_stdout`]
+/// and [`CommandOpts::capture_stderr`]; captured stderr is not also shown on the terminal.
+/// Options that need output to be read on background threads, like
+/// [`CommandOpts::stdout_capture`], [`CommandOpts::read_throttle`], and
+/// [`CommandOpts::timeout`], are not supported and cause an error, as is
+/// [`CommandOpts::pipe_stdin`], as there is no handle to write to stdin with. If
+/// [`CommandOpts::kill_on_drop`] is set, dropping the future kills the command, though never
+/// the rest of its process group.
+#[derive(Debug, Default)]
+pub struct DefaultAsyncCommandRunner {
+    inner: DefaultCommandRunner,
+}
+
+impl DefaultAsyncCommandRunner {
+    /// Use the environment filtering and other settings of `inner`.
+    pub fn new(inner: DefaultCommandRunner) -> Self {
+        Self { inner }
+    }
+
+    /// The runner whose settings are used.
+    pub fn inner(&self) -> &DefaultCommandRunner {
+        &self.inner
+    }
+}
+
+impl From<DefaultCommandRunner> for DefaultAsyncCommandRunner {
+    fn from(inner: DefaultCommandRunner) -> Self {
+        Self::new(inner)
+    }
+}
+
+impl DefaultAsyncCommandRunner {
+    /// Run a command, and get everything that happens to it as a stream of
+    /// [`CommandEvent`]s, like [`DefaultCommandRunner::run_events`].
+    ///
+    /// `Started` comes first, then chunks of stdout and stderr as they are read, for the
+    /// streams that `opts` captures, and finally `Exited`. The command is driven by a task
+    /// on the current tokio runtime, so this must be called from within one. Dropping the
+    /// stream kills the command.
+    pub fn run_output_stream<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<impl Stream<Item = CommandEvent>> {
+        let command_line = command_line.into();
+        // Nothing can be read from the command once the stream is gone.
+        let opts = CommandOpts {
+            kill_on_drop: true,
+            ..opts
+        };
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Running {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref()
+        );
+        let mut child = self.command(&command_line, cwd.as_ref(), &opts)?.spawn()?;
+        let pid = child.id().unwrap_or_default();
+        if let Some(stdin) = child.stdin.take().zip(opts.stdin) {
+            tokio::spawn(write_stdin(stdin));
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(StreamingOpts::default().channel_capacity);
+        tx.try_send(CommandEvent::Started { pid })
+            .expect("the channel has room");
+        tokio::spawn(drive(
+            command_line,
+            child,
+            tx,
+            opts.log_level(log::Level::Debug),
+        ));
+        Ok(ReceiverStream::new(rx))
+    }
+
+    fn command(
+        &self,
+        command_line: &CommandLine,
+        cwd: &AbsolutePath,
+        opts: &CommandOpts,
+    ) -> anyhow::Result<tokio::process::Command> {
+        if opts.stdout_capture.is_some()
+            || opts.stderr_capture.is_some()
+            || opts.read_throttle.is_some()
+            || opts.timeout.is_some()
+            || opts.capture_on_failure
+            || opts.decompress_stdout.is_some()
+            || opts.max_output_bytes.is_some()
+            || opts.capture_interleaved
+            || opts.pipe_stdin
+        {
+            return Err(anyhow::anyhow!(
+                "`{}` was given options that async commands do not support",
+                command_line
+            ));
+        }
+        self.inner.print_if_verbose(command_line);
+        let mut command =
+            tokio::process::Command::from(self.inner.command(command_line, cwd, opts)?);
+        let stdio = |piped: bool| {
+            if piped {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            }
+        };
+        let stdin = match opts.stdin_file.as_ref() {
+            Some(_) if opts.stdin.is_some() => {
+                return Err(anyhow::anyhow!(
+                    "`stdin_file` cannot be set with `stdin` when running `{}`",
+                    command_line
+                ))
+            }
+            Some(path) => Stdio::from(std::fs::File::open(path).with_context(|| {
+                format!("Failed to open `{}` as stdin for `{}`", path, command_line)
+            })?),
+            None => stdio(opts.stdin.is_some()),
+        };
+        command
+            .stdin(stdin)
+            .stdout(stdio(opts.capture_stdout))
+            .stderr(stdio(opts.capture_stderr && !opts.merge_stderr_into_stdout))
+            .kill_on_drop(opts.kill_on_drop);
+        Ok(command)
+    }
+}
+
+impl AsyncCommandRunner for DefaultAsyncCommandRunner {
+    async fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let mut child = self.command(&command_line, cwd, &opts)?.spawn()?;
+        let stdin = child.stdin.take().zip(opts.stdin);
+        let write_stdin = async move {
+            match stdin {
+                Some(stdin) => write_stdin(stdin).await,
+                None => Ok(()),
+            }
+        };
+        let (_, output) = tokio::try_join!(write_stdin, child.wait_with_output())?;
+        Ok(ExecutionResult {
+            merged: opts.merge_stderr_into_stdout,
+            ..ExecutionResult::from(output)
+        })
+    }
+}
+
+async fn write_stdin((mut pipe, bytes): (ChildStdin, Vec<u8>)) -> std::io::Result<()> {
+    match pipe.write_all(&bytes).await {
+        // The child is allowed to exit without reading all of its input.
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        res => res,
+    }
+}
+
+/// Read from `reader` until it is closed, and then never finish.
+async fn read_some<R: AsyncRead + Unpin>(
+    reader: &mut Option<R>,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    match reader {
+        Some(reader) => reader.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Send the output and exit status of `child` to `tx`, until it exits or `tx` is closed.
+///
+/// Returning early drops `child`, which kills it.
+async fn drive(
+    command_line: CommandLine,
+    mut child: Child,
+    tx: Sender<CommandEvent>,
+    completed_level: log::Level,
+) {
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let mut stdout_buf = vec![0; 8192];
+    let mut stderr_buf = vec![0; 8192];
+    while stdout.is_some() || stderr.is_some() {
+        let (stream, read) = tokio::select! {
+            read = read_some(&mut stdout, &mut stdout_buf) => (OutputStream::Stdout, read),
+            read = read_some(&mut stderr, &mut stderr_buf) => (OutputStream::Stderr, read),
+            _ = tx.closed() => return,
+        };
+        let event = match (stream, read) {
+            (OutputStream::Stdout, Ok(n)) if n > 0 => {
+                CommandEvent::Stdout(stdout_buf[..n].to_vec())
+            }
+            (OutputStream::Stderr, Ok(n)) if n > 0 => {
+                CommandEvent::Stderr(stderr_buf[..n].to_vec())
+            }
+            (stream, read) => {
+                if let Err(e) = read {
+                    log::warn!("Could not read {:?} of `{}`: {}", stream, command_line, e);
+                }
+                match stream {
+                    OutputStream::Stdout => stdout = None,
+                    OutputStream::Stderr => stderr = None,
+                }
+                continue;
+            }
+        };
+        if tx.send(event).await.is_err() {
+            return;
+        }
+    }
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = tx.closed() => return,
+    };
+    match status {
+        Ok(status) => {
+            log::log!(
+                completed_level,
+                "Completed `{}` with exit status `{}`",
+                command_line,
+                status
+            );
+            // A closed receiver is fine, nobody wants to know anymore.
+            let _ = tx.send(CommandEvent::Exited(status)).await;
+        }
+        Err(e) => log::error!("Could not wait for `{}`: {}", command_line, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use maplit::hashmap;
+    use paths::AbsolutePathBuf;
+    use tokio_stream::StreamExt;
+
+    use crate::AsyncCommandRunner;
+    use crate::CommandEvent;
+    use crate::CommandFailed;
+    use crate::CommandOpts;
+    use crate::DefaultAsyncCommandRunner;
+    use crate::DefaultCommandRunner;
+
+    #[tokio::test]
+    async fn runs_commands() -> anyhow::Result<()> {
+        let runner = DefaultAsyncCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let res = runner
+            .run_with_opts(
+                ["/bin/sh", "-c", "cat; echo $FOO >&2"],
+                &cwd,
+                CommandOpts {
+                    stdin: Some(b"input".to_vec()),
+                    env: hashmap! { "FOO".to_owned() => "bar".to_owned() },
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert_eq!("input", res.stdout()?);
+        assert_eq!("bar\n", res.stderr()?);
+
+        let err = runner
+            .run_checked(["/bin/sh", "-c", "exit 3"], &cwd)
+            .await
+            .unwrap_err()
+            .downcast::<CommandFailed>()?;
+        assert_eq!(Some(3), err.result.status.code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_output_stream() -> anyhow::Result<()> {
+        let runner = DefaultAsyncCommandRunner::default();
+        let events: Vec<_> = runner
+            .run_output_stream(
+                [
+                    "/bin/sh",
+                    "-c",
+                    "echo out; sleep 0.05; echo err >&2; exit 3",
+                ],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts::default(),
+            )?
+            .collect()
+            .await;
+
+        assert_eq!(4, events.len(), "{:?}", events);
+        assert!(matches!(events[0], CommandEvent::Started { pid } if pid > 0));
+        assert_eq!(CommandEvent::Stdout(b"out\n".to_vec()), events[1]);
+        assert_eq!(CommandEvent::Stderr(b"err\n".to_vec()), events[2]);
+        assert!(matches!(&events[3], CommandEvent::Exited(status) if status.code() == Some(3)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropping_output_stream_kills_command() -> anyhow::Result<()> {
+        let runner = DefaultAsyncCommandRunner::default();
+        let mut events = runner.run_output_stream(
+            ["sleep", "10"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+        )?;
+        let pid = match events.next().await {
+            Some(CommandEvent::Started { pid }) => pid,
+            event => panic!("Expected the command to start, got {:?}", event),
+        };
+        drop(events);
+
+        let stat = format!("/proc/{}/stat", pid);
+        for _ in 0..100 {
+            match std::fs::read_to_string(&stat) {
+                Ok(stat) if !stat.contains(") Z ") => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                _ => return Ok(()),
+            }
+        }
+        panic!("`sleep` was not killed");
+    }
+
+    #[tokio::test]
+    async fn kills_on_drop() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let pid_file = temp.path().join("pid");
+        let runner = DefaultAsyncCommandRunner::default();
+        let run = runner.run_with_opts(
+            [
+                "/bin/sh".to_owned(),
+                "-c".to_owned(),
+                format!("echo $$ > {}; exec sleep 10", pid_file.display()),
+            ],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                kill_on_drop: true,
+                ..Default::default()
+            },
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(500), run)
+            .await
+            .is_err());
+
+        let stat = format!("/proc/{}/stat", std::fs::read_to_string(pid_file)?.trim());
+        for _ in 0..100 {
+            match std::fs::read_to_string(&stat) {
+                Ok(stat) if !stat.contains(") Z ") => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                _ => return Ok(()),
+            }
+        }
+        panic!("`sleep` was not killed");
+    }
+
+    #[tokio::test]
+    async fn rejects_pipe_stdin() {
+        let runner = DefaultAsyncCommandRunner::default();
+        let opts = CommandOpts {
+            pipe_stdin: true,
+            ..Default::default()
+        };
+        assert!(runner
+            .run_with_opts(["cat"], AbsolutePathBuf::current_dir(), opts)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn filters_env_like_the_sync_runner() -> anyhow::Result<()> {
+        let runner = DefaultAsyncCommandRunner::new(DefaultCommandRunner::ignoring_env(&[
+            "CARGO_MANIFEST_DIR",
+        ]));
+        let res = runner
+            .run_checked(
+                ["/bin/sh", "-c", "echo \"[$CARGO_MANIFEST_DIR]\""],
+                AbsolutePathBuf::current_dir(),
+            )
+            .await?;
+        assert_eq!("[]\n", res.stdout()?);
+        Ok(())
+    }
+}
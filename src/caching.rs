@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+
+type CacheKey = (CommandLine, PathBuf);
+
+/// A [`CommandRunner`] that memoizes the results of commands that are safe to repeat.
+///
+/// Results are keyed on the command line and the working directory, and are reused until
+/// `ttl` has passed since they were stored. Only successful results of commands that
+/// `is_cacheable` returns `true` for are stored. Commands that are given stdin or extra
+/// environment variables are never cached, as those are not part of the key, and neither are
+/// commands whose output is sent to a [`crate::StdioCapture`]. Only mark a
+/// command as cacheable if it has no side effects, e.g. `git rev-parse HEAD`.
+///
+/// Expired entries are replaced the next time their command is run. [`Self::clear`] drops
+/// all entries, e.g. after running a command that would change the cached results.
+///
+/// The cache is behind a lock, so this runner can be shared between threads. The lock is
+/// not held while commands run, so concurrent misses for the same key may each run the
+/// command.
+pub struct CachingCommandRunner<R: CommandRunner> {
+    inner: R,
+    ttl: Duration,
+    is_cacheable: Box<dyn Fn(&CommandLine) -> bool + Send + Sync>,
+    cache: Mutex<HashMap<CacheKey, (Instant, ExecutionResult)>>,
+}
+
+impl<R: CommandRunner> Debug for CachingCommandRunner<R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachingCommandRunner")
+            .field("inner", &self.inner)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: CommandRunner> CachingCommandRunner<R> {
+    pub fn new<F: Fn(&CommandLine) -> bool + Send + Sync + 'static>(
+        inner: R,
+        ttl: Duration,
+        is_cacheable: F,
+    ) -> Self {
+        Self {
+            inner,
+            ttl,
+            is_cacheable: Box::new(is_cacheable),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Drop all cached results.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for CachingCommandRunner<R> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        if opts.stdin.is_some()
+            || opts.stdin_file.is_some()
+            || !opts.env.is_empty()
+            || opts.stdout_capture.is_some()
+            || opts.stderr_capture.is_some()
+            || !(self.is_cacheable)(&command_line)
+        {
+            return self.inner.run_inner(command_line, cwd, opts);
+        }
+
+        let cwd_path: &Path = cwd.as_ref();
+        let key = (command_line, cwd_path.to_path_buf());
+        if let Some((stored_at, res)) = self.cache.lock().unwrap().get(&key) {
+            if stored_at.elapsed() < self.ttl {
+                log::debug!("Using cached result for `{}`", key.0);
+                return Ok(res.clone());
+            }
+        }
+
+        let res = self.inner.run_inner(key.0.clone(), cwd, opts)?;
+        if res.status.success() {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(key, (Instant::now(), res.clone()));
+        }
+        Ok(res)
+    }
+
+    /// Pipelines are never cached, and are run by the inner runner.
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        self.inner.run_pipeline(commands, cwd, opts)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        self.inner.exec_with_opts(command_line, cwd, opts)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.inner.default_cwd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CachingCommandRunner;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::DefaultCommandRunner;
+
+    #[test]
+    fn caches_successful_cacheable_commands() -> anyhow::Result<()> {
+        let inner = TestCommandRunner::with_results([(0, "abc123"), (0, "main"), (0, "main")])?;
+        let runner = CachingCommandRunner::new(inner, Duration::from_secs(60), |cli| {
+            cli.get(1).map(String::as_str) == Some("rev-parse")
+        });
+        let cwd = AbsolutePathBuf::current_dir();
+
+        assert_eq!(
+            "abc123",
+            runner.run(["git", "rev-parse", "HEAD"], &cwd)?.stdout()?
+        );
+        assert_eq!(
+            "abc123",
+            runner.run(["git", "rev-parse", "HEAD"], &cwd)?.stdout()?
+        );
+        assert_eq!("main", runner.run(["git", "branch"], &cwd)?.stdout()?);
+        assert_eq!("main", runner.run(["git", "branch"], &cwd)?.stdout()?);
+
+        assert_eq!(3, runner.inner().issued_commands.read().unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn passes_pipelines_on() -> anyhow::Result<()> {
+        let runner = CachingCommandRunner::new(
+            DefaultCommandRunner::default(),
+            Duration::from_secs(60),
+            |_| true,
+        );
+        // `yes` never exits on its own, so this only finishes if the commands run
+        // concurrently, as they do with the inner runner.
+        let commands = vec![
+            CommandLine::from(["yes"]),
+            CommandLine::from(["head", "-1"]),
+        ];
+        let res = runner.run_pipeline(
+            commands,
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+        )?;
+        assert_eq!("y\n", res.stdout()?);
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_cache_failures_or_expired_results() -> anyhow::Result<()> {
+        let inner = TestCommandRunner::with_results([(1, ""), (0, "1"), (0, "2")])?;
+        let runner = CachingCommandRunner::new(inner, Duration::from_secs(60), |_| true);
+        let cwd = AbsolutePathBuf::current_dir();
+
+        assert!(!runner.run(["true"], &cwd)?.status.success());
+        assert_eq!("1", runner.run(["true"], &cwd)?.stdout()?);
+        assert_eq!("1", runner.run(["true"], &cwd)?.stdout()?);
+        runner.clear();
+        assert_eq!("2", runner.run(["true"], &cwd)?.stdout()?);
+
+        let inner = TestCommandRunner::with_results([(0, "1"), (0, "2"), (0, "3")])?;
+        let runner = CachingCommandRunner::new(inner, Duration::ZERO, |_| true);
+        assert_eq!("1", runner.run(["true"], &cwd)?.stdout()?);
+        assert_eq!("2", runner.run(["true"], &cwd)?.stdout()?);
+        let opts = CommandOpts {
+            stdin: Some(vec![]),
+            ..Default::default()
+        };
+        assert_eq!("3", runner.run_with_opts(["true"], &cwd, opts)?.stdout()?);
+        Ok(())
+    }
+}
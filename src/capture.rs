@@ -0,0 +1,370 @@
+//! Helpers to feed and drain a child's stdio on background threads, so it never blocks on a
+//! full pipe.
+
+use std::any::Any;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ChildStdin;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+use tempfile::NamedTempFile;
+
+/// Which of a command's output streams some output came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Controls how streamed output is batched before it is sent over a channel.
+///
+/// A batch is sent once it holds `flush_bytes`, or `flush_interval` after the first byte in
+/// it was read, whichever comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingOpts {
+    pub flush_interval: Duration,
+    pub flush_bytes: usize,
+    /// How many batches can be waiting in the channel before the command is blocked.
+    pub channel_capacity: usize,
+}
+
+impl Default for StreamingOpts {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_millis(50),
+            flush_bytes: 8192,
+            channel_capacity: 16,
+        }
+    }
+}
+
+/// A rate limit for reading output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BytesPerSecond(pub u64);
+
+/// A compression format to decode a command's output from as it is read, per
+/// [`crate::CommandOpts::decompress_stdout`].
+///
+/// Each codec is behind a feature of the same name. The format is whatever was configured;
+/// the output is never sniffed to guess it, so uncompressed output is an error rather than
+/// being passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decompression {
+    /// gzip, including several gzip streams one after the other.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Zstandard.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// The chunks read from both of a child's output streams, in the order they were read.
+pub(crate) type Transcript = Arc<Mutex<Vec<(Stream, Vec<u8>)>>>;
+
+/// Where the output read from one of a child's streams goes.
+#[derive(Default)]
+pub(crate) struct StreamSink {
+    /// Write everything that is read here too, e.g. to show it on the terminal.
+    pub(crate) echo: Option<Box<dyn Write + Send>>,
+    /// Send each chunk that is read over this channel.
+    pub(crate) forward: Option<SyncSender<(Stream, Vec<u8>)>>,
+    /// Keep everything that is read, so it can be put into the result.
+    pub(crate) retain: bool,
+    /// Read no faster than this.
+    pub(crate) throttle: Option<BytesPerSecond>,
+    /// The temp file that `echo` writes to, which is handed back once the stream is closed.
+    pub(crate) temp_file: Option<NamedTempFile>,
+    /// Decode the stream from this format, so that everything else sees decompressed bytes.
+    pub(crate) decompress: Option<Decompression>,
+    /// Retain no more than this many bytes, and discard the rest.
+    pub(crate) max_retained: Option<usize>,
+    /// Append each chunk that is read here too, which the other stream's sink shares.
+    pub(crate) transcript: Option<Transcript>,
+}
+
+impl StreamSink {
+    pub(crate) fn retained() -> Self {
+        Self {
+            retain: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything that was read from one of a child's output streams.
+#[derive(Default)]
+pub(crate) struct Captured {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) bytes_read: usize,
+    pub(crate) temp_file: Option<NamedTempFile>,
+    /// When the first byte was read, if any were.
+    pub(crate) first_read: Option<Instant>,
+    /// Whether some of what was read was not retained, per [`StreamSink::max_retained`].
+    pub(crate) truncated: bool,
+}
+
+/// Reads one of a child's output streams to the end on a separate thread.
+pub(crate) struct StreamReader(Stream, JoinHandle<std::io::Result<Captured>>);
+
+impl StreamReader {
+    pub(crate) fn spawn<R: Read + Send + 'static>(
+        stream: Stream,
+        reader: R,
+        mut sink: StreamSink,
+    ) -> Self {
+        let thread = std::thread::spawn(move || {
+            let mut reader: Box<dyn Read + Send> = match sink.decompress {
+                None => Box::new(reader),
+                #[cfg(feature = "gzip")]
+                Some(Decompression::Gzip) => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+                #[cfg(feature = "zstd")]
+                Some(Decompression::Zstd) => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            };
+            let mut buf = [0; 8192];
+            let mut captured = Captured::default();
+            let started = Instant::now();
+            // When throttled, read about a tenth of a second's worth at a time, so that the
+            // rate is smooth rather than in bursts of a full buffer.
+            let read_size = match sink.throttle {
+                Some(BytesPerSecond(rate)) => (rate / 10).clamp(1, buf.len() as u64) as usize,
+                None => buf.len(),
+            };
+            loop {
+                let n = match reader.read(&mut buf[..read_size]) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                captured.first_read.get_or_insert_with(Instant::now);
+                captured.bytes_read += n;
+                if let Some(echo) = sink.echo.as_mut() {
+                    echo.write_all(&buf[..n])?;
+                }
+                if let Some(transcript) = sink.transcript.as_ref() {
+                    let mut transcript = transcript.lock().unwrap();
+                    transcript.push((stream, buf[..n].to_vec()));
+                }
+                if let Some(forward) = sink.forward.as_ref() {
+                    // If nobody is listening anymore, keep draining the stream so that the
+                    // child does not block.
+                    if forward.send((stream, buf[..n].to_vec())).is_err() {
+                        sink.forward = None;
+                    }
+                }
+                if sink.retain {
+                    let kept = match sink.max_retained {
+                        Some(max) => n.min(max.saturating_sub(captured.bytes.len())),
+                        None => n,
+                    };
+                    captured.bytes.extend_from_slice(&buf[..kept]);
+                    captured.truncated |= kept < n;
+                }
+                if let Some(BytesPerSecond(rate)) = sink.throttle {
+                    let expected =
+                        Duration::from_secs_f64(captured.bytes_read as f64 / rate.max(1) as f64);
+                    if let Some(remaining) = expected.checked_sub(started.elapsed()) {
+                        std::thread::sleep(remaining);
+                    }
+                }
+            }
+            if let Some(echo) = sink.echo.as_mut() {
+                echo.flush()?;
+            }
+            captured.temp_file = sink.temp_file.take();
+            Ok(captured)
+        });
+        Self(stream, thread)
+    }
+
+    /// Wait for the stream to be closed, and get everything that was read.
+    ///
+    /// A panic on the reading thread is turned into an error, rather than propagated.
+    pub(crate) fn join(self) -> anyhow::Result<Captured> {
+        let stream = match self.0 {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        match self.1.join() {
+            Ok(res) => res.map_err(|e| anyhow::anyhow!("Failed to read {}: {}", stream, e)),
+            Err(payload) => Err(anyhow::anyhow!(
+                "Thread reading {} panicked: {}",
+                stream,
+                panic_message(&*payload)
+            )),
+        }
+    }
+}
+
+/// The message that a thread panicked with, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Appends to a file, rotating it once it would grow past `max_size`.
+///
+/// On rotation `path` is renamed to `path.1`, `path.1` to `path.2` and so on, keeping at most
+/// `keep` old files, and a new `path` is started.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &Path, max_size: u64, keep: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Batch up chunks from `rx` per stream and send them to `tx`, per `opts`.
+///
+/// This runs until all senders for `rx` are dropped, at which point any partial batches are
+/// sent, and `tx` is dropped.
+pub(crate) fn coalesce(
+    rx: Receiver<(Stream, Vec<u8>)>,
+    tx: SyncSender<(Stream, Vec<u8>)>,
+    opts: StreamingOpts,
+) {
+    std::thread::spawn(move || {
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let mut deadline: Option<Instant> = None;
+        let flush = |stream: Stream, buf: &mut Vec<u8>| {
+            if !buf.is_empty() {
+                // A closed receiver is fine, the output just gets dropped.
+                let _ = tx.send((stream, std::mem::take(buf)));
+            }
+        };
+        loop {
+            let received = match deadline {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok((stream, chunk)) => {
+                    let buf = match stream {
+                        Stream::Stdout => &mut stdout,
+                        Stream::Stderr => &mut stderr,
+                    };
+                    buf.extend_from_slice(&chunk);
+                    if buf.len() >= opts.flush_bytes {
+                        flush(stream, buf);
+                    }
+                    if stdout.is_empty() && stderr.is_empty() {
+                        deadline = None;
+                    } else if deadline.is_none() {
+                        deadline = Some(Instant::now() + opts.flush_interval);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    flush(Stream::Stdout, &mut stdout);
+                    flush(Stream::Stderr, &mut stderr);
+                    deadline = None;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    flush(Stream::Stdout, &mut stdout);
+                    flush(Stream::Stderr, &mut stderr);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Writes bytes into a child's stdin, and then closes it.
+pub(crate) struct StdinWriter(JoinHandle<std::io::Result<()>>);
+
+impl StdinWriter {
+    pub(crate) fn spawn(mut stdin: ChildStdin, bytes: Vec<u8>) -> Self {
+        Self(std::thread::spawn(move || {
+            match stdin.write_all(&bytes) {
+                // The child is allowed to exit without reading all of its input.
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+                res => res,
+            }
+        }))
+    }
+
+    pub(crate) fn join(self) -> anyhow::Result<()> {
+        match self.0.join() {
+            Ok(res) => Ok(res?),
+            Err(payload) => Err(anyhow::anyhow!(
+                "Thread writing stdin panicked: {}",
+                panic_message(&*payload)
+            )),
+        }
+    }
+}
@@ -1,24 +1,69 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::ops::Deref;
+use std::ops::Range;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use paths::AbsolutePathBuf;
 
 #[derive(thiserror::Error, Debug, Eq, PartialEq)]
 pub enum CommandLineError {
     #[error("At least one argument must be provided")]
     MissingProgram,
+    #[error("Expected at least {expected} arguments after the program, but got {actual}")]
+    TooFewArguments { expected: usize, actual: usize },
+    #[error("Missing a closing quote in `{0}`")]
+    UnbalancedQuotes(String),
+    #[error("Nothing follows the backslash at the end of `{0}`")]
+    TrailingBackslash(String),
+}
+
+/// The error returned when a program is not in any of the directories on `PATH`.
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+#[error(
+    "Could not find `{name}` in any directory on PATH: `{}`",
+    join_dirs(.searched_dirs)
+)]
+pub struct ProgramNotFound {
+    pub name: String,
+    /// The absolute directories on `PATH` that were searched, in order.
+    pub searched_dirs: Vec<PathBuf>,
+}
+
+fn join_dirs(dirs: &[PathBuf]) -> String {
+    let dirs: Vec<_> = dirs.iter().map(|dir| dir.to_string_lossy()).collect();
+    dirs.join(":")
 }
 
 /// Simple wrapper for a vec of strings that lets us push / extend with str literals too.
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct CommandLine(Vec<String>);
+///
+/// [`Display`] shows the arguments joined with spaces, for logs, with arguments added by
+/// [`CommandLine::push_secret`] shown as `****`. Use [`CommandLine::to_shell_string`] for
+/// something that can be pasted into a shell.
+///
+/// Whether arguments are secret does not affect equality or hashing.
+#[derive(Clone, Default)]
+pub struct CommandLine(
+    Vec<String>,
+    /// The indices of the arguments that are secret.
+    BTreeSet<usize>,
+);
+
+/// What secret arguments are shown as.
+const REDACTED: &str = "****";
 
 impl Display for CommandLine {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut first = true;
-        for a in &self.0 {
-            if first {
-                first = false;
-            } else {
+        for (i, a) in self.redacted().enumerate() {
+            if i > 0 {
                 f.write_str(" ")?;
             }
             f.write_str(a)?;
@@ -27,10 +72,88 @@ impl Display for CommandLine {
     }
 }
 
+impl Debug for CommandLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CommandLine")
+            .field(&self.redacted().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl PartialEq for CommandLine {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for CommandLine {}
+
+impl Hash for CommandLine {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+/// Serializes as an array of strings, e.g. `["git", "status"]` in JSON.
+///
+/// Secret arguments are serialized as they are, not redacted, as the point is to be able to
+/// run the command again. They are not secret once deserialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for CommandLine {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for CommandLine {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<String>::deserialize(deserializer).map(CommandLine::from)
+    }
+}
+
+/// A chainable way to build a [`CommandLine`], from [`CommandLine::builder`].
+#[derive(Debug, Default, Clone)]
+pub struct CommandLineBuilder(CommandLine);
+
+impl CommandLineBuilder {
+    /// Add an argument, or the program if this is the first one.
+    pub fn arg<T: Into<String>>(mut self, v: T) -> Self {
+        self.0.push(v);
+        self
+    }
+
+    /// Add several arguments.
+    pub fn args<T: Into<CommandLine>>(mut self, v: T) -> Self {
+        self.0.extend(v);
+        self
+    }
+
+    /// Add an argument only if `cond` is true, e.g. for optional flags.
+    pub fn arg_if<T: Into<String>>(self, cond: bool, v: T) -> Self {
+        if cond {
+            self.arg(v)
+        } else {
+            self
+        }
+    }
+
+    pub fn build(self) -> CommandLine {
+        self.0
+    }
+}
+
 /// [`From`] for anything that is an iterator of things that can be `str`s
+///
+/// There is intentionally no conversion from a single `&str`, as there is no one right way to
+/// split it: `"git status"` could just as well be a program with a space in its name. Use
+/// [`CommandLine::split_whitespace`] or [`CommandLine::parse_shell`] to say which is meant.
 impl<T: IntoIterator<Item = impl AsRef<str>>> From<T> for CommandLine {
     fn from(items: T) -> Self {
-        Self(items.into_iter().map(|i| i.as_ref().to_owned()).collect())
+        Self(
+            items.into_iter().map(|i| i.as_ref().to_owned()).collect(),
+            BTreeSet::new(),
+        )
     }
 }
 
@@ -49,23 +172,396 @@ impl Deref for CommandLine {
 }
 
 impl CommandLine {
+    /// Start building a command line argument by argument, which reads better than `push`
+    /// when some arguments are conditional.
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let verbose = true;
+    /// let cli = CommandLine::builder()
+    ///     .arg("cargo")
+    ///     .args(["build", "--release"])
+    ///     .arg_if(verbose, "--verbose")
+    ///     .build();
+    /// assert_eq!(["cargo", "build", "--release", "--verbose"], *cli);
+    /// ```
+    pub fn builder() -> CommandLineBuilder {
+        CommandLineBuilder::default()
+    }
+
+    /// Build a command line from dynamic JSON, which must be an array of strings.
+    ///
+    /// This is for commands that come from e.g. an RPC interface, where the JSON is not
+    /// known ahead of time. Fails with a description of the problem if `value` is not an
+    /// array, or if any of its elements are not strings.
+    #[cfg(feature = "serde")]
+    pub fn from_json_array(value: &serde_json::Value) -> anyhow::Result<CommandLine> {
+        let items = value.as_array().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Expected a JSON array of strings for a command line, got `{}`",
+                value
+            )
+        })?;
+        let args = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                item.as_str().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Expected element {} of the command line to be a string, got `{}`",
+                        i,
+                        item
+                    )
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(CommandLine::from(args))
+    }
+
+    /// Split a string into arguments on whitespace, for quick command lines like
+    /// `"git status --short"`.
+    ///
+    /// Quotes and backslashes have no special meaning, so an argument can never contain
+    /// whitespace; use [`Self::parse_shell`] for that.
+    pub fn split_whitespace(s: &str) -> CommandLine {
+        CommandLine::from(s.split_whitespace())
+    }
+
+    /// Run `script` with `sh -c`, for the rare commands that need shell features like globs,
+    /// pipes, or redirection.
+    ///
+    /// `script` is interpreted by the shell, so never build it by formatting in untrusted
+    /// input; anything that is not a literal part of the script should be passed with
+    /// [`Self::sh_c_with_args`] instead, and referenced from the script as `"$1"` etc.
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let cli = CommandLine::sh_c("ls *.rs | wc -l");
+    /// assert_eq!(["sh", "-c", "ls *.rs | wc -l"], *cli);
+    /// ```
+    pub fn sh_c(script: &str) -> CommandLine {
+        CommandLine::from(["sh", "-c", script])
+    }
+
+    /// Like [`Self::sh_c`], but with positional arguments for the script. Note that the first
+    /// argument becomes `$0`, the name of the script in error messages, so `"$1"` is the one
+    /// after it.
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let cli = CommandLine::sh_c_with_args("cp \"$1\" \"$2\"", ["copy", "a b", "c"]);
+    /// assert_eq!(["sh", "-c", "cp \"$1\" \"$2\"", "copy", "a b", "c"], *cli);
+    /// ```
+    pub fn sh_c_with_args<I, S>(script: &str, args: I) -> CommandLine
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut cli = Self::sh_c(script);
+        cli.extend(args);
+        cli
+    }
+
+    /// Split a string into arguments the way a POSIX shell would, e.g. for commands that are
+    /// stored in config files as a single string.
+    ///
+    /// Arguments are separated by whitespace, and quotes and backslashes work like in `sh`:
+    /// single quotes keep everything literally, and within double quotes a backslash only
+    /// escapes `$`, `` ` ``, `"`, `\`, and newlines. A `#` at the start of an argument starts
+    /// a comment that runs to the end of the line. Nothing is expanded, so `$HOME` or `*` are
+    /// passed on as-is. This is the inverse of [`Self::to_shell_string`].
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let cli = CommandLine::parse_shell(r#"git commit -m "it's done" --author='Me <me@example.com>'"#)?;
+    /// assert_eq!(["git", "commit", "-m", "it's done", "--author=Me <me@example.com>"], *cli);
+    /// # Ok::<(), commands::CommandLineError>(())
+    /// ```
+    pub fn parse_shell(s: &str) -> Result<CommandLine, CommandLineError> {
+        let mut args = vec![];
+        // The argument being built, if one was started. This is `Some("")` after `''`.
+        let mut current: Option<String> = None;
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => args.extend(current.take()),
+                '#' if current.is_none() => {
+                    chars.by_ref().find(|c| *c == '\n');
+                }
+                '\\' => match chars.next() {
+                    Some('\n') => {}
+                    Some(c) => current.get_or_insert_with(String::new).push(c),
+                    None => return Err(CommandLineError::TrailingBackslash(s.to_owned())),
+                },
+                '\'' => {
+                    let arg = current.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => arg.push(c),
+                            None => return Err(CommandLineError::UnbalancedQuotes(s.to_owned())),
+                        }
+                    }
+                }
+                '"' => {
+                    let arg = current.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some('\n') => {}
+                                Some(c @ ('$' | '`' | '"' | '\\')) => arg.push(c),
+                                Some(c) => {
+                                    arg.push('\\');
+                                    arg.push(c);
+                                }
+                                None => {
+                                    return Err(CommandLineError::UnbalancedQuotes(s.to_owned()))
+                                }
+                            },
+                            Some(c) => arg.push(c),
+                            None => return Err(CommandLineError::UnbalancedQuotes(s.to_owned())),
+                        }
+                    }
+                }
+                c => current.get_or_insert_with(String::new).push(c),
+            }
+        }
+        args.extend(current);
+        Ok(CommandLine::from(args))
+    }
+
     /// Add an argument onto this command line.
     pub fn push<T: Into<String>>(&mut self, v: T) {
         self.0.push(v.into())
     }
 
+    /// Add a secret argument, like a password, onto this command line.
+    ///
+    /// The command gets the real value, as do [`Self::args`] and [`Self::to_shell_string`],
+    /// but [`Display`], [`Debug`], and [`Self::to_redacted_shell_string`], and so log lines
+    /// and other output about the command, show `****` instead.
+    pub fn push_secret<T: Into<String>>(&mut self, v: T) {
+        self.1.insert(self.0.len());
+        self.0.push(v.into())
+    }
+
+    /// Whether the argument at `index` (where the program is 0) was added as a secret.
+    pub fn is_secret(&self, index: usize) -> bool {
+        self.1.contains(&index)
+    }
+
+    /// The arguments, with secret ones replaced by `****`.
+    fn redacted(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().enumerate().map(|(i, arg)| {
+            if self.is_secret(i) {
+                REDACTED
+            } else {
+                arg.as_str()
+            }
+        })
+    }
+
     /// Add all elements of another command line onto this one.
     pub fn extend<T: Into<CommandLine>>(&mut self, v: T) {
-        self.0.extend(v.into().0)
+        let other = v.into();
+        let offset = self.0.len();
+        self.1.extend(other.1.iter().map(|i| i + offset));
+        self.0.extend(other.0)
+    }
+
+    /// Add all elements of another command line onto the front of this one.
+    pub fn prepend<T: Into<CommandLine>>(&mut self, v: T) {
+        let mut new = v.into();
+        new.extend(std::mem::take(self));
+        *self = new;
+    }
+
+    /// Replace the first argument for which `pred` returns true with `replacement`.
+    ///
+    /// Returns whether an argument was replaced. The program is also considered.
+    pub fn replace_first<F: Fn(&str) -> bool>(
+        &mut self,
+        pred: F,
+        replacement: impl Into<String>,
+    ) -> bool {
+        match self.0.iter().position(|arg| pred(arg)) {
+            Some(i) => {
+                self.0[i] = replacement.into();
+                self.1.remove(&i);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Clones this command line and adds `v` to that clone.
     pub fn clone_with<T: Into<CommandLine>>(&self, v: T) -> Self {
         let mut new = self.clone();
-        new.extend(v.into().0);
+        new.extend(v);
+        new
+    }
+
+    /// Clones this command line and prefixes it with `env K=V ...`.
+    ///
+    /// This sets the environment as part of the command itself, which is
+    /// useful when the runner's environment does not propagate to the
+    /// actual process (e.g. when running through `ssh` or `sudo`). If `vars`
+    /// is empty, the command line is returned unchanged.
+    pub fn with_env_prefix(&self, vars: &[(&str, &str)]) -> Self {
+        let mut new = self.clone();
+        if !vars.is_empty() {
+            let mut prefix = CommandLine::from(["env"]);
+            prefix.extend(vars.iter().map(|(k, v)| format!("{}={}", k, v)));
+            new.prepend(prefix);
+        }
         new
     }
 
+    /// Like [`Self::with_env_prefix`], but for e.g. [`crate::CommandOpts::env`], with the
+    /// variables sorted by name, so that the command line is the same on every run.
+    ///
+    /// The variables are added as secrets, as they often hold tokens and are not expected to
+    /// be shown, though they are still visible to anything that can see the command's
+    /// arguments, like `ps`.
+    pub(crate) fn with_env_map_prefix(&self, env: &HashMap<String, String>) -> Self {
+        let mut vars: Vec<_> = env.iter().collect();
+        vars.sort_unstable();
+        let mut new = self.clone();
+        if !vars.is_empty() {
+            let mut prefix = CommandLine::from(["env"]);
+            for (k, v) in vars {
+                prefix.push_secret(format!("{}={}", k, v));
+            }
+            new.prepend(prefix);
+        }
+        new
+    }
+
+    /// Clones this command line without duplicate flags.
+    ///
+    /// Flags are arguments that start with `-`. `value_flags` lists the flags that take the
+    /// following argument as their value, e.g. `--color` in `--color always`. Flags of the
+    /// form `--flag=value` are always treated as `--flag` with a value, so `--color=always`
+    /// and `--color never` are duplicates of each other. For each flag that appears more
+    /// than once, only the last occurrence (and its value) is kept if `last_wins`, and only
+    /// the first otherwise. Other arguments, the program, and anything after `--` are never
+    /// removed.
+    pub fn dedup_flags(&self, value_flags: &[&str], last_wins: bool) -> CommandLine {
+        // Group the args into units that are kept or removed together, with the flag name
+        // that identifies duplicates.
+        let mut units: Vec<(Option<&str>, Range<usize>)> = vec![];
+        let mut i = 0;
+        let mut positional_only = false;
+        while i < self.0.len() {
+            let arg = self.0[i].as_str();
+            let len = if !positional_only && value_flags.contains(&arg) {
+                2.min(self.0.len() - i)
+            } else {
+                1
+            };
+            let key = if i == 0 || positional_only || !arg.starts_with('-') || arg == "-" {
+                None
+            } else if arg == "--" {
+                positional_only = true;
+                None
+            } else {
+                Some(arg.split_once('=').map_or(arg, |(name, _)| name))
+            };
+            units.push((key, i..i + len));
+            i += len;
+        }
+
+        let mut kept: HashMap<&str, usize> = HashMap::new();
+        for (i, (key, _)) in units.iter().enumerate() {
+            if let Some(key) = *key {
+                if last_wins {
+                    kept.insert(key, i);
+                } else {
+                    kept.entry(key).or_insert(i);
+                }
+            }
+        }
+        let mut deduped = CommandLine::default();
+        for (i, (key, range)) in units.into_iter().enumerate() {
+            if key.is_none_or(|key| kept[key] == i) {
+                for j in range {
+                    if self.is_secret(j) {
+                        deduped.push_secret(&self.0[j]);
+                    } else {
+                        deduped.push(&self.0[j]);
+                    }
+                }
+            }
+        }
+        deduped
+    }
+
+    /// Render the command line so that it can be pasted into a shell, and runs exactly this
+    /// command, e.g. to tell users how to reproduce a failure.
+    ///
+    /// Each argument that contains anything other than a conservative set of safe characters
+    /// is wrapped in single quotes. Unlike this, [`Display`] just joins the arguments with
+    /// spaces, which is easier to read in logs, but ambiguous for arguments with spaces.
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let cli = CommandLine::from(["sh", "-c", "echo hi there"]);
+    /// assert_eq!("sh -c echo hi there", cli.to_string());
+    /// assert_eq!("sh -c 'echo hi there'", cli.to_shell_string());
+    /// ```
+    pub fn to_shell_string(&self) -> String {
+        self.0
+            .iter()
+            .map(String::as_str)
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Like [`Self::to_shell_string`], but with secret arguments shown as `****`, for output
+    /// that people see, like verbose output or fixture files.
+    ///
+    /// ```
+    /// use commands::CommandLine;
+    ///
+    /// let mut cli = CommandLine::from(["login", "--password"]);
+    /// cli.push_secret("hunter 2");
+    /// assert_eq!("login --password 'hunter 2'", cli.to_shell_string());
+    /// assert_eq!("login --password '****'", cli.to_redacted_shell_string());
+    /// ```
+    pub fn to_redacted_shell_string(&self) -> String {
+        self.redacted()
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Check whether this command line matches `pattern`.
+    ///
+    /// Each argument in the pattern must equal the argument at the same position, except
+    /// that `*` matches any single argument, and `**` matches any number of arguments
+    /// (including none). e.g. `git commit -m *` matches any `git commit -m <message>`, and
+    /// `git **` matches any git command.
+    pub fn matches_pattern(&self, pattern: &CommandLine) -> bool {
+        fn matches(args: &[String], pattern: &[String]) -> bool {
+            match pattern.split_first() {
+                None => args.is_empty(),
+                Some((p, rest)) if p == "**" => (0..=args.len()).any(|i| matches(&args[i..], rest)),
+                Some((p, rest)) => match args.split_first() {
+                    Some((a, args)) => (p == "*" || p == a) && matches(args, rest),
+                    None => false,
+                },
+            }
+        }
+        matches(&self.0, &pattern.0)
+    }
+
     /// Get the program that is to be executed.
     ///
     /// This is the first element of the list of arguments. If
@@ -89,12 +585,105 @@ impl CommandLine {
             Ok(&self.0[1..])
         }
     }
+
+    /// Like [`Self::args`], but as a command line that keeps which arguments are secret, to
+    /// run the same arguments with another program.
+    pub(crate) fn args_command_line(&self) -> Result<CommandLine, CommandLineError> {
+        let args = self.args()?.to_vec();
+        let secrets = self.1.iter().filter(|i| **i > 0).map(|i| i - 1).collect();
+        Ok(CommandLine(args, secrets))
+    }
+
+    /// Find the absolute path of the program.
+    ///
+    /// A bare program name is looked up in each absolute directory of `env_path`, which is in
+    /// the same format as `PATH`, and the first executable file with that name is used.
+    /// Programs that are already absolute paths are returned as they are. This fails with
+    /// [`ProgramNotFound`] if the program cannot be found, and also fails if it is a relative
+    /// path like `./foo`.
+    pub fn resolve_program(&self, env_path: &str) -> anyhow::Result<AbsolutePathBuf> {
+        let program = self.program()?;
+        if Path::new(program).is_absolute() {
+            return Ok(AbsolutePathBuf::try_new(program)?);
+        }
+        if program.contains('/') {
+            return Err(anyhow::anyhow!(
+                "`{}` is a relative path, so it cannot be looked up on PATH",
+                program
+            ));
+        }
+        let searched_dirs: Vec<_> = std::env::split_paths(env_path)
+            .filter(|dir| dir.is_absolute())
+            .collect();
+        match searched_dirs
+            .iter()
+            .map(|dir| dir.join(program))
+            .find(|candidate| is_executable(candidate))
+        {
+            Some(resolved) => Ok(AbsolutePathBuf::try_from(resolved)?),
+            None => Err(ProgramNotFound {
+                name: program.to_owned(),
+                searched_dirs,
+            }
+            .into()),
+        }
+    }
+
+    /// Clones this command line with the program replaced by its absolute path, per
+    /// [`Self::resolve_program`].
+    pub fn resolve_program_absolute(&self, env_path: &str) -> anyhow::Result<CommandLine> {
+        let resolved = self.resolve_program(env_path)?;
+        let resolved: &Path = resolved.as_ref();
+        let resolved = resolved
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Path `{}` is not valid UTF-8", resolved.display()))?;
+        let mut new = self.clone();
+        new.0[0] = resolved.to_owned();
+        Ok(new)
+    }
+
+    /// Check that there are at least `min` arguments after the program.
+    ///
+    /// Fails with [`CommandLineError::MissingProgram`] if there is no program at all.
+    pub fn require_args(&self, min: usize) -> Result<(), CommandLineError> {
+        let actual = self.args()?.len();
+        if actual < min {
+            Err(CommandLineError::TooFewArguments {
+                expected: min,
+                actual,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use maplit::hashmap;
+
     use crate::command_line::CommandLineError;
     use crate::CommandLine;
+    use crate::ProgramNotFound;
 
     #[test]
     fn from_string_collection() {
@@ -120,6 +709,31 @@ mod test {
         );
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_json_array() -> anyhow::Result<()> {
+        let cli = CommandLine::from_json_array(&serde_json::json!(["git", "commit", "-m", "a b"]))?;
+        assert_eq!(["git", "commit", "-m", "a b"], *cli);
+
+        let err = CommandLine::from_json_array(&serde_json::json!("git status")).unwrap_err();
+        assert!(err.to_string().contains("array"), "{}", err);
+        let err = CommandLine::from_json_array(&serde_json::json!(["git", 1])).unwrap_err();
+        assert!(err.to_string().contains("element 1"), "{}", err);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() -> anyhow::Result<()> {
+        let mut cli = CommandLine::from(["curl", "-H"]);
+        cli.push_secret("Authorization: token");
+        let json = serde_json::to_string(&cli)?;
+        assert_eq!(r#"["curl","-H","Authorization: token"]"#, json);
+        assert_eq!(cli, serde_json::from_str::<CommandLine>(&json)?);
+        assert!(serde_json::from_str::<CommandLine>(r#""curl -H""#).is_err());
+        Ok(())
+    }
+
     #[test]
     fn push_works() {
         let mut cli = CommandLine::from(vec!["foo"]);
@@ -162,6 +776,184 @@ mod test {
         assert_eq!(["foo", "bar", "baz"], *cloned4);
     }
 
+    #[test]
+    fn prepend_works() {
+        let mut cli = CommandLine::from(["foo", "bar"]);
+        cli.prepend(["sudo", "-n"]);
+        assert_eq!(["sudo", "-n", "foo", "bar"], *cli);
+    }
+
+    #[test]
+    fn replace_first() {
+        let mut cli = CommandLine::from(["cargo", "add", "foo@1.0", "bar@1.0"]);
+        assert!(cli.replace_first(|arg| arg.ends_with("@1.0"), "foo@2.0"));
+        assert_eq!(["cargo", "add", "foo@2.0", "bar@1.0"], *cli);
+        assert!(!cli.replace_first(|arg| arg == "baz", "quz"));
+        assert_eq!(["cargo", "add", "foo@2.0", "bar@1.0"], *cli);
+    }
+
+    #[test]
+    fn with_env_prefix() {
+        let cli = CommandLine::from(["foo", "bar"]);
+        assert_eq!(
+            ["env", "FOO=bar", "BAZ=quz", "foo", "bar"],
+            *cli.with_env_prefix(&[("FOO", "bar"), ("BAZ", "quz")])
+        );
+        assert_eq!(["foo", "bar"], *cli.with_env_prefix(&[]));
+    }
+
+    #[test]
+    fn with_env_map_prefix() {
+        let cli = CommandLine::from(["foo", "bar"]);
+        let env = hashmap! {
+            "FOO".to_owned() => "bar".to_owned(),
+            "BAZ".to_owned() => "quz".to_owned(),
+        };
+        let prefixed = cli.with_env_map_prefix(&env);
+        assert_eq!(["env", "BAZ=quz", "FOO=bar", "foo", "bar"], *prefixed);
+        assert_eq!("env **** **** foo bar", prefixed.to_string());
+        assert_eq!(["foo", "bar"], *cli.with_env_map_prefix(&HashMap::new()));
+    }
+
+    #[test]
+    fn dedup_flags() {
+        let cli = CommandLine::from([
+            "ls",
+            "--color=always",
+            "-l",
+            "--sort",
+            "size",
+            "dir",
+            "-l",
+            "--color",
+            "never",
+            "--sort=time",
+            "--",
+            "-l",
+        ]);
+        let value_flags = ["--color", "--sort"];
+        assert_eq!(
+            [
+                "ls",
+                "--color=always",
+                "-l",
+                "--sort",
+                "size",
+                "dir",
+                "--",
+                "-l"
+            ],
+            *cli.dedup_flags(&value_flags, false)
+        );
+        assert_eq!(
+            [
+                "ls",
+                "dir",
+                "-l",
+                "--color",
+                "never",
+                "--sort=time",
+                "--",
+                "-l"
+            ],
+            *cli.dedup_flags(&value_flags, true)
+        );
+
+        let cli = CommandLine::from(["git", "commit", "--verbose", "--verbose", "-m"]);
+        assert_eq!(
+            ["git", "commit", "--verbose", "-m"],
+            *cli.dedup_flags(&["-m"], true)
+        );
+    }
+
+    #[test]
+    fn to_shell_string() {
+        let cli = CommandLine::from(["sh", "-c", "echo 'hi' there", "", "--foo=bar/baz"]);
+        assert_eq!(
+            r#"sh -c 'echo '\''hi'\'' there' '' --foo=bar/baz"#,
+            cli.to_shell_string()
+        );
+    }
+
+    #[test]
+    fn sh_c() {
+        assert_eq!(["sh", "-c", "echo *"], *CommandLine::sh_c("echo *"));
+        let cli = CommandLine::sh_c_with_args("echo \"$1\"", ["sh", "a; b"]);
+        assert_eq!(["sh", "-c", "echo \"$1\"", "sh", "a; b"], *cli);
+        assert_eq!(
+            ["sh", "-c", "true"],
+            *CommandLine::sh_c_with_args("true", Vec::<String>::new())
+        );
+    }
+
+    #[test]
+    fn split_whitespace() {
+        assert_eq!(
+            ["git", "status", "--short"],
+            *CommandLine::split_whitespace("  git status\t--short\n")
+        );
+        assert_eq!(
+            ["echo", "'a", "b'"],
+            *CommandLine::split_whitespace("echo 'a b'")
+        );
+        assert!(CommandLine::split_whitespace(" ").is_empty());
+    }
+
+    #[test]
+    fn parse_shell() -> anyhow::Result<()> {
+        assert_eq!(
+            ["git", "log", "--oneline", "-n", "5"],
+            *CommandLine::parse_shell("  git log\t--oneline -n 5\n")?
+        );
+        assert_eq!(
+            ["echo", "a b", "", "it's", "$HOME", "x\\y\"z", "a b", "c#d"],
+            *CommandLine::parse_shell(
+                r#"echo 'a b' "" "it's" '$HOME' "x\y\"z" a\ b c#d # comment"#
+            )?
+        );
+        assert_eq!(["foo", "bar"], *CommandLine::parse_shell("foo \\\nbar")?);
+        assert!(CommandLine::parse_shell("")?.is_empty());
+
+        assert_eq!(
+            Err(CommandLineError::UnbalancedQuotes("echo 'hi".to_owned())),
+            CommandLine::parse_shell("echo 'hi")
+        );
+        assert_eq!(
+            Err(CommandLineError::UnbalancedQuotes(
+                r#"echo "hi\""#.to_owned()
+            )),
+            CommandLine::parse_shell(r#"echo "hi\""#)
+        );
+        assert_eq!(
+            Err(CommandLineError::TrailingBackslash("echo \\".to_owned())),
+            CommandLine::parse_shell("echo \\")
+        );
+
+        let cli = CommandLine::from(["sh", "-c", "echo 'hi' there", "", "--foo=bar/baz"]);
+        assert_eq!(cli, CommandLine::parse_shell(&cli.to_shell_string())?);
+        Ok(())
+    }
+
+    #[test]
+    fn matches_pattern() {
+        let cli = CommandLine::from(["git", "commit", "-m", "some message"]);
+        let matches = |pattern: &[&str]| cli.matches_pattern(&CommandLine::from(pattern));
+
+        assert!(matches(&["git", "commit", "-m", "some message"]));
+        assert!(matches(&["git", "commit", "-m", "*"]));
+        assert!(matches(&["*", "*", "*", "*"]));
+        assert!(matches(&["git", "**"]));
+        assert!(matches(&["git", "commit", "-m", "some message", "**"]));
+        assert!(matches(&["**", "-m", "*"]));
+        assert!(matches(&["**"]));
+
+        assert!(!matches(&["git", "commit", "-m"]));
+        assert!(!matches(&["git", "commit", "*"]));
+        assert!(!matches(&["git", "commit", "-m", "*", "*"]));
+        assert!(!matches(&["git", "push", "**"]));
+        assert!(!matches(&[]));
+    }
+
     #[test]
     fn program_and_args_work() {
         let cli = CommandLine::from(["foo", "bar", "baz"]);
@@ -172,4 +964,108 @@ mod test {
         assert_eq!(Err(CommandLineError::MissingProgram), bad_cli.program());
         assert_eq!(Err(CommandLineError::MissingProgram), bad_cli.args());
     }
+
+    #[test]
+    fn resolve_program_absolute() -> anyhow::Result<()> {
+        let cli = CommandLine::from(["sh", "-c", "true"]);
+        let resolved = cli.resolve_program_absolute("relative:/nonexistent:/bin:/usr/bin")?;
+        assert!(resolved.program()?.starts_with('/'));
+        assert!(resolved.program()?.ends_with("/sh"));
+        assert_eq!(cli.args()?, resolved.args()?);
+
+        let absolute = CommandLine::from(["/bin/sh"]);
+        assert_eq!(absolute, absolute.resolve_program_absolute("")?);
+
+        assert!(CommandLine::from(["./sh"])
+            .resolve_program_absolute("/bin")
+            .is_err());
+        assert!(CommandLine::from(["definitely-not-a-program"])
+            .resolve_program_absolute("/bin:/usr/bin")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let mut cli = CommandLine::from(["login", "--password"]);
+        cli.push_secret("hunter2");
+        assert_eq!("login --password ****", cli.to_string());
+        assert_eq!(
+            r#"CommandLine(["login", "--password", "****"])"#,
+            format!("{:?}", cli)
+        );
+        assert_eq!(["--password", "hunter2"], *cli.args().unwrap());
+        assert_eq!("login --password hunter2", cli.to_shell_string());
+        assert_eq!("login --password '****'", cli.to_redacted_shell_string());
+        let args = cli.args_command_line().unwrap();
+        assert_eq!(["--password", "hunter2"], *args);
+        assert_eq!("--password ****", args.to_string());
+        assert_eq!(CommandLine::from(["login", "--password", "hunter2"]), cli);
+
+        let mut wrapped = CommandLine::from(["sudo"]).clone_with(cli.clone());
+        wrapped.prepend(["env", "A=b"]);
+        assert_eq!("env A=b sudo login --password ****", wrapped.to_string());
+        let mut deduped = wrapped.dedup_flags(&["--password"], true);
+        assert_eq!("env A=b sudo login --password ****", deduped.to_string());
+        assert!(deduped.replace_first(|arg| arg == "hunter2", "public"));
+        assert_eq!("env A=b sudo login --password public", deduped.to_string());
+    }
+
+    #[test]
+    fn builder() {
+        let cli = CommandLine::builder()
+            .arg("git")
+            .args(["log", "--oneline"])
+            .arg_if(false, "--all")
+            .arg_if(true, "-n5")
+            .build();
+        assert_eq!(["git", "log", "--oneline", "-n5"], *cli);
+        assert!(CommandLine::builder().build().is_empty());
+    }
+
+    #[test]
+    fn resolve_program() -> anyhow::Result<()> {
+        let sh = CommandLine::from(["sh"]).resolve_program("/bin:/usr/bin")?;
+        let sh: &Path = sh.as_ref();
+        assert!(sh.ends_with("sh"));
+
+        let err = CommandLine::from(["definitely-not-a-program", "--help"])
+            .resolve_program("relative:/nonexistent:/bin")
+            .unwrap_err()
+            .downcast::<ProgramNotFound>()?;
+        assert_eq!(
+            ProgramNotFound {
+                name: "definitely-not-a-program".to_owned(),
+                searched_dirs: vec![PathBuf::from("/nonexistent"), PathBuf::from("/bin")],
+            },
+            err
+        );
+        assert_eq!(
+            "Could not find `definitely-not-a-program` in any directory on PATH: \
+             `/nonexistent:/bin`",
+            err.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn require_args() {
+        let cli = CommandLine::from(["foo", "bar", "baz"]);
+        assert_eq!(Ok(()), cli.require_args(0));
+        assert_eq!(Ok(()), cli.require_args(2));
+        assert_eq!(
+            Err(CommandLineError::TooFewArguments {
+                expected: 3,
+                actual: 2
+            }),
+            cli.require_args(3)
+        );
+        assert_eq!(Ok(()), CommandLine::from(["foo"]).require_args(0));
+
+        let bad_cli = CommandLine::from(Vec::<String>::new());
+        assert_eq!(
+            Err(CommandLineError::MissingProgram),
+            bad_cli.require_args(0)
+        );
+    }
 }
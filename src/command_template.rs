@@ -0,0 +1,174 @@
+use crate::CommandLine;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Value(String),
+}
+
+/// Builds a [`CommandLine`] out of trusted literal text and untrusted values.
+///
+/// Literal text is split on whitespace into separate arguments, while each value always
+/// becomes exactly one argument, regardless of its content. This means that a value can
+/// never be misinterpreted as several arguments. Compare that to formatting values into a
+/// string that is run with `sh -c`, where a value like `"foo; rm -rf ~"` is interpreted by
+/// the shell.
+///
+/// Note that a value starting with `-` may still be interpreted as a flag by the program
+/// itself. Insert a `--` literal before values if the program supports it.
+///
+/// ```
+/// use commands::CommandTemplate;
+///
+/// let message = "fix: don't split me";
+/// let command_line = CommandTemplate::new()
+///     .literal("git commit -m")
+///     .value(message)
+///     .build();
+/// assert_eq!(["git", "commit", "-m", message], *command_line);
+/// ```
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct CommandTemplate(Vec<TemplatePart>);
+
+impl CommandTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add trusted text, which is split on whitespace into arguments.
+    pub fn literal(mut self, literal: &str) -> Self {
+        self.0.push(TemplatePart::Literal(literal.to_owned()));
+        self
+    }
+
+    /// Add a value, which always becomes a single argument.
+    pub fn value<S: Into<String>>(mut self, value: S) -> Self {
+        self.0.push(TemplatePart::Value(value.into()));
+        self
+    }
+
+    /// Create the [`CommandLine`] from this template.
+    pub fn build(&self) -> CommandLine {
+        let mut command_line = CommandLine::from(Vec::<String>::new());
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(l) => command_line.extend(l.split_whitespace()),
+                TemplatePart::Value(v) => command_line.push(v.as_str()),
+            }
+        }
+        command_line
+    }
+}
+
+/// Build a [`CommandLine`] from a format string, where each `{}` is replaced by one value.
+///
+/// The format string is split on whitespace into arguments, and each value is formatted with
+/// [`ToString`] into the argument that its `{}` is in. A value is never split, no matter what
+/// it contains, so this is safe to use with untrusted values. That is unlike formatting a
+/// string with [`format!`] and running it with `sh -c`, where the shell would interpret any
+/// spaces, quotes, or `;` in the values. Like [`CommandTemplate`], values starting with `-`
+/// may still be taken as flags by the program.
+///
+/// This panics if the number of `{}`s does not match the number of values.
+///
+/// ```
+/// use commands::cmd;
+///
+/// let message = "fix: don't split me";
+/// let command_line = cmd!("git commit -m {} --author={}", message, "Me <me@example.com>");
+/// assert_eq!(
+///     ["git", "commit", "-m", message, "--author=Me <me@example.com>"],
+///     *command_line
+/// );
+/// ```
+#[macro_export]
+macro_rules! cmd {
+    ($format:literal $(, $value:expr)* $(,)?) => {
+        $crate::__format_command_line(
+            $format,
+            ::std::vec![$(::std::string::ToString::to_string(&$value)),*],
+        )
+    };
+}
+
+#[doc(hidden)]
+pub fn format_command_line(format: &str, values: Vec<String>) -> CommandLine {
+    let placeholders = format.matches("{}").count();
+    assert_eq!(
+        placeholders,
+        values.len(),
+        "`{}` has {} placeholders, but {} values were given",
+        format,
+        placeholders,
+        values.len()
+    );
+    let mut values = values.into_iter();
+    let mut command_line = CommandLine::from(Vec::<String>::new());
+    for word in format.split_whitespace() {
+        let mut pieces = word.split("{}");
+        let mut arg = pieces.next().unwrap_or_default().to_owned();
+        for piece in pieces {
+            arg.push_str(&values.next().expect("counted above"));
+            arg.push_str(piece);
+        }
+        command_line.push(arg);
+    }
+    command_line
+}
+
+#[cfg(test)]
+mod test {
+    use crate::CommandTemplate;
+
+    #[test]
+    fn literals_are_split() {
+        let cli = CommandTemplate::new()
+            .literal("git  commit")
+            .literal("-m")
+            .build();
+        assert_eq!(["git", "commit", "-m"], *cli);
+    }
+
+    #[test]
+    fn values_are_single_arguments() {
+        let cli = CommandTemplate::new()
+            .literal("git commit -m")
+            .value("two words; echo injected")
+            .value("")
+            .literal("--")
+            .value(" padded ".to_owned())
+            .build();
+        assert_eq!(
+            [
+                "git",
+                "commit",
+                "-m",
+                "two words; echo injected",
+                "",
+                "--",
+                " padded "
+            ],
+            *cli
+        );
+    }
+
+    #[test]
+    fn cmd_macro() {
+        let message = "two words; echo injected";
+        assert_eq!(
+            ["git", "commit", "-m", message],
+            *crate::cmd!("git commit -m {}", message)
+        );
+        assert_eq!(
+            ["head", "-n", "3", "--file=a b.txt"],
+            *crate::cmd!("head  -n {} --file={}", 3, "a b.txt",)
+        );
+        assert_eq!(["ls"], *crate::cmd!("ls"));
+    }
+
+    #[test]
+    #[should_panic(expected = "placeholders")]
+    fn cmd_macro_checks_values() {
+        crate::cmd!("git commit -m {}");
+    }
+}
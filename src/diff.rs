@@ -0,0 +1,56 @@
+//! A minimal line-based diff, for showing why output did not match what was expected.
+
+/// Render the differences between `expected` and `actual` line by line.
+///
+/// Lines only in `expected` are prefixed with `-`, lines only in `actual` with `+`, and lines
+/// in both with a space. This uses the longest common subsequence of lines, which is quadratic,
+/// but fine for the size of output that is compared in tests.
+pub(crate) fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of expected[i..] and
+    // actual[j..].
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            out.push_str(&format!(" {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push_str(&format!("+{}\n", actual[j]));
+            j += 1;
+        } else {
+            out.push_str(&format!("-{}\n", expected[i]));
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::line_diff;
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(
+            " a\n-b\n+B\n c\n+d\n",
+            line_diff("a\nb\nc\n", "a\nB\nc\nd\n")
+        );
+        assert_eq!(" a\n", line_diff("a", "a\n"));
+        assert_eq!("-a\n", line_diff("a", ""));
+    }
+}
@@ -0,0 +1,139 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::process::Output;
+
+use itertools::Itertools;
+use paths::AbsolutePath;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+
+/// A [`CommandRunner`] that logs the commands that would be run, without running anything.
+///
+/// This is for `--dry-run` flags in production tools. Every command "succeeds" with empty
+/// output, so code that depends on what commands print may take different paths than it
+/// would for real. [`CommandRunner::exec`] logs the command and returns, rather than
+/// replacing this process.
+#[derive(Debug, Default)]
+pub struct DryRunCommandRunner;
+
+impl DryRunCommandRunner {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// What every command "returns".
+fn succeeded() -> ExecutionResult {
+    ExecutionResult::from(Output {
+        status: ExitStatus::from_raw(0),
+        stdout: vec![],
+        stderr: vec![],
+    })
+}
+
+impl CommandRunner for DryRunCommandRunner {
+    /// Overridden so that only "Would run" is logged, rather than also "Running".
+    fn run_with_opts<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let command_line = command_line.into();
+        let program_name = command_line.program()?.to_owned();
+        let mut res = self.run_inner(command_line, cwd.as_ref(), opts)?;
+        res.program = program_name;
+        Ok(res)
+    }
+
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let last = match commands.last() {
+            Some(last) => last.program()?.to_owned(),
+            None => return Err(anyhow::anyhow!("A pipeline needs at least one command")),
+        };
+        log::info!(
+            "Would run {}pipeline `{}` in `{}`",
+            opts.log_label(),
+            commands.iter().join(" | "),
+            cwd.as_ref()
+        );
+        let mut res = succeeded();
+        res.program = last;
+        Ok(res)
+    }
+
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        log::info!(
+            "Would run {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd
+        );
+        Ok(succeeded())
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        log::info!(
+            "Would exec {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use paths::AbsolutePath;
+
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::DryRunCommandRunner;
+
+    #[test]
+    fn runs_nothing() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let runner = DryRunCommandRunner::new();
+        let res = runner.run_checked(["touch", "created"], AbsolutePath::try_new(temp.path())?)?;
+        assert!(res.success());
+        assert_eq!("", res.stdout()?);
+        assert!(!temp.path().join("created").exists());
+
+        let commands = vec![
+            CommandLine::from(["touch", "created"]),
+            CommandLine::from(["cat"]),
+        ];
+        let cwd = AbsolutePath::try_new(temp.path())?;
+        assert!(runner
+            .run_pipeline(commands, cwd, CommandOpts::default())?
+            .success());
+        assert!(!temp.path().join("created").exists());
+
+        runner.exec(CommandLine::from(["touch", "created"]))?;
+        assert!(!temp.path().join("created").exists());
+        Ok(())
+    }
+}
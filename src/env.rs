@@ -1,21 +1,64 @@
 //!-- Simple runner for executables and accessing a few other small pieces of the exeuction env.
 
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::ffi::CStr;
 use std::ffi::OsStr;
 use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
 use std::io::Write;
 use std::ops::Deref;
+use std::os::unix::io::OwnedFd;
 use std::os::unix::prelude::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::ChildStdout;
+use std::process::ExitStatus;
 use std::process::Output;
 use std::process::Stdio;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
-use maplit::hashset;
+use anyhow::Context;
+use itertools::Itertools;
 use paths::AbsolutePath;
 use paths::AbsolutePathBuf;
-use tee::Tee;
+use tempfile::NamedTempFile;
 
+use crate::capture::RotatingFile;
+use crate::capture::StreamSink;
+use crate::capture::Transcript;
+use crate::events::CommandEvents;
+use crate::signals::SignalForwarding;
+use crate::BytesPerSecond;
+use crate::ChildStdoutReader;
+use crate::CommandEvent;
+use crate::CommandHandle;
 use crate::CommandLine;
+use crate::Decompression;
+use crate::EnvProfile;
+use crate::ProgramNotFound;
+use crate::RetryPolicy;
+use crate::Stream;
+use crate::StreamingOpts;
+use crate::SupervisedOutcome;
+use crate::SupervisionOpts;
+
+/// The most of a script that is read to find its shebang, which matches Linux.
+const MAX_SHEBANG_LEN: u64 = 256;
+
+/// The most stdin that is shown when [`CommandOpts::log_stdin`] is set.
+const MAX_LOGGED_STDIN: usize = 512;
+
+/// The most stdout that is shown when [`ExecutionResult::json`] cannot parse it.
+#[cfg(feature = "serde")]
+const MAX_SHOWN_JSON: usize = 1024;
 
 pub trait CommandRunner: Debug + Send + Sync {
     fn run_checked<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
@@ -37,16 +80,45 @@ pub trait CommandRunner: Debug + Send + Sync {
         let ret = self.run_with_opts(command_line, cwd, opts)?;
         match ret.status.success() {
             true => Ok(ret),
-            false => Err(anyhow::anyhow!(
-                "Command `{}` failed with status `{}`\nStdout:\n{}\nStderr:\n{}",
-                program_name,
-                ret.status,
-                String::from_utf8_lossy(&ret.stdout),
-                String::from_utf8_lossy(&ret.stderr)
-            )),
+            false => Err(CommandFailed::new(program_name, ret).into()),
+        }
+    }
+
+    /// Like [`Self::run_checked_with_opts`], but any exit code in `ok_codes` counts as success.
+    ///
+    /// This is useful for tools like `grep`, which exit with `1` when nothing matched.
+    /// Commands that are terminated by a signal always fail.
+    fn run_checked_codes<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        ok_codes: &[i32],
+    ) -> anyhow::Result<ExecutionResult> {
+        let command_line = command_line.into();
+        let program_name = command_line.program()?.to_owned();
+        let ret = self.run_with_opts(command_line, cwd, opts)?;
+        match ret.status.code() {
+            Some(code) if ok_codes.contains(&code) => Ok(ret),
+            _ => Err(CommandFailed::new(program_name, ret).into()),
         }
     }
 
+    /// Like [`Self::run_checked`], but attach a hint on how to fix the problem to the
+    /// [`CommandFailed`] error if the command fails.
+    fn run_checked_with_hint<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        hint: &str,
+    ) -> anyhow::Result<ExecutionResult> {
+        self.run_checked(command_line, cwd)
+            .map_err(|e| match e.downcast::<CommandFailed>() {
+                Ok(failed) => failed.with_hint(hint).into(),
+                Err(e) => e,
+            })
+    }
+
     fn run<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
         &self,
         command_line: C,
@@ -55,6 +127,173 @@ pub trait CommandRunner: Debug + Send + Sync {
         self.run_with_opts(command_line, cwd, CommandOpts::default())
     }
 
+    /// Like [`Self::run`], but in [`Self::default_cwd`].
+    fn run_here<C: Into<CommandLine>>(&self, command_line: C) -> anyhow::Result<ExecutionResult> {
+        self.run(command_line, self.default_cwd())
+    }
+
+    /// Like [`Self::run_checked`], but in [`Self::default_cwd`].
+    fn run_checked_here<C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+    ) -> anyhow::Result<ExecutionResult> {
+        self.run_checked(command_line, self.default_cwd())
+    }
+
+    /// Run a command with this process' stdin, stdout, and stderr, and wait for it to exit.
+    ///
+    /// This behaves like [`Self::exec`] from the user's point of view, as the command has the
+    /// terminal to itself while it runs, but this process keeps running and gets the exit
+    /// status back, e.g. to clean up afterwards. Nothing is captured.
+    fn run_foreground<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+    ) -> anyhow::Result<ExitStatus> {
+        let opts = CommandOpts {
+            capture_stdout: false,
+            capture_stderr: false,
+            ..Default::default()
+        };
+        Ok(self.run_with_opts(command_line, cwd, opts)?.status)
+    }
+
+    /// Run `script` with `/bin/sh`, and get the environment that it leaves behind.
+    ///
+    /// This captures the effect of scripts that are meant to be sourced, like activating a
+    /// virtualenv, as the script is run in the same shell as `env -0` afterwards. The result
+    /// is the whole environment, not just what changed. Anything the script writes to stdout
+    /// is sent to stderr instead. This fails if the script fails, or if the environment is
+    /// not valid UTF-8.
+    fn env_after_script<P: AsRef<AbsolutePath>>(
+        &self,
+        script: &str,
+        cwd: P,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let res = self.run_checked(
+            ["/bin/sh", "-c", "eval \"$1\" >&2 && env -0", "sh", script],
+            cwd,
+        )?;
+        Ok(res
+            .stdout()?
+            .split('\0')
+            .filter_map(|var| var.split_once('='))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect())
+    }
+
+    /// Run `base` with `items` appended as arguments, at most `max_args` at a time, like
+    /// `xargs -n`.
+    ///
+    /// Batches are run one after the other, and the final batch has whatever items are left,
+    /// so it may be smaller. Nothing is run if there are no items. As with `xargs`, every
+    /// batch is run even if earlier ones fail, after which this fails with
+    /// [`BatchesFailed`] if any of them did. Otherwise, the results are returned in the
+    /// order the batches ran. A batch that cannot be run at all, e.g. because the program
+    /// does not exist, is not a failed batch, so this fails with its error straight away, and
+    /// no later batches are run, as `xargs` does.
+    fn xargs<P: AsRef<AbsolutePath>, I: Iterator<Item = String>>(
+        &self,
+        base: CommandLine,
+        items: I,
+        cwd: P,
+        max_args: usize,
+    ) -> anyhow::Result<Vec<ExecutionResult>> {
+        if max_args == 0 {
+            return Err(anyhow::anyhow!(
+                "`max_args` must be at least 1 to run `{}`",
+                base
+            ));
+        }
+        let program = base.program()?.to_owned();
+        let mut results = vec![];
+        for batch in &items.chunks(max_args) {
+            results.push(self.run(base.clone_with(batch.collect::<Vec<_>>()), cwd.as_ref())?);
+        }
+        if results.iter().all(|res| res.status.success()) {
+            Ok(results)
+        } else {
+            Err(BatchesFailed { program, results }.into())
+        }
+    }
+
+    /// Run a command until it succeeds, running it again per `policy` when it fails, e.g.
+    /// for network-bound commands like `git fetch`.
+    ///
+    /// Each retry is logged as a warning. Once `policy.max_attempts` have been made, the
+    /// result or error of the last attempt is returned, so the result may be unsuccessful.
+    /// Results and errors that `policy` does not retry are returned straight away.
+    fn run_with_retries<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        policy: RetryPolicy,
+    ) -> anyhow::Result<ExecutionResult> {
+        let command_line = command_line.into();
+        let mut attempt = 1;
+        loop {
+            let res = self.run_with_opts(command_line.clone(), cwd.as_ref(), opts.clone());
+            let failure = match &res {
+                Ok(res) if !policy.should_retry(res) => None,
+                Ok(res) => Some(format!("exited with status `{}`", res.status)),
+                Err(e) if !policy.should_retry_error(e) => None,
+                Err(e) => Some(format!("failed: {:#}", e)),
+            };
+            match failure {
+                Some(failure) if attempt < policy.max_attempts => {
+                    log::warn!(
+                        "Attempt {} of {} of `{}` {}, retrying in {:?}",
+                        attempt,
+                        policy.max_attempts,
+                        command_line,
+                        failure,
+                        policy.backoff
+                    );
+                    std::thread::sleep(policy.backoff);
+                    attempt += 1;
+                }
+                _ => return res,
+            }
+        }
+    }
+
+    /// Run `commands` as a pipeline, like `a | b` in a shell but without one, and get the
+    /// result of the final command.
+    ///
+    /// `opts` apply to every command, except that only the first is given stdin, and only the
+    /// final command's stdout is captured (or passed through). If [`CommandOpts::pipefail`]
+    /// is set, this fails with [`CommandFailed`] for the first command before the final one
+    /// that exited unsuccessfully. The final command's exit status is not checked.
+    ///
+    /// By default, each command runs to completion before the next one starts, and its
+    /// stdout is given to the next one as stdin, and [`CommandOpts::timeout`] applies to each
+    /// command on its own. [`DefaultCommandRunner`] runs them concurrently instead, like
+    /// [`DefaultCommandRunner::run_pipeline_stages`], where the timeout applies to the whole
+    /// pipeline, and every command is stopped once it passes. Runners that wrap another
+    /// runner should pass pipelines on to it, so that they still run concurrently if the
+    /// wrapped runner supports it.
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let pipefail = opts.pipefail;
+        let mut results: Vec<(String, ExecutionResult)> = Vec::with_capacity(commands.len());
+        for (command_line, mut opts) in pipeline_stages(commands, opts)? {
+            if let Some((_, previous)) = results.last_mut() {
+                opts.stdin = Some(std::mem::take(&mut previous.output.stdout));
+            }
+            let program = command_line.program()?.to_owned();
+            results.push((
+                program,
+                self.run_with_opts(command_line, cwd.as_ref(), opts)?,
+            ));
+        }
+        finish_pipeline(results, pipefail)
+    }
+
     fn run_with_opts<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
         &self,
         command_line: C,
@@ -63,24 +302,61 @@ pub trait CommandRunner: Debug + Send + Sync {
     ) -> anyhow::Result<ExecutionResult> {
         let command_line = command_line.into();
         let program_name = command_line.program()?.to_owned();
-        log::info!("Running `{}` in `{}`", command_line, cwd.as_ref());
-        let output = self.run_inner(command_line, cwd.as_ref(), opts)?;
-        log::debug!(
-            "Completed `{}` with exit status `{}`",
+        let label = opts.log_label();
+        let completed_level = opts.log_level(log::Level::Debug);
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Running {}`{}` in `{}`",
+            label,
+            command_line,
+            cwd.as_ref()
+        );
+        let start = Instant::now();
+        let mut res = self.run_inner(command_line, cwd.as_ref(), opts)?;
+        // Runners that time commands themselves know better, e.g. without setup.
+        if res.duration.is_zero() {
+            res.duration = start.elapsed();
+        }
+        log::log!(
+            completed_level,
+            "Completed {}`{}` with exit status `{}`",
+            label,
             program_name,
-            output.status
+            res.status
         );
-        Ok(ExecutionResult(output))
+        res.program = program_name;
+        Ok(res)
     }
     fn run_inner(
         &self,
         command_line: CommandLine,
         cwd: &AbsolutePath,
         opts: CommandOpts,
-    ) -> anyhow::Result<Output>;
+    ) -> anyhow::Result<ExecutionResult>;
 
-    /// `exec()` the command, handing the process over to this command.
+    /// `exec()` the command in [`Self::default_cwd`] with the default [`CommandOpts`], handing
+    /// the process over to this command.
     fn exec(&self, command_line: CommandLine) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        self.exec_with_opts(command_line, &self.default_cwd(), CommandOpts::default())
+    }
+
+    /// `exec()` the command in `cwd`, with `opts`, handing the process over to this command.
+    ///
+    /// The command gets the same working directory, environment, and stdin as it would from
+    /// [`Self::run_inner`]. Options that need this process to keep running, like capturing
+    /// output or [`CommandOpts::timeout`], have no effect, so the command inherits this
+    /// process' stdout and stderr. [`CommandOpts::new_process_group`] and
+    /// [`CommandOpts::signal_forwarding`] are rejected, as is [`CommandOpts::pipe_stdin`].
+    /// This only returns if the command could not be exec'd.
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
     where
         Self: Sized;
 
@@ -98,6 +374,32 @@ pub trait CommandRunner: Debug + Send + Sync {
     fn hostname(&self) -> anyhow::Result<String> {
         Ok(hostname::get()?.to_string_lossy().to_string())
     }
+
+    /// The name of the user that this process runs as, from the user database, e.g. for
+    /// paths or log lines.
+    ///
+    /// Prefer this to running `whoami`, so that tests can stub it, as with
+    /// [`test::TestCommandRunner::username`].
+    fn username(&self) -> anyhow::Result<String> {
+        current_username()
+    }
+
+    /// Get an environment variable of this process, or `None` if it is not set or is not
+    /// valid UTF-8.
+    ///
+    /// Prefer this to [`std::env::var`], so that tests can stub variables like `$HOME`, as
+    /// with [`test::TestCommandRunner::vars`].
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    /// The directory that the `*_here` methods and [`Self::exec`] run commands in.
+    ///
+    /// This is the current directory of this process, unless the runner is configured with
+    /// something else, e.g. with [`DefaultCommandRunner::with_default_cwd`].
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        AbsolutePathBuf::current_dir()
+    }
 }
 
 #[derive(Clone)]
@@ -107,7 +409,161 @@ pub struct CommandOpts {
     /// Otherwise, stream to the calling process' stdout.
     pub capture_stdout: bool,
     pub stdin: Option<Vec<u8>>,
+    /// If set, [`DefaultCommandRunner::spawn`] connects stdin to a pipe that stays open, and
+    /// can be written to with [`CommandHandle::write_line`]. This cannot be combined with
+    /// `stdin`, and is ignored when running commands to completion.
+    pub pipe_stdin: bool,
+    /// If set, the command reads its stdin from this file.
+    ///
+    /// The file is opened and given to the command as its stdin directly, so the command
+    /// reads it incrementally, and it is never read into memory here. This is the way to
+    /// give a command large inputs. This cannot be combined with `stdin` or `pipe_stdin`.
+    pub stdin_file: Option<AbsolutePathBuf>,
+    /// Extra environment variables for just this command, which override any variables of
+    /// the same name from the runner.
     pub env: HashMap<String, String>,
+    /// If set, limit how quickly output is read from each of the command's streams.
+    ///
+    /// This is primarily for testing how code copes with a slow consumer (backpressure):
+    /// once the pipe's buffer is full, the command blocks on writing more output. It can also
+    /// be used to avoid overwhelming whatever the output is streamed to.
+    pub read_throttle: Option<BytesPerSecond>,
+    /// If set, replace the supplementary groups of the child with these gids.
+    ///
+    /// This is applied with `setgroups` in a `pre_exec` closure, i.e. in the child after `fork`
+    /// but before `exec`. When dropping privileges, supplementary groups must be set first,
+    /// then the gid, then the uid, as changing the uid removes the permission to change
+    /// groups. This requires privilege (generally root or `CAP_SETGID`), and spawning the
+    /// command fails with the OS error otherwise.
+    pub groups: Option<Vec<u32>>,
+    /// If set, run the command as this user id, with `setuid` in the child.
+    ///
+    /// This is applied after [`Self::groups`] and [`Self::gid`], as described there. If
+    /// `groups` is not set and this process is root, the child's supplementary groups are
+    /// cleared first, so that it does not keep root's. Changing to another user requires
+    /// privilege (generally root or `CAP_SETUID`), and spawning the command fails with the
+    /// OS error otherwise.
+    pub uid: Option<u32>,
+    /// If set, run the command with this group id, with `setgid` in the child after
+    /// [`Self::groups`] and before [`Self::uid`]. This requires privilege (generally root or
+    /// `CAP_SETGID`), and spawning the command fails with the OS error otherwise.
+    pub gid: Option<u32>,
+    /// If set, run the command with this nice value, with `setpriority` in the child, e.g. so
+    /// that background maintenance does not starve foreground work.
+    ///
+    /// Nice values range from -20 (the highest priority) to 19 (the lowest), and values
+    /// outside of that are clamped. This is the absolute value the command gets, not an
+    /// adjustment to this process' nice value. Anything below this process' nice value
+    /// requires privilege (generally root or `CAP_SYS_NICE`), and spawning the command fails
+    /// with the OS error otherwise.
+    pub nice: Option<i32>,
+    /// If set, run the command with this umask, e.g. `0o022`, so that the permissions of
+    /// files that it creates do not depend on the umask of this process.
+    ///
+    /// This is applied with `umask` in the child, so this process' umask is unchanged.
+    pub umask: Option<u32>,
+    /// If set, write the command's stdout here instead of capturing it or passing it through.
+    pub stdout_capture: Option<StdioCapture>,
+    /// If set, write the command's stderr here instead of capturing it or passing it through.
+    pub stderr_capture: Option<StdioCapture>,
+    /// If set, log the bytes given in `stdin` at debug level before writing them to the
+    /// command.
+    ///
+    /// They are logged as lossy UTF-8, and only the first few hundred bytes are shown. If
+    /// [`Self::secret_stdin`] is set, only how many bytes there are is logged.
+    pub log_stdin: bool,
+    /// If set, `stdin` holds something that should not be shown, like a password, so
+    /// [`Self::log_stdin`] logs only its length.
+    pub secret_stdin: bool,
+    /// A human-readable description of what the command is for, e.g. `fetching deps`.
+    ///
+    /// If set, this is included in log lines about the command, like
+    /// ``Running [fetching deps] `git fetch` ``, and in any traces of it.
+    pub label: Option<String>,
+    /// If set, send the command's stderr to wherever its stdout goes, so that both are
+    /// captured together in [`ExecutionResult::stdout`], in the order they were written.
+    ///
+    /// Whether the merged output is captured is up to [`Self::capture_stdout`], so
+    /// [`Self::capture_stderr`] has no effect, which is why it does not have to be turned off.
+    /// Sending stderr somewhere else with [`Self::stderr_capture`] contradicts this, and is
+    /// an error. [`ExecutionResult::is_merged`] is set on the result, whose stderr is always
+    /// empty.
+    pub merge_stderr_into_stdout: bool,
+    /// Whether signals sent to this process, like `SIGINT` from Ctrl-C, reach the command.
+    ///
+    /// See [`SignalForwarding`] for the options. Every option but the default runs the
+    /// command in a new process group, as if [`Self::new_process_group`] were set, but only
+    /// forwarded signals go to the whole group: stopping the command, e.g. on
+    /// [`Self::timeout`], still only signals the command itself unless
+    /// [`Self::new_process_group`] is set too. While any command that signals are forwarded
+    /// to is running, this process is not terminated by the forwarded signals itself, so
+    /// that it can wait for the command to shut down. This only applies to spawned commands,
+    /// and [`CommandRunner::exec_with_opts`] rejects it.
+    pub signal_forwarding: SignalForwarding,
+    /// If set, start the command in a new process group, and stop the whole group when the
+    /// command is stopped, e.g. by [`Self::timeout`] or [`CommandHandle::kill`].
+    ///
+    /// This makes sure that children of the command, e.g. of shell wrappers and build tools,
+    /// do not outlive it when it is stopped. Like any command in its own process group, the
+    /// command no longer gets signals from the terminal, unless they are forwarded per
+    /// [`Self::signal_forwarding`], which always starts a new group. With
+    /// [`Self::kill_on_drop`], dropping the handle also kills the whole group.
+    pub new_process_group: bool,
+    /// If set, kill the command with `SIGKILL` if its [`CommandHandle`] is dropped before it
+    /// has been waited on, e.g. when a test panics or returns early after spawning a server.
+    ///
+    /// If [`Self::new_process_group`] is also set, the whole group is killed. A new group
+    /// from [`Self::signal_forwarding`] alone is not enough for that, and only the command
+    /// itself is killed.
+    pub kill_on_drop: bool,
+    /// If set, show stdout and stderr on the terminal as the command runs, but only keep them
+    /// in the result if it fails.
+    ///
+    /// This gives quiet success, while the output is still available for diagnostics, e.g.
+    /// in the [`CommandFailed`] from [`CommandRunner::run_checked`]. [`Self::capture_stdout`]
+    /// and [`Self::capture_stderr`] are ignored. Output is buffered until the command exits,
+    /// so this is not meant for commands with huge output.
+    pub capture_on_failure: bool,
+    /// If set, stop the command if it is still running after this long, and fail with a
+    /// [`CommandTimeout`].
+    ///
+    /// The command is sent `SIGTERM`, and then `SIGKILL` if it does not exit within a few
+    /// seconds. For [`DefaultCommandRunner::run_supervised`], this is used if
+    /// [`SupervisionOpts::timeout`] is not set. It is ignored by
+    /// [`DefaultCommandRunner::spawn`].
+    pub timeout: Option<Duration>,
+    /// If set, decompress stdout from this format as it is read, so that the result and
+    /// anything stdout is streamed or written to get the decompressed bytes.
+    ///
+    /// [`ExecutionResult::stdout_bytes_read`] counts decompressed bytes too. Output that is
+    /// not in this format makes collecting the result fail.
+    pub decompress_stdout: Option<Decompression>,
+    /// How the OS should start the command. See [`SpawnMethod`].
+    pub spawn_method: SpawnMethod,
+    /// If set, [`CommandRunner::run_pipeline`] fails if any command but the final one exits
+    /// unsuccessfully, like `set -o pipefail` in a shell. This is set by default, and has no
+    /// effect on single commands.
+    pub pipefail: bool,
+    /// If set, log the per-command `Running` and `Completed` lines at trace level rather than
+    /// info and debug, e.g. for tight loops that run thousands of tiny commands. A pipeline
+    /// is only logged at trace level if every command in it is quiet.
+    pub quiet: bool,
+    /// If set, retain at most this many bytes of each of stdout and stderr in the result.
+    ///
+    /// Anything beyond that is read and discarded, so the command keeps running normally,
+    /// and [`ExecutionResult::stdout_truncated`] says whether anything was lost. This protects
+    /// against commands that print far more than expected. Output that is echoed or written
+    /// to a [`StdioCapture`] is not limited.
+    pub max_output_bytes: Option<usize>,
+    /// If set, also keep the chunks of stdout and stderr in the order they were read, for
+    /// [`ExecutionResult::interleaved`], while [`ExecutionResult::stdout`] and
+    /// [`ExecutionResult::stderr`] are still kept separately.
+    ///
+    /// This is for showing a transcript of what the command printed. As the two streams are
+    /// separate pipes, the order is only as good as the order that they were read in, which
+    /// is usually, but not always, the order they were written in. Only captured streams are
+    /// included, and [`Self::max_output_bytes`] does not limit this.
+    pub capture_interleaved: bool,
 }
 
 impl Default for CommandOpts {
@@ -116,367 +572,3871 @@ impl Default for CommandOpts {
             capture_stderr: true,
             capture_stdout: true,
             stdin: None,
+            pipe_stdin: false,
+            stdin_file: None,
             env: HashMap::default(),
+            read_throttle: None,
+            groups: None,
+            uid: None,
+            gid: None,
+            nice: None,
+            umask: None,
+            stdout_capture: None,
+            stderr_capture: None,
+            log_stdin: false,
+            secret_stdin: false,
+            label: None,
+            merge_stderr_into_stdout: false,
+            signal_forwarding: SignalForwarding::default(),
+            new_process_group: false,
+            kill_on_drop: false,
+            capture_on_failure: false,
+            timeout: None,
+            decompress_stdout: None,
+            spawn_method: SpawnMethod::default(),
+            pipefail: true,
+            quiet: false,
+            max_output_bytes: None,
+            capture_interleaved: false,
+        }
+    }
+}
+
+impl CommandOpts {
+    /// The level to log a per-command line at, which is normally `level`, per [`Self::quiet`].
+    pub(crate) fn log_level(&self, level: log::Level) -> log::Level {
+        if self.quiet {
+            log::Level::Trace
+        } else {
+            level
+        }
+    }
+
+    /// The label to put before the command in log lines, if any.
+    pub(crate) fn log_label(&self) -> String {
+        match &self.label {
+            Some(label) => format!("[{}] ", label),
+            None => String::new(),
         }
     }
 }
 
+/// Somewhere other than memory to send one of a command's output streams.
+///
+/// Output that goes to one of these is not available in the [`ExecutionResult`], though it is
+/// still counted in e.g. [`ExecutionResult::stdout_bytes_read`]. stdout and stderr should not
+/// be sent to the same path, as each stream writes to its own handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StdioCapture {
+    /// Append everything to a single file.
+    File(PathBuf),
+    /// Append to `path`, rotating it when it reaches `max_size` bytes, and keeping up to
+    /// `keep` rotated files as `path.1` (the most recent) through `path.{keep}`.
+    ///
+    /// The size is checked as output is written, before each chunk read from the command. If
+    /// the chunk would take the file past `max_size`, the file is rotated first, so files
+    /// only exceed `max_size` if a single chunk is bigger than it. Lines may be split across
+    /// files. This is meant for long-running services whose logs must not grow unbounded.
+    RotatingFile {
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+    },
+    /// Write everything to a new temp file, which is available from the result via e.g.
+    /// [`ExecutionResult::stdout_file`], and is deleted once the result is dropped.
+    ///
+    /// This is useful to process large output with APIs that want a file, or
+    /// `impl Read + Seek`.
+    TempFile,
+}
+
+/// How a command's process is created, per [`CommandOpts::spawn_method`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpawnMethod {
+    /// Whatever [`std::process::Command`] picks, which is `fork` and `exec` whenever
+    /// something has to run in the child before `exec`.
+    #[default]
+    ForkExec,
+    /// Make sure that the command can be started with `posix_spawn`.
+    ///
+    /// `posix_spawn` does not copy this process' page tables the way `fork` does, so it is
+    /// much faster for large processes that spawn many commands. [`std::process::Command`]
+    /// uses it whenever nothing has to run in the child before `exec`, so options that
+    /// need that, e.g. [`CommandOpts::groups`], [`CommandOpts::umask`], and
+    /// [`CommandOpts::merge_stderr_into_stdout`], are rejected with an error. Programs
+    /// without a `/` are looked up on the `PATH` the command would see beforehand, which is
+    /// otherwise done in the child. On old versions of libc, e.g. glibc before 2.29, std may
+    /// still fall back to `fork` to set the working directory.
+    PosixSpawn,
+}
+
+/// Point `sink` at `capture`, rather than at memory or the terminal.
+fn redirect(sink: &mut StreamSink, capture: Option<&StdioCapture>) -> anyhow::Result<()> {
+    let echo: Box<dyn Write + Send> = match capture {
+        None => return Ok(()),
+        Some(StdioCapture::File(path)) => Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        Some(StdioCapture::RotatingFile {
+            path,
+            max_size,
+            keep,
+        }) => Box::new(RotatingFile::open(path, *max_size, *keep)?),
+        Some(StdioCapture::TempFile) => {
+            let file = NamedTempFile::new()?;
+            // Write through a separate handle, so that the result's handle is still at the
+            // start of the file.
+            let echo = Box::new(file.reopen()?);
+            sink.temp_file = Some(file);
+            echo
+        }
+    };
+    sink.echo = Some(echo);
+    sink.retain = false;
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct DefaultCommandRunner {
-    ignored_env_vars: Option<HashSet<String>>,
-    allowed_env_vars: Option<HashSet<String>>,
+    env_profile: EnvProfile,
+    verbose: bool,
+    shebang_fallback: bool,
+    default_cwd: Option<AbsolutePathBuf>,
 }
 
 impl Default for DefaultCommandRunner {
     fn default() -> Self {
         Self {
-            ignored_env_vars: Some(hashset!["GIT_DIR".to_owned()]),
-            allowed_env_vars: None,
+            env_profile: EnvProfile::new().ignoring(&["GIT_DIR"]),
+            verbose: false,
+            shebang_fallback: false,
+            default_cwd: None,
         }
     }
 }
 
-impl DefaultCommandRunner {
-    pub fn ignoring_env<S: ToString>(ignored: &[S]) -> Self {
-        let ignored = ignored.iter().map(S::to_string).collect();
-        Self {
-            ignored_env_vars: Some(ignored),
-            allowed_env_vars: None,
-        }
+/// A chainable way to configure a [`DefaultCommandRunner`], from
+/// [`DefaultCommandRunner::builder`].
+///
+/// Anything that is not set is as in [`DefaultCommandRunner::default`]. Every method here
+/// calls the matching setter on [`DefaultCommandRunner`], which is where options are
+/// implemented and documented, so a new option only needs a one-line method here.
+#[derive(Debug, Default)]
+pub struct DefaultCommandRunnerBuilder(DefaultCommandRunner);
+
+impl DefaultCommandRunnerBuilder {
+    /// Like [`DefaultCommandRunner::with_profile`].
+    pub fn env_profile(self, profile: EnvProfile) -> Self {
+        Self(self.0.with_profile(profile))
     }
 
-    pub fn allowing_env<S: ToString>(ignored: &[S]) -> Self {
-        let allowed = ignored.iter().map(S::to_string).collect();
-        Self {
-            ignored_env_vars: None,
-            allowed_env_vars: Some(allowed),
-        }
+    /// Like [`DefaultCommandRunner::ignoring_env`], but keeping the variables that the
+    /// profile so far sets, per [`EnvProfile::ignoring`].
+    pub fn ignoring_env<S: ToString>(self, ignored: &[S]) -> Self {
+        let profile = self.0.env_profile.clone().ignoring(ignored);
+        self.env_profile(profile)
     }
 
-    fn env_vars(&self) -> HashMap<String, String> {
-        if let Some(ignored) = self.ignored_env_vars.as_ref() {
-            std::env::vars()
-                .filter(|(name, _)| !ignored.contains(name.as_str()))
-                .collect()
-        } else if let Some(allowed) = self.allowed_env_vars.as_ref() {
-            std::env::vars()
-                .filter(|(name, _)| allowed.contains(name.as_str()))
-                .collect()
-        } else {
-            std::env::vars().collect()
-        }
+    /// Like [`DefaultCommandRunner::allowing_env`], but keeping the variables that the
+    /// profile so far sets, per [`EnvProfile::allowing`].
+    pub fn allowing_env<S: ToString>(self, allowed: &[S]) -> Self {
+        let profile = self.0.env_profile.clone().allowing(allowed);
+        self.env_profile(profile)
     }
-}
 
-impl CommandRunner for DefaultCommandRunner {
-    fn run_inner(
-        &self,
-        command_line: CommandLine,
-        cwd: &AbsolutePath,
-        opts: CommandOpts,
-    ) -> anyhow::Result<Output> {
-        let (stderr, stderr_tee) = if opts.capture_stderr {
-            let tee = Tee::new(std::io::stderr())?;
-            (tee.clone().into(), Some(tee))
-        } else {
-            (Stdio::inherit(), None)
-        };
-        let stdin = if opts.stdin.is_some() {
-            Stdio::piped()
-        } else {
-            Stdio::inherit()
-        };
-        let stdout = if opts.capture_stdout {
-            Stdio::piped()
-        } else {
-            Stdio::inherit()
-        };
-        let mut env_vars = self.env_vars();
-        for (k, v) in opts.env {
-            env_vars.insert(k, v);
-        }
-        let mut child = std::process::Command::new(command_line.program()?)
-            .args(command_line.args()?)
-            .current_dir(cwd)
-            .env_clear()
-            .envs(env_vars)
-            .stdin(stdin)
-            .stdout(stdout)
-            .stderr(stderr)
-            .spawn()?;
-        if let (Some(stdin), Some(stdin_bytes)) = (child.stdin.as_mut(), opts.stdin) {
-            stdin.write_all(&stdin_bytes)?;
-        }
-        let mut res = child.wait_with_output()?;
+    /// Like [`DefaultCommandRunner::verbose`].
+    pub fn verbose(self, verbose: bool) -> Self {
+        Self(self.0.verbose(verbose))
+    }
 
-        if let Some(tee) = stderr_tee {
-            res.stderr = tee.get_output()?;
-        }
-        Ok(res)
+    /// Like [`DefaultCommandRunner::shebang_fallback`].
+    pub fn shebang_fallback(self, enabled: bool) -> Self {
+        Self(self.0.shebang_fallback(enabled))
     }
 
-    fn exec(&self, command_line: CommandLine) -> anyhow::Result<()>
-    where
-        Self: Sized,
-    {
-        log::info!("Exec'ing `{}`", command_line);
+    /// Like [`DefaultCommandRunner::with_default_cwd`].
+    pub fn default_cwd(self, cwd: AbsolutePathBuf) -> Self {
+        Self(self.0.with_default_cwd(cwd))
+    }
 
-        Err(std::process::Command::new(command_line.program()?)
-            .args(command_line.args()?)
-            .exec()
-            .into())
+    /// Finish configuring, and get the runner.
+    pub fn build(self) -> DefaultCommandRunner {
+        self.0
     }
 }
 
-pub mod test {
-    use std::collections::VecDeque;
-    use std::ops::Deref;
-    use std::os::unix::process::ExitStatusExt;
-    use std::process::ExitStatus;
-    use std::process::Output;
-    use std::sync::RwLock;
-
-    use paths::AbsolutePath;
-    use paths::AbsolutePathBuf;
-
-    use super::CommandOpts;
-    use super::CommandRunner;
-    use crate::CommandLine;
-
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    pub struct Invocation {
-        command_line: CommandLine,
-        cwd: AbsolutePathBuf,
+impl DefaultCommandRunner {
+    /// Start configuring a runner, which reads better than a constructor when several
+    /// things are changed.
+    ///
+    /// ```
+    /// use commands::DefaultCommandRunner;
+    ///
+    /// let runner = DefaultCommandRunner::builder()
+    ///     .ignoring_env(&["GIT_DIR", "GIT_WORK_TREE"])
+    ///     .verbose(true)
+    ///     .build();
+    /// ```
+    pub fn builder() -> DefaultCommandRunnerBuilder {
+        DefaultCommandRunnerBuilder::default()
     }
 
-    impl Deref for Invocation {
-        type Target = CommandLine;
+    pub fn ignoring_env<S: ToString>(ignored: &[S]) -> Self {
+        Self::default().with_profile(EnvProfile::new().ignoring(ignored))
+    }
 
-        fn deref(&self) -> &Self::Target {
-            &self.command_line
-        }
+    pub fn allowing_env<S: ToString>(ignored: &[S]) -> Self {
+        Self::default().with_profile(EnvProfile::new().allowing(ignored))
     }
 
-    impl Invocation {
-        pub fn new<P: Into<AbsolutePathBuf>>(command_line: CommandLine, cwd: P) -> Self {
-            Self {
-                command_line,
-                cwd: cwd.into(),
-            }
-        }
+    /// Use `profile` for the environment of commands, instead of the filter that this runner
+    /// was created with.
+    pub fn with_profile(mut self, profile: EnvProfile) -> Self {
+        self.env_profile = profile;
+        self
     }
 
-    #[derive(Debug)]
-    pub struct TestCommandRunner {
-        pub hostname: String,
-        pub temp: tempfile::TempDir,
-        pub issued_commands: RwLock<Vec<Invocation>>,
-        pub outputs: RwLock<VecDeque<Output>>,
+    /// If set, print each command to stderr with a `+ ` prefix before running it.
+    ///
+    /// This is similar to `set -x` in a shell script. Commands are quoted so that they can be
+    /// pasted into a shell, and are printed regardless of how `log` is configured. Secret
+    /// arguments are shown as `****`, as in [`CommandLine::to_redacted_shell_string`].
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
     }
 
-    impl Default for TestCommandRunner {
-        fn default() -> Self {
-            Self {
-                hostname: "local.example.com".to_owned(),
-                temp: tempfile::tempdir().expect("to be able to create a tempdir"),
-                issued_commands: RwLock::new(vec![]),
-                outputs: RwLock::new(Default::default()),
-            }
-        }
+    /// If set, retry programs that the OS refuses to execute through their shebang.
+    ///
+    /// When spawning fails because the program is not executable (e.g. a script without the
+    /// exec bit), or because the OS does not know how to run it, and the file starts with
+    /// `#!`, the command is run again as the interpreter named there, followed by any argument
+    /// from the shebang line, the path to the script, and the original arguments. Programs
+    /// without a `/` are looked up in the `PATH` that the command would see.
+    ///
+    /// This is off by default, as it runs files that the OS was asked not to run.
+    pub fn shebang_fallback(mut self, enabled: bool) -> Self {
+        self.shebang_fallback = enabled;
+        self
     }
 
-    impl TestCommandRunner {
-        pub fn new() -> Self {
-            Self::default()
-        }
+    /// Run the `*_here` methods like [`CommandRunner::run_here`] in `cwd`, rather than in the
+    /// current directory of this process.
+    ///
+    /// This is useful when there is a logical working directory, like a project root, or
+    /// when the process' current directory is not reliable, e.g. in a long-running daemon.
+    pub fn with_default_cwd(mut self, cwd: AbsolutePathBuf) -> Self {
+        self.default_cwd = Some(cwd);
+        self
+    }
 
-        pub fn with_results<I: IntoIterator<Item = (i32, S)>, S: ToString>(
-            i: I,
-        ) -> anyhow::Result<Self> {
-            let issued_commands = RwLock::new(Vec::new());
-            let outputs: VecDeque<_> = i
-                .into_iter()
-                .map(|(code, stdout)| {
-                    let _s = String::new();
-                    Output {
-                        status: ExitStatus::from_raw(code),
-                        stdout: stdout.to_string().into_bytes(),
-                        stderr: vec![],
-                    }
-                })
-                .collect();
-            Ok(Self {
-                issued_commands,
-                outputs: RwLock::new(outputs),
-                ..Default::default()
-            })
+    pub(crate) fn print_if_verbose(&self, command_line: &CommandLine) {
+        if self.verbose {
+            eprintln!("+ {}", command_line.to_redacted_shell_string());
         }
     }
 
-    impl CommandRunner for TestCommandRunner {
-        fn run_inner(
-            &self,
-            command_line: CommandLine,
-            cwd: &AbsolutePath,
-            _opts: CommandOpts,
-        ) -> anyhow::Result<Output> {
-            let invocation = Invocation::new(command_line, cwd);
-            self.issued_commands.write().unwrap().push(invocation);
-            Ok(self
-                .outputs
-                .write()
-                .unwrap()
-                .pop_front()
-                .expect("An output"))
-        }
+    /// Start a command in the background, and return a handle to wait on or kill it.
+    ///
+    /// Stdout and stderr are captured according to `opts`, as with [`CommandRunner::run`].
+    pub fn spawn<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<CommandHandle> {
+        let command_line = command_line.into();
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Spawning {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref()
+        );
+        self.print_if_verbose(&command_line);
+        self.spawn_with_sinks(
+            command_line,
+            cwd.as_ref(),
+            opts,
+            StreamSink::retained(),
+            echoed_stderr(),
+        )
+    }
 
-        fn exec(&self, _command_line: CommandLine) -> anyhow::Result<()>
-        where
-            Self: Sized,
-        {
-            Ok(())
-        }
+    /// Like [`Self::spawn`], but in [`CommandRunner::default_cwd`], e.g. for a server that
+    /// a test starts and later kills.
+    pub fn spawn_here<C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        opts: CommandOpts,
+    ) -> anyhow::Result<CommandHandle> {
+        self.spawn(command_line, self.default_cwd(), opts)
+    }
 
-        fn hostname(&self) -> anyhow::Result<String> {
-            Ok(self.hostname.clone())
+    /// Like [`Self::spawn`], but read stdout directly while the command runs in the
+    /// background, e.g. as the producer of a pipeline that continues in Rust code.
+    ///
+    /// Stdout is always piped to the reader, rather than captured, so this fails if `opts`
+    /// sets [`CommandOpts::stdout_capture`] or [`CommandOpts::decompress_stdout`]. Stderr is
+    /// captured per `opts`, and is in the result of [`ChildStdoutReader::wait`].
+    pub fn spawn_stdout_reader<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ChildStdoutReader> {
+        let command_line = command_line.into();
+        if opts.stdout_capture.is_some() || opts.decompress_stdout.is_some() {
+            return Err(anyhow::anyhow!(
+                "`stdout_capture` and `decompress_stdout` cannot be set when reading the stdout \
+                 of `{}`",
+                command_line
+            ));
         }
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Spawning {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref()
+        );
+        self.print_if_verbose(&command_line);
+        let opts = CommandOpts {
+            capture_stdout: true,
+            ..opts
+        };
+        let (handle, stdout) = self.spawn_handle(
+            command_line,
+            cwd.as_ref(),
+            opts,
+            StreamSink::retained(),
+            echoed_stderr(),
+            true,
+        )?;
+        let stdout = stdout.expect("stdout is piped");
+        Ok(ChildStdoutReader::new(stdout, handle))
     }
-}
-
-#[derive(thiserror::Error, Debug)]
-#[error("Could not get $HOME!")]
-pub struct MissingHomeError;
+
+    /// Like [`Self::spawn`], but send output over a bounded channel as it is produced,
+    /// instead of retaining it in the result.
+    ///
+    /// Output from each stream is batched per `streaming`, which saves overhead for chatty
+    /// commands. Any partially filled batches are sent once the command closes its output,
+    /// after which the channel is closed. Only the streams that `opts` captures are sent.
+    ///
+    /// If the receiver falls behind, the command is eventually blocked while writing output,
+    /// so drain the receiver before calling [`CommandHandle::wait`]. Dropping the receiver is
+    /// fine; any further output is discarded. The result from [`CommandHandle::wait`] has
+    /// empty stdout / stderr, but still reports how many bytes were read.
+    pub fn spawn_streaming<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        streaming: StreamingOpts,
+    ) -> anyhow::Result<(CommandHandle, Receiver<(Stream, Vec<u8>)>)> {
+        let (raw_tx, raw_rx) = sync_channel(streaming.channel_capacity);
+        let (tx, rx) = sync_channel(streaming.channel_capacity);
+        crate::capture::coalesce(raw_rx, tx, streaming);
+        let stdout = StreamSink {
+            forward: Some(raw_tx.clone()),
+            ..Default::default()
+        };
+        let stderr = StreamSink {
+            forward: Some(raw_tx),
+            ..Default::default()
+        };
+        let command_line = command_line.into();
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Spawning {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref()
+        );
+        self.print_if_verbose(&command_line);
+        let handle = self.spawn_with_sinks(command_line, cwd.as_ref(), opts, stdout, stderr)?;
+        Ok((handle, rx))
+    }
+
+    /// Run a command, and get everything that happens to it as [`CommandEvent`]s, in order.
+    ///
+    /// This is the most general way to stream a command: `Started` comes first, then chunks
+    /// of stdout and stderr as they are read, and finally `Exited` once the command has
+    /// closed its output and exited. Output is sent as soon as it is read, without batching,
+    /// and only for the streams that `opts` captures.
+    ///
+    /// The command runs in the background, and blocks once it has written a little output
+    /// that was not consumed. Dropping the iterator early leaves the command running, with
+    /// its remaining output discarded. If waiting on the command fails, that is logged and
+    /// there is no `Exited` event.
+    pub fn run_events<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<impl Iterator<Item = CommandEvent>> {
+        let (tx, rx) = sync_channel(StreamingOpts::default().channel_capacity);
+        let stdout = StreamSink {
+            forward: Some(tx.clone()),
+            ..Default::default()
+        };
+        let stderr = StreamSink {
+            forward: Some(tx),
+            ..Default::default()
+        };
+        let command_line = command_line.into();
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Running {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref()
+        );
+        self.print_if_verbose(&command_line);
+        let opts = CommandOpts {
+            pipe_stdin: false,
+            ..opts
+        };
+        let handle = self.spawn_with_sinks(command_line, cwd.as_ref(), opts, stdout, stderr)?;
+        Ok(CommandEvents::new(handle, rx))
+    }
+
+    /// Run `stages` as a pipeline, like `a | b | c` in a shell, without spawning a shell.
+    ///
+    /// Each stage's stdout is connected directly to the next stage's stdin. Everything else
+    /// about a stage follows its own [`CommandOpts`], so e.g. stderr of each stage can be
+    /// captured, passed through to this process, or written to a file independently of the
+    /// other stages, and is never part of what is piped. Only the first stage may be given
+    /// stdin, and only the final stage's stdout is captured (or passed through).
+    ///
+    /// All stages run concurrently, and all of them are waited on. The results are returned
+    /// in the same order as `stages`; the stdout of every stage but the last is empty. As in
+    /// a shell, a stage that exits early may cause the stages before it to be killed by
+    /// `SIGPIPE`. No stage's exit status is checked. If a stage cannot be started, the stages
+    /// that were already started are killed, so [`CommandOpts::kill_on_drop`] is ignored.
+    ///
+    /// The shortest [`CommandOpts::timeout`] of any stage applies to the whole pipeline, as
+    /// one stage that hangs usually blocks the others. Once it passes, every stage that is
+    /// still running is stopped as for a single command, and this fails with
+    /// [`CommandTimeout`]. [`CommandOpts::capture_interleaved`] applies to each stage on its
+    /// own, so for every stage but the last, only stderr is in it.
+    pub fn run_pipeline_stages<P: AsRef<AbsolutePath>>(
+        &self,
+        stages: Vec<(CommandLine, CommandOpts)>,
+        cwd: P,
+    ) -> anyhow::Result<Vec<ExecutionResult>> {
+        let cwd = cwd.as_ref();
+        let description = stages.iter().map(|(c, _)| c.to_string()).join(" | ");
+        // A pipeline is only quiet if all of it is, so that no stage goes unlogged.
+        let quiet = stages.iter().all(|(_, opts)| opts.quiet);
+        let log_level = |level| if quiet { log::Level::Trace } else { level };
+        log::log!(
+            log_level(log::Level::Info),
+            "Running pipeline `{}` in `{}`",
+            description,
+            cwd
+        );
+        if self.verbose {
+            eprintln!(
+                "+ {}",
+                stages
+                    .iter()
+                    .map(|(c, _)| c.to_redacted_shell_string())
+                    .join(" | ")
+            );
+        }
+
+        for (i, (command_line, opts)) in stages.iter().enumerate() {
+            command_line.program()?;
+            if i > 0 && (opts.stdin.is_some() || opts.pipe_stdin || opts.stdin_file.is_some()) {
+                return Err(anyhow::anyhow!(
+                    "Only the first stage of a pipeline can be given stdin, but `{}` was",
+                    command_line
+                ));
+            }
+        }
+
+        let count = stages.len();
+        let timeout = stages.iter().filter_map(|(_, opts)| opts.timeout).min();
+        let mut handles = Vec::with_capacity(count);
+        let mut previous_stdout: Option<OwnedFd> = None;
+        for (i, (command_line, opts)) in stages.into_iter().enumerate() {
+            let is_last = i + 1 == count;
+            let opts = CommandOpts {
+                pipe_stdin: false,
+                capture_stdout: opts.capture_stdout || !is_last,
+                stdout_capture: if is_last { opts.stdout_capture } else { None },
+                ..opts
+            };
+            let transcript = opts.capture_interleaved.then(Transcript::default);
+            let mut stdout = StreamSink {
+                transcript: transcript.clone(),
+                ..StreamSink::retained()
+            };
+            let mut stderr = StreamSink {
+                transcript: transcript.clone(),
+                ..echoed_stderr()
+            };
+            let mut child = self.spawn_child(
+                &command_line,
+                cwd,
+                &opts,
+                previous_stdout.as_ref(),
+                &mut stdout,
+                &mut stderr,
+            )?;
+            previous_stdout = if is_last {
+                None
+            } else {
+                child.stdout.take().map(OwnedFd::from)
+            };
+            // Every handle is waited on below, so this only kills (and reaps) the stages that
+            // were already started if a later one fails to start.
+            handles.push(
+                CommandHandle::new(
+                    command_line.program()?.to_owned(),
+                    child,
+                    opts.stdin,
+                    stdout,
+                    stderr,
+                )
+                .merged(opts.merge_stderr_into_stdout)
+                .signal_group(opts.new_process_group)
+                .kill_on_drop(true)
+                .quiet(opts.quiet)
+                .transcript(transcript)
+                .forward_signals(opts.signal_forwarding)?,
+            );
+        }
+        // Wait on every stage, even once one fails, so that none are left running. Every stage
+        // started at about the same time, so they share a deadline.
+        let mut results = Vec::with_capacity(count);
+        let mut error = None;
+        for handle in handles {
+            match handle.wait_with_timeout(timeout) {
+                Ok(res) => results.push(res),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        log::log!(
+            log_level(log::Level::Debug),
+            "Completed pipeline `{}`",
+            description
+        );
+        Ok(results)
+    }
+
+    /// Run a command for a human to read, piping its stdout through a pager like `less` if
+    /// this process' stdout is a terminal, as `git` does.
+    ///
+    /// The pager is `$PAGER`, split on whitespace, or `less` if that is not set. `LESS` is set
+    /// to `FRX` for the pager if it is not already set, so that output that fits on one
+    /// screen is shown without paging. If stdout is not a terminal, `PAGER` is empty, or the
+    /// pager cannot be found on `PATH`, the command's stdout goes straight to this process'
+    /// stdout instead. Either way, stdout is not captured, and the exit status of
+    /// the command (not of the pager) is returned without being checked.
+    pub fn run_paged<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+    ) -> anyhow::Result<ExecutionResult> {
+        let opts = CommandOpts {
+            capture_stdout: false,
+            ..Default::default()
+        };
+        // Safe, as this only inspects the file descriptor.
+        let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let pager = match self.pager(&opts) {
+            Some(pager) if is_tty => pager,
+            _ => return self.run_with_opts(command_line, cwd, opts),
+        };
+        self.run_through_pager(command_line.into(), cwd.as_ref(), pager)
+    }
+
+    /// The pager that [`Self::run_paged`] should use, if one is available.
+    fn pager(&self, opts: &CommandOpts) -> Option<CommandLine> {
+        let env = self.dry_env(opts);
+        let pager = env.get("PAGER").map(String::as_str).unwrap_or("less");
+        let pager = CommandLine::from(pager.split_whitespace());
+        if pager.is_empty() {
+            return None;
+        }
+        match pager.resolve_program_absolute(env.get("PATH").map_or("", String::as_str)) {
+            Ok(pager) => Some(pager),
+            Err(e) => {
+                log::debug!("Not paging output: {}", e);
+                None
+            }
+        }
+    }
+
+    fn run_through_pager(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        pager: CommandLine,
+    ) -> anyhow::Result<ExecutionResult> {
+        let mut pager_opts = CommandOpts {
+            capture_stdout: false,
+            capture_stderr: false,
+            ..Default::default()
+        };
+        if !self.dry_env(&pager_opts).contains_key("LESS") {
+            pager_opts.env.insert("LESS".to_owned(), "FRX".to_owned());
+        }
+        let mut results = self.run_pipeline_stages(
+            vec![(command_line, CommandOpts::default()), (pager, pager_opts)],
+            cwd,
+        )?;
+        Ok(results.swap_remove(0))
+    }
+
+    /// Run a command to completion, while enforcing `supervision`'s limits, and passing its
+    /// output to `on_output` as it is produced.
+    ///
+    /// This is the one way to combine streaming, a timeout, an output limit, and
+    /// cancellation, e.g. for untrusted or long-running commands. If any limit is hit, the
+    /// command is killed with `SIGKILL`. Either way, the outcome says why the command ended,
+    /// and the result has all of the output that was read, which is also passed to
+    /// `on_output`. The result's exit status is not checked.
+    ///
+    /// `on_output` is called on the calling thread with chunks of stdout or stderr, in the
+    /// order they were read, for each stream that `opts` captures. Commands that fork
+    /// children which keep the output streams open are only considered done once those
+    /// streams are closed.
+    ///
+    /// The command always runs in its own process group, as with
+    /// [`CommandOpts::new_process_group`], so that killing it also kills such children.
+    /// Otherwise they would keep its output open for as long as they run, regardless of the
+    /// limits. So that it still gets e.g. `SIGINT` from the terminal, the default
+    /// [`SignalForwarding::Inherit`] is treated as [`SignalForwarding::Forward`].
+    pub fn run_supervised<P, C, F>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        supervision: SupervisionOpts,
+        on_output: F,
+    ) -> anyhow::Result<(SupervisedOutcome, ExecutionResult)>
+    where
+        P: AsRef<AbsolutePath>,
+        C: Into<CommandLine>,
+        F: FnMut(Stream, &[u8]),
+    {
+        let (tx, rx) = sync_channel(StreamingOpts::default().channel_capacity);
+        let stdout = StreamSink {
+            forward: Some(tx.clone()),
+            ..StreamSink::retained()
+        };
+        let stderr = StreamSink {
+            forward: Some(tx),
+            ..StreamSink::retained()
+        };
+        let command_line = command_line.into();
+        log::log!(
+            opts.log_level(log::Level::Info),
+            "Running {}`{}` in `{}` with {:?}",
+            opts.log_label(),
+            command_line,
+            cwd.as_ref(),
+            supervision
+        );
+        self.print_if_verbose(&command_line);
+        let opts = CommandOpts {
+            pipe_stdin: false,
+            new_process_group: true,
+            signal_forwarding: match opts.signal_forwarding {
+                SignalForwarding::Inherit => SignalForwarding::Forward,
+                mode => mode,
+            },
+            ..opts
+        };
+        let supervision = SupervisionOpts {
+            timeout: supervision.timeout.or(opts.timeout),
+            ..supervision
+        };
+        let handle = self.spawn_with_sinks(command_line, cwd.as_ref(), opts, stdout, stderr)?;
+        crate::supervise::supervise(handle, rx, &supervision, on_output)
+    }
+
+    /// Read the first bit of a command's output, e.g. a few lines of `tail -f`, stopping once
+    /// either `max_bytes` have been read or `max_time` has passed, whichever comes first.
+    ///
+    /// The command is then killed, and what was read so far is returned, along with which
+    /// limit was hit ([`SupervisedOutcome::OutputLimitExceeded`] for bytes, or
+    /// [`SupervisedOutcome::TimedOut`] for time), or [`SupervisedOutcome::Completed`] if the
+    /// command exited first. Output is read in chunks, so slightly more than `max_bytes` may
+    /// be returned. Stdout and stderr count towards `max_bytes` together, so set
+    /// [`CommandOpts::capture_stderr`] to `false` to only sample stdout.
+    ///
+    /// Unlike treating the limits as a failure, hitting them is the expected way for this to
+    /// finish, so it is not an error, and the exit status (which will show that the command
+    /// was killed) can generally be ignored.
+    pub fn sample_output<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        &self,
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        max_bytes: usize,
+        max_time: Duration,
+    ) -> anyhow::Result<(SupervisedOutcome, ExecutionResult)> {
+        let supervision = SupervisionOpts {
+            timeout: Some(max_time),
+            // The supervisor stops once output exceeds its limit, while a sample is done
+            // once it has reached `max_bytes`.
+            max_output_bytes: Some(max_bytes.saturating_sub(1)),
+            ..Default::default()
+        };
+        self.run_supervised(command_line, cwd, opts, supervision, |_, _| {})
+    }
+
+    fn spawn_with_sinks(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+        stdout: StreamSink,
+        stderr: StreamSink,
+    ) -> anyhow::Result<CommandHandle> {
+        let (handle, _) = self.spawn_handle(command_line, cwd, opts, stdout, stderr, false)?;
+        Ok(handle)
+    }
+
+    /// Like [`Self::spawn_with_sinks`], but if `take_stdout`, hand stdout back to be read
+    /// directly, instead of reading it into `stdout`.
+    fn spawn_handle(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+        mut stdout: StreamSink,
+        mut stderr: StreamSink,
+        take_stdout: bool,
+    ) -> anyhow::Result<(CommandHandle, Option<ChildStdout>)> {
+        let transcript = opts.capture_interleaved.then(Transcript::default);
+        stdout.transcript = transcript.clone();
+        stderr.transcript = transcript.clone();
+        let mut child =
+            self.spawn_child(&command_line, cwd, &opts, None, &mut stdout, &mut stderr)?;
+        let taken = if take_stdout {
+            child.stdout.take()
+        } else {
+            None
+        };
+        let handle = CommandHandle::new(
+            command_line.program()?.to_owned(),
+            child,
+            opts.stdin,
+            stdout,
+            stderr,
+        )
+        .merged(opts.merge_stderr_into_stdout)
+        .signal_group(opts.new_process_group)
+        .kill_on_drop(opts.kill_on_drop)
+        .quiet(opts.quiet)
+        .transcript(transcript)
+        .forward_signals(opts.signal_forwarding)?;
+        Ok((handle, taken))
+    }
+
+    /// Spawn `command_line`, and point `stdout` and `stderr` wherever `opts` says.
+    ///
+    /// If `stdin` is provided, the child reads from it instead of per `opts`.
+    fn spawn_child(
+        &self,
+        command_line: &CommandLine,
+        cwd: &AbsolutePath,
+        opts: &CommandOpts,
+        stdin: Option<&OwnedFd>,
+        stdout: &mut StreamSink,
+        stderr: &mut StreamSink,
+    ) -> anyhow::Result<Child> {
+        if opts.pipe_stdin && opts.stdin.is_some() {
+            return Err(anyhow::anyhow!(
+                "`stdin` and `pipe_stdin` cannot both be set when spawning `{}`",
+                command_line
+            ));
+        }
+        if opts.stdin_file.is_some() && (opts.pipe_stdin || opts.stdin.is_some()) {
+            return Err(anyhow::anyhow!(
+                "`stdin_file` cannot be set with `stdin` or `pipe_stdin` when spawning `{}`",
+                command_line
+            ));
+        }
+        if opts.merge_stderr_into_stdout && opts.stderr_capture.is_some() {
+            return Err(anyhow::anyhow!(
+                "`merge_stderr_into_stdout` and `stderr_capture` cannot both be set when \
+                 spawning `{}`",
+                command_line
+            ));
+        }
+        stdout.throttle = opts.read_throttle;
+        stderr.throttle = opts.read_throttle;
+        stdout.decompress = opts.decompress_stdout;
+        stdout.max_retained = opts.max_output_bytes;
+        stderr.max_retained = opts.max_output_bytes;
+        redirect(stdout, opts.stdout_capture.as_ref())?;
+        if !opts.merge_stderr_into_stdout {
+            redirect(stderr, opts.stderr_capture.as_ref())?;
+        }
+        if let Some(stdin) = opts.stdin.as_ref().filter(|_| opts.log_stdin) {
+            log::debug!(
+                "Stdin for `{}`: {}",
+                command_line,
+                describe_stdin(stdin, opts.secret_stdin)
+            );
+        }
+        let stdin_file = match opts.stdin_file.as_ref() {
+            Some(path) => Some(OwnedFd::from(std::fs::File::open(path).with_context(
+                || format!("Failed to open `{}` as stdin for `{}`", path, command_line),
+            )?)),
+            None => None,
+        };
+        let stdin = stdin.or(stdin_file.as_ref());
+        let spawn = |command_line: &CommandLine| -> anyhow::Result<std::io::Result<Child>> {
+            let stdin = match stdin {
+                Some(fd) => Stdio::from(fd.try_clone()?),
+                None => piped_if(opts.stdin.is_some() || opts.pipe_stdin),
+            };
+            Ok(self
+                .command(command_line, cwd, opts)?
+                .stdin(stdin)
+                .stdout(piped_if(
+                    opts.capture_stdout || opts.stdout_capture.is_some(),
+                ))
+                .stderr(piped_if(
+                    !opts.merge_stderr_into_stdout
+                        && (opts.capture_stderr || opts.stderr_capture.is_some()),
+                ))
+                .spawn())
+        };
+        let child = match spawn(command_line)? {
+            Err(e) if self.shebang_fallback && is_not_executable(&e) => {
+                match self.shebang_command(command_line, cwd, opts)? {
+                    Some(fallback) => {
+                        log::debug!("Retrying `{}` as `{}`", command_line, fallback);
+                        spawn(&fallback)??
+                    }
+                    None => return Err(e.into()),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(self.not_found(command_line, opts, e))
+            }
+            res => res?,
+        };
+        Ok(child)
+    }
+
+    /// Explain that spawning `command_line` failed with `NotFound`, with [`ProgramNotFound`]
+    /// if it is because the program is not on `PATH`, rather than e.g. because `cwd` is
+    /// missing.
+    fn not_found(
+        &self,
+        command_line: &CommandLine,
+        opts: &CommandOpts,
+        e: std::io::Error,
+    ) -> anyhow::Error {
+        let env = self.dry_env(opts);
+        match command_line.resolve_program(env.get("PATH").map_or("", String::as_str)) {
+            Err(not_found) if not_found.is::<ProgramNotFound>() => not_found,
+            _ => e.into(),
+        }
+    }
+
+    /// Build the command line that runs `command_line` through the interpreter in its
+    /// shebang, if the program can be found and has one.
+    fn shebang_command(
+        &self,
+        command_line: &CommandLine,
+        cwd: &AbsolutePath,
+        opts: &CommandOpts,
+    ) -> anyhow::Result<Option<CommandLine>> {
+        let program = command_line.program()?;
+        let cwd: &Path = cwd.as_ref();
+        let script = if program.contains('/') {
+            Some(cwd.join(program))
+        } else {
+            self.dry_env(opts).get("PATH").and_then(|path| {
+                std::env::split_paths(path)
+                    .map(|dir| cwd.join(dir).join(program))
+                    .find(|candidate| candidate.is_file())
+            })
+        };
+        let script = match script {
+            Some(script) => script,
+            None => return Ok(None),
+        };
+
+        let mut head = vec![];
+        std::fs::File::open(&script)?
+            .take(MAX_SHEBANG_LEN)
+            .read_to_end(&mut head)?;
+        let line = match head.strip_prefix(b"#!") {
+            Some(rest) => {
+                String::from_utf8_lossy(rest.split(|b| *b == b'\n').next().unwrap_or(rest))
+                    .into_owned()
+            }
+            None => return Ok(None),
+        };
+        // Like Linux, treat everything after the interpreter as a single argument.
+        let mut parts = line.trim().splitn(2, char::is_whitespace);
+        let mut fallback = match parts.next() {
+            Some(interpreter) if !interpreter.is_empty() => CommandLine::from([interpreter]),
+            _ => return Ok(None),
+        };
+        if let Some(arg) = parts.next().map(str::trim).filter(|arg| !arg.is_empty()) {
+            fallback.push(arg);
+        }
+        fallback.push(script.to_string_lossy());
+        fallback.extend(command_line.args_command_line()?);
+        Ok(Some(fallback))
+    }
+
+    /// Set up everything but stdio to run `command_line`.
+    pub(crate) fn command(
+        &self,
+        command_line: &CommandLine,
+        cwd: &AbsolutePath,
+        opts: &CommandOpts,
+    ) -> anyhow::Result<std::process::Command> {
+        let env = self.dry_env(opts);
+        for names in case_insensitive_duplicates(&env) {
+            log::debug!(
+                "`{}` will see environment variables that differ only in case: {}",
+                command_line,
+                names.iter().map(|name| format!("`{}`", name)).join(", ")
+            );
+        }
+        let mut program = command_line.program()?.to_owned();
+        if opts.spawn_method == SpawnMethod::PosixSpawn {
+            if opts.groups.is_some()
+                || opts.uid.is_some()
+                || opts.gid.is_some()
+                || opts.nice.is_some()
+                || opts.umask.is_some()
+                || opts.merge_stderr_into_stdout
+            {
+                return Err(anyhow::anyhow!(
+                    "`groups`, `uid`, `gid`, `nice`, `umask`, and `merge_stderr_into_stdout` \
+                     cannot be used with `SpawnMethod::PosixSpawn`, but were for `{}`",
+                    command_line
+                ));
+            }
+            // std looks up bare program names on a PATH that was changed in the child, after
+            // forking.
+            if !program.contains('/') {
+                let path = env.get("PATH").map_or("", String::as_str);
+                program = command_line
+                    .resolve_program_absolute(path)?
+                    .program()?
+                    .to_owned();
+            }
+        }
+        let mut command = std::process::Command::new(program);
+        command
+            .args(command_line.args()?)
+            .current_dir(cwd)
+            .env_clear()
+            .envs(env);
+        apply_pre_exec(&mut command, opts);
+        Ok(command)
+    }
+
+    /// Get the environment that a command run with `opts` would see, without running it.
+    ///
+    /// This is the environment of the current process, filtered per [`Self::ignoring_env`],
+    /// [`Self::allowing_env`], or [`Self::with_profile`], with [`CommandOpts::env`] applied on
+    /// top. A variable in [`CommandOpts::env`] replaces one of this process with exactly the
+    /// same name, but names are case-sensitive, so e.g. `path` is passed on alongside `PATH`
+    /// rather than replacing it. Variables whose names differ only in case are logged at
+    /// debug level when a command is run, as they are usually a mistake.
+    pub fn dry_env(&self, opts: &CommandOpts) -> HashMap<String, String> {
+        let mut env_vars = self.computed_env();
+        env_vars.extend(opts.env.clone());
+        env_vars
+    }
+
+    /// Get the environment that every command starts from, before any [`CommandOpts::env`],
+    /// e.g. to check which variables of this process are kept from commands.
+    pub fn computed_env(&self) -> HashMap<String, String> {
+        self.env_profile.apply(std::env::vars())
+    }
+}
+
+/// The groups of names in `env` that are the same but for case, sorted.
+fn case_insensitive_duplicates(env: &HashMap<String, String>) -> Vec<Vec<&str>> {
+    let mut by_name: HashMap<String, Vec<&str>> = HashMap::new();
+    for name in env.keys() {
+        by_name
+            .entry(name.to_uppercase())
+            .or_default()
+            .push(name.as_str());
+    }
+    let mut duplicates: Vec<_> = by_name
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .map(|mut names| {
+            names.sort_unstable();
+            names
+        })
+        .collect();
+    duplicates.sort_unstable();
+    duplicates
+}
+
+/// Split `opts` between the stages of a pipeline of `commands`, for
+/// [`CommandRunner::run_pipeline`].
+fn pipeline_stages(
+    commands: Vec<CommandLine>,
+    opts: CommandOpts,
+) -> anyhow::Result<Vec<(CommandLine, CommandOpts)>> {
+    if commands.is_empty() {
+        return Err(anyhow::anyhow!("A pipeline needs at least one command"));
+    }
+    let count = commands.len();
+    Ok(commands
+        .into_iter()
+        .enumerate()
+        .map(|(i, command_line)| {
+            let mut opts = opts.clone();
+            if i > 0 {
+                opts.stdin = None;
+                opts.stdin_file = None;
+                opts.pipe_stdin = false;
+            }
+            if i + 1 < count {
+                opts.capture_stdout = true;
+                opts.stdout_capture = None;
+            }
+            (command_line, opts)
+        })
+        .collect())
+}
+
+/// Get the result of the final stage of a pipeline, failing per `pipefail`.
+fn finish_pipeline(
+    mut stages: Vec<(String, ExecutionResult)>,
+    pipefail: bool,
+) -> anyhow::Result<ExecutionResult> {
+    let (_, last) = stages.pop().expect("pipelines are not empty");
+    match stages.into_iter().find(|(_, res)| !res.status.success()) {
+        Some((program, res)) if pipefail => Err(CommandFailed::new(program, res).into()),
+        _ => Ok(last),
+    }
+}
+
+impl CommandRunner for DefaultCommandRunner {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        self.print_if_verbose(&command_line);
+        if opts.capture_on_failure {
+            let opts = CommandOpts {
+                pipe_stdin: false,
+                capture_stdout: true,
+                capture_stderr: true,
+                ..opts
+            };
+            let stdout = StreamSink {
+                echo: Some(Box::new(std::io::stdout())),
+                ..StreamSink::retained()
+            };
+            let timeout = opts.timeout;
+            let mut res = self
+                .spawn_with_sinks(command_line, cwd, opts, stdout, echoed_stderr())?
+                .wait_with_timeout(timeout)?;
+            if res.status.success() {
+                res.output.stdout.clear();
+                res.output.stderr.clear();
+            }
+            return Ok(res);
+        }
+        let opts = CommandOpts {
+            pipe_stdin: false,
+            ..opts
+        };
+        let timeout = opts.timeout;
+        self.spawn_with_sinks(
+            command_line,
+            cwd,
+            opts,
+            StreamSink::retained(),
+            echoed_stderr(),
+        )?
+        .wait_with_timeout(timeout)
+    }
+
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let pipefail = opts.pipefail;
+        let stages = pipeline_stages(commands, opts)?;
+        let programs = stages
+            .iter()
+            .map(|(command_line, _)| Ok(command_line.program()?.to_owned()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let results = self.run_pipeline_stages(stages, cwd)?;
+        finish_pipeline(programs.into_iter().zip(results).collect(), pipefail)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        log::info!(
+            "Exec'ing {}`{}` in `{}`",
+            opts.log_label(),
+            command_line,
+            cwd
+        );
+        self.print_if_verbose(&command_line);
+        if opts.pipe_stdin || (opts.stdin.is_some() && opts.stdin_file.is_some()) {
+            return Err(anyhow::anyhow!(
+                "`pipe_stdin`, or both `stdin` and `stdin_file`, cannot be set when exec'ing `{}`",
+                command_line
+            ));
+        }
+        // Nothing is left to signal or forward signals to the command once this process is
+        // replaced, and a new group would take it out of the terminal's foreground.
+        if opts.new_process_group || opts.signal_forwarding != SignalForwarding::Inherit {
+            return Err(anyhow::anyhow!(
+                "`new_process_group` and `signal_forwarding` cannot be set when exec'ing `{}`",
+                command_line
+            ));
+        }
+        let mut command = self.command(&command_line, cwd, &opts)?;
+        if let Some(stdin) = opts.stdin.as_ref() {
+            // Nothing is left to write to a pipe once this process is replaced, so the
+            // command reads stdin from an anonymous file instead.
+            let mut file = tempfile::tempfile()?;
+            file.write_all(stdin)?;
+            file.rewind()?;
+            command.stdin(file);
+        }
+        if let Some(path) = opts.stdin_file.as_ref() {
+            command.stdin(std::fs::File::open(path).with_context(|| {
+                format!("Failed to open `{}` as stdin for `{}`", path, command_line)
+            })?);
+        }
+        let e = command.exec();
+        if e.kind() == ErrorKind::NotFound {
+            return Err(self.not_found(&command_line, &opts, e));
+        }
+        Err(e.into())
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.default_cwd
+            .clone()
+            .unwrap_or_else(AbsolutePathBuf::current_dir)
+    }
+}
+
+/// Show stderr on the terminal as it is produced, while also capturing it.
+fn echoed_stderr() -> StreamSink {
+    StreamSink {
+        echo: Some(Box::new(std::io::stderr())),
+        ..StreamSink::retained()
+    }
+}
+
+/// Render `bytes` for logs or errors, truncated to `max` bytes.
+fn describe_bytes(bytes: &[u8], max: usize) -> String {
+    let shown = String::from_utf8_lossy(&bytes[..bytes.len().min(max)]);
+    if bytes.len() > max {
+        format!("{:?}... ({} more bytes)", shown, bytes.len() - max)
+    } else {
+        format!("{:?}", shown)
+    }
+}
+
+/// Render `stdin` for [`CommandOpts::log_stdin`], without its contents if it is `secret`.
+fn describe_stdin(stdin: &[u8], secret: bool) -> String {
+    if secret {
+        format!("{} secret bytes", stdin.len())
+    } else {
+        describe_bytes(stdin, MAX_LOGGED_STDIN)
+    }
+}
+
+/// Whether spawning failed because the OS would not execute the program itself.
+fn is_not_executable(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::PermissionDenied || e.raw_os_error() == Some(libc::ENOEXEC)
+}
+
+fn piped_if(piped: bool) -> Stdio {
+    if piped {
+        Stdio::piped()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Set up any options that must be applied in the child between `fork` and `exec`.
+fn apply_pre_exec(command: &mut std::process::Command, opts: &CommandOpts) {
+    if opts.new_process_group || opts.signal_forwarding.new_process_group() {
+        command.process_group(0);
+    }
+    if opts.merge_stderr_into_stdout {
+        // SAFETY: `dup2` is async-signal-safe. This runs after std has set up stdio, so fd 1
+        // is already whatever stdout was configured as.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    if let Some(nice) = opts.nice {
+        // SAFETY: `setpriority` is async-signal-safe. This runs before the user is changed
+        // below, so that a privileged process can still raise the priority.
+        unsafe {
+            command.pre_exec(move || os_result(libc::setpriority(libc::PRIO_PROCESS, 0, nice)));
+        }
+    }
+    if let Some(umask) = opts.umask {
+        // SAFETY: `umask` is async-signal-safe, and cannot fail.
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(umask as libc::mode_t);
+                Ok(())
+            });
+        }
+    }
+    let groups = opts.groups.clone();
+    let (gid, uid) = (opts.gid, opts.uid);
+    if groups.is_some() || gid.is_some() || uid.is_some() {
+        // SAFETY: `setgroups`, `setgid`, `setuid`, and `geteuid` are async-signal-safe, and
+        // the closure does not allocate. This is one closure, rather than std's `uid` and
+        // `gid`, which run before any `pre_exec`, so that the groups can be set before the
+        // uid is changed.
+        unsafe {
+            command.pre_exec(move || {
+                match groups.as_ref() {
+                    Some(groups) => os_result(libc::setgroups(groups.len() as _, groups.as_ptr()))?,
+                    // As in std, don't let root's groups leak to another user.
+                    None if uid.is_some() && libc::geteuid() == 0 => {
+                        os_result(libc::setgroups(0, std::ptr::null()))?
+                    }
+                    None => {}
+                }
+                if let Some(gid) = gid {
+                    os_result(libc::setgid(gid))?;
+                }
+                if let Some(uid) = uid {
+                    os_result(libc::setuid(uid))?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Turn the return value of a libc call into the error that it set, if it failed.
+fn os_result(ret: libc::c_int) -> std::io::Result<()> {
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The name of the effective user of this process, from the user database.
+fn current_username() -> anyhow::Result<String> {
+    // SAFETY: `geteuid` cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: `passwd` is plain data, which `getpwuid_r` fills in.
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: All pointers are valid for the duration of the call, and `buf.len()` is the
+        // size of `buf`.
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        if ret == libc::ERANGE {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret))
+                .with_context(|| format!("Could not look up the user with uid {}", uid));
+        }
+        if found.is_null() {
+            return Err(anyhow::anyhow!("There is no user with uid {}", uid));
+        }
+        // SAFETY: On success, `pw_name` points to a nul-terminated string in `buf`.
+        let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+        return name
+            .to_str()
+            .map(str::to_owned)
+            .with_context(|| format!("The name of the user with uid {} is not UTF-8", uid));
+    }
+}
+
+pub mod test {
+    use std::collections::HashMap;
+    use std::collections::VecDeque;
+    use std::fmt::Debug;
+    use std::fmt::Formatter;
+    use std::ops::Deref;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    use std::sync::Arc;
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    use itertools::Itertools;
+    use paths::AbsolutePath;
+    use paths::AbsolutePathBuf;
+
+    use super::CommandOpts;
+    use super::CommandRunner;
+    use super::ExecutionResult;
+    use crate::CommandLine;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Invocation {
+        command_line: CommandLine,
+        cwd: AbsolutePathBuf,
+        timeout: Option<Duration>,
+        env: HashMap<String, String>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        nice: Option<i32>,
+    }
+
+    impl Deref for Invocation {
+        type Target = CommandLine;
+
+        fn deref(&self) -> &Self::Target {
+            &self.command_line
+        }
+    }
+
+    impl Invocation {
+        pub fn new<P: Into<AbsolutePathBuf>>(command_line: CommandLine, cwd: P) -> Self {
+            Self {
+                command_line,
+                cwd: cwd.into(),
+                timeout: None,
+                env: HashMap::new(),
+                uid: None,
+                gid: None,
+                nice: None,
+            }
+        }
+
+        /// Expect the command to have been run with [`CommandOpts::env`] set to `env`.
+        pub fn with_env(self, env: HashMap<String, String>) -> Self {
+            Self { env, ..self }
+        }
+
+        /// The extra environment variables in [`CommandOpts::env`] that the command was run
+        /// with.
+        pub fn env(&self) -> &HashMap<String, String> {
+            &self.env
+        }
+
+        /// Expect the command to have been run with [`CommandOpts::timeout`] set to `timeout`.
+        pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+            Self { timeout, ..self }
+        }
+
+        /// The [`CommandOpts::timeout`] that the command was run with.
+        ///
+        /// [`TestCommandRunner`] never enforces it.
+        pub fn timeout(&self) -> Option<Duration> {
+            self.timeout
+        }
+
+        /// Expect the command to have been run with [`CommandOpts::uid`] and
+        /// [`CommandOpts::gid`] set to `uid` and `gid`.
+        pub fn with_user(self, uid: Option<u32>, gid: Option<u32>) -> Self {
+            Self { uid, gid, ..self }
+        }
+
+        /// The [`CommandOpts::uid`] that the command was run with.
+        pub fn uid(&self) -> Option<u32> {
+            self.uid
+        }
+
+        /// The [`CommandOpts::gid`] that the command was run with.
+        pub fn gid(&self) -> Option<u32> {
+            self.gid
+        }
+
+        /// Expect the command to have been run with [`CommandOpts::nice`] set to `nice`.
+        pub fn with_nice(self, nice: Option<i32>) -> Self {
+            Self { nice, ..self }
+        }
+
+        /// The [`CommandOpts::nice`] that the command was run with.
+        pub fn nice(&self) -> Option<i32> {
+            self.nice
+        }
+    }
+
+    /// Which commands an output registered with [`TestCommandRunner::expect`] is for.
+    #[derive(Clone)]
+    pub enum CommandMatcher {
+        /// Any command that runs this program.
+        Program(String),
+        /// Exactly this command line.
+        Exact(CommandLine),
+        /// Any command line that this returns `true` for.
+        Predicate(Arc<dyn Fn(&CommandLine) -> bool + Send + Sync>),
+    }
+
+    impl CommandMatcher {
+        pub fn program<S: Into<String>>(program: S) -> Self {
+            Self::Program(program.into())
+        }
+
+        pub fn exact<C: Into<CommandLine>>(command_line: C) -> Self {
+            Self::Exact(command_line.into())
+        }
+
+        pub fn predicate<F: Fn(&CommandLine) -> bool + Send + Sync + 'static>(f: F) -> Self {
+            Self::Predicate(Arc::new(f))
+        }
+
+        pub fn matches(&self, command_line: &CommandLine) -> bool {
+            match self {
+                Self::Program(program) => command_line.program().ok() == Some(program.as_str()),
+                Self::Exact(expected) => expected == command_line,
+                Self::Predicate(f) => f(command_line),
+            }
+        }
+    }
+
+    impl Debug for CommandMatcher {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Program(program) => f.debug_tuple("Program").field(program).finish(),
+                Self::Exact(command_line) => f.debug_tuple("Exact").field(command_line).finish(),
+                Self::Predicate(_) => f.write_str("Predicate(..)"),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TestCommandRunner {
+        pub hostname: String,
+        /// The user that [`CommandRunner::username`] reports, instead of the current one.
+        pub username: String,
+        pub temp: tempfile::TempDir,
+        pub issued_commands: RwLock<Vec<Invocation>>,
+        pub outputs: RwLock<VecDeque<Output>>,
+        /// Outputs for specific commands, which take precedence over [`Self::outputs`].
+        pub expected_outputs: RwLock<Vec<(CommandMatcher, Output)>>,
+        /// Errors for specific commands to fail with, which take precedence over any outputs.
+        pub failures: RwLock<Vec<(CommandMatcher, anyhow::Error)>>,
+        /// How long every command reports that it took. If this is zero, it is the (tiny)
+        /// time that it actually took.
+        pub duration: Duration,
+        /// The environment variables that [`CommandRunner::var`] sees, instead of those of
+        /// this process.
+        pub vars: HashMap<String, String>,
+    }
+
+    impl Default for TestCommandRunner {
+        fn default() -> Self {
+            Self {
+                hostname: "local.example.com".to_owned(),
+                username: "user".to_owned(),
+                temp: tempfile::tempdir().expect("to be able to create a tempdir"),
+                issued_commands: RwLock::new(vec![]),
+                outputs: RwLock::new(Default::default()),
+                expected_outputs: RwLock::new(vec![]),
+                failures: RwLock::new(vec![]),
+                duration: Duration::ZERO,
+                vars: HashMap::new(),
+            }
+        }
+    }
+
+    impl TestCommandRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn with_results<I: IntoIterator<Item = (i32, S)>, S: ToString>(
+            i: I,
+        ) -> anyhow::Result<Self> {
+            let issued_commands = RwLock::new(Vec::new());
+            let outputs: VecDeque<_> = i
+                .into_iter()
+                .map(|(code, stdout)| {
+                    let _s = String::new();
+                    Output {
+                        status: ExitStatus::from_raw(code),
+                        stdout: stdout.to_string().into_bytes(),
+                        stderr: vec![],
+                    }
+                })
+                .collect();
+            Ok(Self {
+                issued_commands,
+                outputs: RwLock::new(outputs),
+                ..Default::default()
+            })
+        }
+
+        /// Create a runner whose one command is terminated by `signal`, after writing `stdout`.
+        ///
+        /// This panics if `signal` is not a valid signal number.
+        pub fn with_signal<S: ToString>(signal: i32, stdout: S) -> Self {
+            Self::default().and_signal(signal, stdout)
+        }
+
+        /// Queue an output for the next command that is terminated by `signal`, after
+        /// writing `stdout`.
+        ///
+        /// This panics if `signal` is not a valid signal number.
+        pub fn and_signal<S: ToString>(self, signal: i32, stdout: S) -> Self {
+            // In a wait status, the signal that terminated a process is in the low 7 bits,
+            // and 0 and 0x7f there mean that it exited or was stopped instead.
+            assert!(
+                (1..0x7f).contains(&signal),
+                "`{}` is not a valid signal",
+                signal
+            );
+            self.outputs.write().unwrap().push_back(Output {
+                status: ExitStatus::from_raw(signal),
+                stdout: stdout.to_string().into_bytes(),
+                stderr: vec![],
+            });
+            self
+        }
+
+        /// Make [`CommandRunner::var`] return `value` for `name`.
+        pub fn with_var<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+            self.vars.insert(name.into(), value.into());
+            self
+        }
+
+        /// Report that every command took `duration`, e.g. to test handling of slow commands.
+        pub fn with_duration(mut self, duration: Duration) -> Self {
+            self.duration = duration;
+            self
+        }
+
+        /// Return `output` for the next command that `matcher` matches, regardless of the
+        /// order that commands are run in.
+        ///
+        /// Each registered output is used once, and if several match a command, the one that
+        /// was registered first is used. Commands that match none of them get the next output
+        /// from [`Self::outputs`] as usual.
+        pub fn expect(&self, matcher: CommandMatcher, output: Output) {
+            self.expected_outputs
+                .write()
+                .unwrap()
+                .push((matcher, output));
+        }
+
+        /// Make the next command that `matcher` matches fail with `err`, as if it could not be
+        /// run at all, e.g. with a [`crate::ProgramNotFound`].
+        ///
+        /// As with [`Self::expect`], each failure is used once, in the order they were
+        /// registered. The command is still recorded in [`Self::issued_commands`], and does not
+        /// use up an output.
+        pub fn fail_matching(&self, matcher: CommandMatcher, err: anyhow::Error) {
+            self.failures.write().unwrap().push((matcher, err));
+        }
+
+        /// Make the next command fail with `err`, whatever it is.
+        pub fn fail_next(&self, err: anyhow::Error) {
+            self.fail_matching(CommandMatcher::predicate(|_| true), err);
+        }
+
+        /// Asserts that `commands` were issued in the given order.
+        ///
+        /// Other commands may have been issued before, after, or in between
+        /// them; only their relative order is checked.
+        pub fn assert_order(&self, commands: &[CommandLine]) {
+            let issued = self.issued_commands.read().unwrap();
+            let mut expected = commands.iter().peekable();
+            for invocation in issued.iter() {
+                expected.next_if(|c| **c == invocation.command_line);
+            }
+            if expected.peek().is_some() {
+                panic!(
+                    "Expected commands to be issued in order:\n  {}\nIssued commands were:\n  {}",
+                    commands.iter().join("\n  "),
+                    issued.iter().map(|i| &i.command_line).join("\n  ")
+                );
+            }
+        }
+
+        /// Asserts that no commands were issued at all.
+        pub fn assert_nothing_ran(&self) {
+            let issued = self.issued_commands.read().unwrap();
+            if !issued.is_empty() {
+                panic!(
+                    "Expected no commands to be issued, but these were:\n  {}",
+                    issued.iter().map(|i| &i.command_line).join("\n  ")
+                );
+            }
+        }
+
+        /// Asserts that at least one command that runs `program` was issued.
+        pub fn assert_ran(&self, program: &str) {
+            self.assert_ran_in_order(&[program]);
+        }
+
+        /// Asserts that commands running `programs` were issued in the given order.
+        ///
+        /// As with [`Self::assert_order`], other commands may have been issued before, after,
+        /// or in between them.
+        pub fn assert_ran_in_order(&self, programs: &[&str]) {
+            let issued = self.issued_commands.read().unwrap();
+            let mut expected = programs.iter().peekable();
+            for invocation in issued.iter() {
+                expected.next_if(|p| invocation.command_line.program().ok() == Some(**p));
+            }
+            if expected.peek().is_some() {
+                panic!(
+                    "Expected programs to be run in order:\n  {}\nIssued commands were:\n  {}",
+                    programs.join("\n  "),
+                    issued.iter().map(|i| &i.command_line).join("\n  ")
+                );
+            }
+        }
+
+        /// How many commands were issued.
+        pub fn invocation_count(&self) -> usize {
+            self.issued_commands.read().unwrap().len()
+        }
+
+        /// The `i`th command that was issued, counting from 0.
+        pub fn nth_invocation(&self, i: usize) -> Option<Invocation> {
+            self.issued_commands.read().unwrap().get(i).cloned()
+        }
+
+        /// The failure or output for `command_line`, per [`Self::failures`],
+        /// [`Self::expected_outputs`], and [`Self::outputs`], in that order.
+        fn next_output(&self, command_line: &CommandLine) -> anyhow::Result<Output> {
+            let failure = {
+                let mut failures = self.failures.write().unwrap();
+                failures
+                    .iter()
+                    .position(|(matcher, _)| matcher.matches(command_line))
+                    .map(|i| failures.remove(i).1)
+            };
+            if let Some(err) = failure {
+                return Err(err);
+            }
+            let expected = {
+                let mut expected = self.expected_outputs.write().unwrap();
+                expected
+                    .iter()
+                    .position(|(matcher, _)| matcher.matches(command_line))
+                    .map(|i| expected.remove(i).1)
+            };
+            let output = expected.or_else(|| self.outputs.write().unwrap().pop_front());
+            output.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "TestCommandRunner has no output for `{}`: 0 outputs remained, and no \
+                    expected output matched",
+                    command_line
+                )
+            })
+        }
+    }
+
+    impl CommandRunner for TestCommandRunner {
+        fn run_inner(
+            &self,
+            command_line: CommandLine,
+            cwd: &AbsolutePath,
+            opts: CommandOpts,
+        ) -> anyhow::Result<ExecutionResult> {
+            let output = self.next_output(&command_line);
+            let invocation = Invocation::new(command_line, cwd)
+                .with_timeout(opts.timeout)
+                .with_env(opts.env)
+                .with_user(opts.uid, opts.gid)
+                .with_nice(opts.nice);
+            self.issued_commands.write().unwrap().push(invocation);
+            Ok(ExecutionResult {
+                duration: self.duration,
+                ..output?.into()
+            })
+        }
+
+        fn exec_with_opts(
+            &self,
+            _command_line: CommandLine,
+            _cwd: &AbsolutePath,
+            _opts: CommandOpts,
+        ) -> anyhow::Result<()>
+        where
+            Self: Sized,
+        {
+            Ok(())
+        }
+
+        fn hostname(&self) -> anyhow::Result<String> {
+            Ok(self.hostname.clone())
+        }
+
+        fn username(&self) -> anyhow::Result<String> {
+            Ok(self.username.clone())
+        }
+
+        fn var(&self, name: &str) -> Option<String> {
+            self.vars.get(name).cloned()
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Could not get $HOME!")]
+pub struct MissingHomeError;
+
+/// The error returned when a checked command exits unsuccessfully.
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "Command `{program}` failed with status `{}`\nStdout:\n{}\nStderr:\n{}{}",
+    .result.status,
+    String::from_utf8_lossy(&.result.stdout),
+    String::from_utf8_lossy(&.result.stderr),
+    format_hint(.hint)
+)]
+pub struct CommandFailed {
+    pub program: String,
+    pub result: ExecutionResult,
+    /// An optional suggestion shown to users on how to fix the failure.
+    pub hint: Option<String>,
+}
+
+impl CommandFailed {
+    pub fn new<S: Into<String>>(program: S, result: ExecutionResult) -> Self {
+        Self {
+            program: program.into(),
+            result,
+            hint: None,
+        }
+    }
+
+    /// Attach a hint on how to fix the failure, which is included in the error message.
+    pub fn with_hint<S: Into<String>>(mut self, hint: S) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+fn format_hint(hint: &Option<String>) -> String {
+    hint.as_ref()
+        .map(|hint| format!("\nHint: {}", hint))
+        .unwrap_or_default()
+}
+
+/// The error returned when some of the batches run by [`CommandRunner::xargs`] fail.
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "{} of {} batches of `{program}` failed",
+    .results.iter().filter(|res| !res.status.success()).count(),
+    .results.len()
+)]
+pub struct BatchesFailed {
+    pub program: String,
+    /// The results of every batch, including the successful ones, in the order they ran.
+    pub results: Vec<ExecutionResult>,
+}
+
+/// The error returned when a command runs for longer than [`CommandOpts::timeout`].
+#[derive(thiserror::Error, Debug)]
+#[error("Command `{program}` timed out after {elapsed:?}")]
+pub struct CommandTimeout {
+    pub program: String,
+    /// How long the command had been running when it was stopped.
+    pub elapsed: Duration,
+    /// The status of the stopped command, and any output it produced before that.
+    pub result: ExecutionResult,
+}
 
 /// The outcome of a command + helper methods
-pub struct ExecutionResult(pub Output);
+#[derive(Debug, Clone)]
+pub struct ExecutionResult {
+    pub(crate) output: Output,
+    pub(crate) stdout_bytes_read: usize,
+    pub(crate) stderr_bytes_read: usize,
+    pub(crate) stdout_truncated: bool,
+    pub(crate) stderr_truncated: bool,
+    pub(crate) duration: Duration,
+    pub(crate) time_to_first_output: Option<Duration>,
+    pub(crate) stdout_file: Option<Arc<NamedTempFile>>,
+    pub(crate) stderr_file: Option<Arc<NamedTempFile>>,
+    pub(crate) merged: bool,
+    pub(crate) interleaved: Vec<(Stream, Vec<u8>)>,
+    /// The program that was run, for [`Self::into_result`], which is empty if unknown.
+    pub(crate) program: String,
+}
+
 impl ExecutionResult {
+    /// Get stdout as a string, failing if it is not valid UTF-8.
+    ///
+    /// If [`Self::is_merged`], this contains stderr too.
     pub fn stdout(&self) -> anyhow::Result<String> {
-        Ok(String::from_utf8(self.0.stdout.clone())?)
+        Ok(String::from_utf8(self.output.stdout.clone())?)
+    }
+
+    /// Get stderr as a string, failing if it is not valid UTF-8.
+    ///
+    /// This is always empty if [`Self::is_merged`], as stderr went to stdout instead.
+    pub fn stderr(&self) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.output.stderr.clone())?)
+    }
+
+    /// Get stdout as lines, split on `\n`, failing if it is not valid UTF-8.
+    ///
+    /// The final newline does not start another line, so `"a\nb\n"` is `["a", "b"]`.
+    pub fn stdout_lines(&self) -> anyhow::Result<Vec<String>> {
+        Ok(lines(self.stdout()?))
+    }
+
+    /// Get stderr as lines, like [`Self::stdout_lines`].
+    pub fn stderr_lines(&self) -> anyhow::Result<Vec<String>> {
+        Ok(lines(self.stderr()?))
+    }
+
+    /// Get stdout followed by stderr as a string, e.g. to log everything a failed command
+    /// wrote, failing if either is not valid UTF-8.
+    ///
+    /// The two are not interleaved in the order they were written; use
+    /// [`CommandOpts::merge_stderr_into_stdout`] for that.
+    pub fn combined(&self) -> anyhow::Result<String> {
+        Ok(self.stdout()? + &self.stderr()?)
+    }
+
+    /// Parse stdout as JSON, e.g. for tools run with `--format json`.
+    ///
+    /// The error includes the start of stdout, to help debug malformed output.
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        serde_json::from_slice(&self.output.stdout).with_context(|| {
+            format!(
+                "Could not parse stdout as JSON: {}",
+                describe_bytes(&self.output.stdout, MAX_SHOWN_JSON)
+            )
+        })
+    }
+
+    /// Fail if stderr does not match the regex `pattern` somewhere, e.g. to check that a
+    /// command rejected bad input with the expected diagnostic.
+    ///
+    /// The error includes the actual stderr. This also fails if `pattern` is not a valid
+    /// regex, or stderr is not valid UTF-8.
+    #[cfg(feature = "regex")]
+    pub fn expect_stderr_matches(&self, pattern: &str) -> anyhow::Result<()> {
+        let regex = regex::Regex::new(pattern)?;
+        let stderr = self.stderr()?;
+        if regex.is_match(&stderr) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Expected stderr to match `{}`, but it was:\n{}",
+                pattern,
+                stderr
+            ))
+        }
+    }
+
+    /// Fail with a diff if stdout is not exactly the contents of the file at `path`.
+    ///
+    /// This is meant for golden file tests, e.g. checking that generated output matches what
+    /// is checked in. The diff shows lines from the file with `-`, and lines from stdout with
+    /// `+`.
+    pub fn assert_matches_file(&self, path: &AbsolutePath) -> anyhow::Result<()> {
+        let expected = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read `{}`: {}", path, e))?;
+        let actual = self.stdout()?;
+        if expected == actual {
+            return Ok(());
+        }
+        let diff = crate::diff::line_diff(&expected, &actual);
+        if diff.lines().all(|l| l.starts_with(' ')) {
+            // Only trailing newlines differ, which a line diff cannot show.
+            Err(anyhow::anyhow!(
+                "stdout does not match `{}`: they differ in trailing newlines",
+                path
+            ))
+        } else {
+            Err(anyhow::anyhow!(
+                "stdout does not match `{}`:\n{}",
+                path,
+                diff
+            ))
+        }
+    }
+
+    /// Whether stderr was merged into stdout, per [`CommandOpts::merge_stderr_into_stdout`].
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    /// The number of bytes read from the command's stdout.
+    ///
+    /// This is counted as the output is captured, regardless of whether it is retained in
+    /// this result. Streams that are inherited from the calling process are not observed,
+    /// and report zero.
+    pub fn stdout_bytes_read(&self) -> usize {
+        self.stdout_bytes_read
+    }
+
+    /// The number of bytes read from the command's stderr.
+    ///
+    /// See [`Self::stdout_bytes_read`] for details.
+    pub fn stderr_bytes_read(&self) -> usize {
+        self.stderr_bytes_read
+    }
+
+    /// Whether stdout was cut short by [`CommandOpts::max_output_bytes`], i.e. the command
+    /// wrote more than is in [`Self::stdout`].
+    pub fn stdout_truncated(&self) -> bool {
+        self.stdout_truncated
+    }
+
+    /// Whether stderr was cut short by [`CommandOpts::max_output_bytes`].
+    pub fn stderr_truncated(&self) -> bool {
+        self.stderr_truncated
+    }
+
+    /// The chunks of stdout and stderr in the order they were read, if the command was run with
+    /// [`CommandOpts::capture_interleaved`], and empty otherwise.
+    pub fn interleaved(&self) -> &[(Stream, Vec<u8>)] {
+        &self.interleaved
+    }
+
+    /// The temp file that stdout was written to, if it was run with [`StdioCapture::TempFile`].
+    ///
+    /// Its handle starts at the beginning of the file. The file is shared between clones of
+    /// this result, and so is the position of the handle, which `&File` can read and seek.
+    pub fn stdout_file(&self) -> Option<&NamedTempFile> {
+        self.stdout_file.as_deref()
+    }
+
+    /// The temp file that stderr was written to, if it was run with [`StdioCapture::TempFile`].
+    ///
+    /// See [`Self::stdout_file`] for details.
+    pub fn stderr_file(&self) -> Option<&NamedTempFile> {
+        self.stderr_file.as_deref()
+    }
+
+    /// How long the command took to run, measured by the wall clock.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Fail if the command took longer than `max` to run.
+    ///
+    /// This is useful in tests to catch performance regressions in wrapped tools.
+    pub fn expect_within(&self, max: Duration) -> anyhow::Result<()> {
+        if self.duration > max {
+            Err(anyhow::anyhow!(
+                "Command took {:?}, which is longer than the allowed {:?}",
+                self.duration,
+                max
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How long it took from starting the command until the first byte was read from its
+    /// stdout.
+    ///
+    /// This tells a command that is slow to start apart from one that is slow overall. It is
+    /// `None` if the command wrote nothing to stdout, or if stdout was not read by this
+    /// process, e.g. for [`CommandRunner::run_foreground`].
+    pub fn time_to_first_output(&self) -> Option<Duration> {
+        self.time_to_first_output
+    }
+
+    /// Fail if the command did not produce any stdout within `max` of being started.
+    pub fn expect_first_output_within(&self, max: Duration) -> anyhow::Result<()> {
+        match self.time_to_first_output {
+            Some(latency) if latency <= max => Ok(()),
+            Some(latency) => Err(anyhow::anyhow!(
+                "Command took {:?} to produce output, which is longer than the allowed {:?}",
+                latency,
+                max
+            )),
+            None => Err(anyhow::anyhow!("Command did not produce any output")),
+        }
+    }
+
+    /// Iterate over the lines of stdout, along with their 1-based line numbers.
+    ///
+    /// This fails if stdout is not valid UTF-8.
+    pub fn numbered_lines(&self) -> anyhow::Result<impl Iterator<Item = (usize, &str)>> {
+        Ok(std::str::from_utf8(&self.output.stdout)?
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line)))
+    }
+
+    /// The exit code of the command, or `None` if it was terminated by a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.output.status.code()
+    }
+
+    /// The signal that terminated the command, or `None` if it exited normally.
+    pub fn signal(&self) -> Option<i32> {
+        self.output.status.signal()
+    }
+
+    /// Whether the command exited with code 0.
+    pub fn success(&self) -> bool {
+        self.output.status.success()
+    }
+
+    /// Turn an unsuccessful result into an error, e.g. to decide whether a command failed only
+    /// after looking at its result, unlike [`CommandRunner::run_checked`].
+    ///
+    /// The error carries the whole result, and can be given more context as usual, e.g.
+    /// `res.into_result().context("while syncing the repo")?`.
+    pub fn into_result(self) -> Result<ExecutionResult, CommandFailed> {
+        if self.success() {
+            Ok(self)
+        } else {
+            Err(CommandFailed::new(self.program.clone(), self))
+        }
+    }
+
+    /// Translate the exit code into some other type, e.g. an enum with tool specific meanings.
+    ///
+    /// `f` is given `None` if the command was terminated by a signal.
+    pub fn map_code<T, F: FnOnce(Option<i32>) -> T>(&self, f: F) -> T {
+        f(self.output.status.code())
+    }
+
+    /// The underlying [`Output`], which used to be the public `.0` field.
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    /// Get the underlying [`Output`].
+    pub fn into_output(self) -> Output {
+        self.output
+    }
+}
+
+fn lines(output: String) -> Vec<String> {
+    let mut lines: Vec<_> = output.split('\n').map(str::to_owned).collect();
+    if lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    lines
+}
+
+impl From<Output> for ExecutionResult {
+    fn from(output: Output) -> Self {
+        Self {
+            stdout_bytes_read: output.stdout.len(),
+            stderr_bytes_read: output.stderr.len(),
+            stdout_truncated: false,
+            stderr_truncated: false,
+            duration: Duration::ZERO,
+            time_to_first_output: None,
+            stdout_file: None,
+            stderr_file: None,
+            merged: false,
+            interleaved: vec![],
+            program: String::new(),
+            output,
+        }
+    }
+}
+
+impl Deref for ExecutionResult {
+    type Target = Output;
+
+    fn deref(&self) -> &Self::Target {
+        &self.output
+    }
+}
+
+/// Various pieces of the environment needed to execute commands, find paths, etc.
+///
+/// A trait so that testing is easier.
+pub trait Env {
+    fn execute<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<ExecutionResult>
+    where
+        Self: Sized;
+
+    fn exec<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<()>
+    where
+        Self: Sized;
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf;
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf>;
+    fn hostname(&self) -> anyhow::Result<String>;
+
+    /// The name of the user that this process runs as, like [`CommandRunner::username`].
+    fn username(&self) -> anyhow::Result<String> {
+        current_username()
+    }
+
+    /// Get an environment variable, like [`CommandRunner::var`].
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+}
+
+#[cfg(test)]
+mod default_runner_tests {
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::ExitStatusExt;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use maplit::hashmap;
+    use paths::AbsolutePath;
+    use paths::AbsolutePathBuf;
+
+    use crate::BytesPerSecond;
+    use crate::CommandEvent;
+    use crate::CommandFailed;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::CommandTimeout;
+    use crate::DefaultCommandRunner;
+    use crate::EnvProfile;
+    use crate::ProgramNotFound;
+    use crate::SignalForwarding;
+    use crate::SpawnMethod;
+    use crate::StdioCapture;
+    use crate::Stream;
+
+    #[test]
+    fn sets_cwd_correctly() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let runner = DefaultCommandRunner::default();
+        std::fs::write(temp.path().join("test_file"), "contents")?;
+
+        let out = runner.run_checked(["ls", "-1", "."], AbsolutePath::try_new(temp.path())?)?;
+        assert_eq!("test_file", out.stdout()?.trim());
+
+        let out = runner.run(["ls", "-1", "."], AbsolutePath::try_new(temp.path())?)?;
+        assert_eq!("test_file", out.stdout()?.trim());
+
+        let out = runner.run_with_opts(
+            ["ls", "-1", "."],
+            AbsolutePath::try_new(temp.path())?,
+            CommandOpts::default(),
+        )?;
+        assert_eq!("test_file", out.stdout()?.trim());
+        Ok(())
+    }
+
+    #[test]
+    fn excludes_env_vars() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let stdout = runner
+            .run_checked(["env"], AbsolutePathBuf::current_dir())?
+            .stdout()?;
+
+        assert!(stdout.contains("CARGO_MANIFEST_DIR"));
+
+        let runner = DefaultCommandRunner::ignoring_env(&["CARGO_MANIFEST_DIR"]);
+        let stdout = runner
+            .run_checked(["env"], AbsolutePathBuf::current_dir())?
+            .stdout()?;
+
+        assert!(!stdout.contains("CARGO_MANIFEST_DIR"));
+
+        let runner = DefaultCommandRunner::allowing_env(&["CWD", "CARGO_MANIFEST_DIR"]);
+        let stdout = runner
+            .run_checked(["env"], AbsolutePathBuf::current_dir())?
+            .stdout()?;
+
+        assert!(stdout.contains("CARGO_MANIFEST_DIR"));
+
+        let runner = DefaultCommandRunner::allowing_env(&["CWD"]);
+        let stdout = runner
+            .run_checked(["env"], AbsolutePathBuf::current_dir())?
+            .stdout()?;
+
+        assert!(!stdout.contains("CARGO_MANIFEST_DIR"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_profile() -> anyhow::Result<()> {
+        let profile = EnvProfile::new()
+            .allowing(&["PATH"])
+            .with_var("LC_ALL", "C");
+        let runner = DefaultCommandRunner::default().with_profile(profile);
+        let res = runner.run_checked(
+            ["/bin/sh", "-c", "echo \"$LC_ALL [$CARGO_MANIFEST_DIR]\""],
+            AbsolutePathBuf::current_dir(),
+        )?;
+        assert_eq!("C []\n", res.stdout()?);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_env() {
+        let runner = DefaultCommandRunner::ignoring_env(&["CARGO_MANIFEST_DIR"]);
+        let env = runner.dry_env(&CommandOpts {
+            env: hashmap! { "FOO".to_owned() => "BAR".to_owned() },
+            ..Default::default()
+        });
+
+        assert_eq!(Some("BAR"), env.get("FOO").map(String::as_str));
+        assert!(!env.contains_key("CARGO_MANIFEST_DIR"));
+        assert!(DefaultCommandRunner::default()
+            .dry_env(&CommandOpts::default())
+            .contains_key("CARGO_MANIFEST_DIR"));
+    }
+
+    #[test]
+    fn case_insensitive_duplicates() {
+        let env = hashmap! {
+            "PATH".to_owned() => "/bin".to_owned(),
+            "Path".to_owned() => "/usr/bin".to_owned(),
+            "path".to_owned() => "/sbin".to_owned(),
+            "HOME".to_owned() => "/root".to_owned(),
+        };
+        assert_eq!(
+            vec![vec!["PATH", "Path", "path"]],
+            super::case_insensitive_duplicates(&env)
+        );
+        assert!(super::case_insensitive_duplicates(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn builder() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let cwd = AbsolutePathBuf::try_from(temp.path().to_path_buf())?;
+        let runner = DefaultCommandRunner::builder()
+            .ignoring_env(&["CARGO_MANIFEST_DIR"])
+            .default_cwd(cwd.clone())
+            .build();
+        assert_eq!(cwd, runner.default_cwd());
+        assert!(!runner.computed_env().contains_key("CARGO_MANIFEST_DIR"));
+
+        let runner = DefaultCommandRunner::builder().build();
+        assert!(runner.computed_env().contains_key("CARGO_MANIFEST_DIR"));
+        assert_eq!(AbsolutePathBuf::current_dir(), runner.default_cwd());
+
+        // Filters replace each other, but keep the variables that the profile sets.
+        let runner = DefaultCommandRunner::builder()
+            .env_profile(EnvProfile::new().with_var("LC_ALL", "C"))
+            .ignoring_env(&["CARGO_MANIFEST_DIR"])
+            .allowing_env(&["PATH"])
+            .build();
+        let env = runner.computed_env();
+        assert_eq!(Some("C"), env.get("LC_ALL").map(String::as_str));
+        assert!(env.contains_key("PATH"));
+        assert!(!env.contains_key("CARGO_MANIFEST_DIR"));
+        Ok(())
+    }
+
+    #[test]
+    fn username() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let expected = runner.run_checked_here(["id", "-un"])?;
+        assert_eq!(expected.stdout()?.trim_end(), runner.username()?);
+        Ok(())
+    }
+
+    #[test]
+    fn computed_env() {
+        let runner = DefaultCommandRunner::ignoring_env(&["CARGO_MANIFEST_DIR"]);
+        let env = runner.computed_env();
+        assert!(!env.contains_key("CARGO_MANIFEST_DIR"));
+        assert_eq!(
+            runner.dry_env(&CommandOpts::default()),
+            runner.computed_env()
+        );
+    }
+
+    #[test]
+    fn uses_stdin() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let stdout = runner
+            .run_checked_with_opts(
+                ["cat"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    stdin: Some("TESTING".as_bytes().to_vec()),
+                    ..Default::default()
+                },
+            )?
+            .stdout()?;
+
+        assert_eq!("TESTING", stdout);
+        Ok(())
+    }
+
+    #[test]
+    fn run_pipeline() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let res = runner.run_pipeline(
+            vec![
+                CommandLine::from(["cat"]),
+                CommandLine::from(["sort"]),
+                CommandLine::from(["head", "-n", "2"]),
+            ],
+            &cwd,
+            CommandOpts {
+                stdin: Some(b"c\nb\na\n".to_vec()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("a\nb\n", res.stdout()?);
+
+        let commands = vec![
+            CommandLine::from(["/bin/sh", "-c", "exit 3"]),
+            CommandLine::from(["cat"]),
+        ];
+        let err = runner
+            .run_pipeline(commands.clone(), &cwd, CommandOpts::default())
+            .unwrap_err()
+            .downcast::<CommandFailed>()?;
+        assert_eq!("/bin/sh", err.program);
+        assert_eq!(Some(3), err.result.code());
+        let opts = CommandOpts {
+            pipefail: false,
+            ..Default::default()
+        };
+        assert!(runner.run_pipeline(commands, &cwd, opts)?.success());
+        assert!(runner
+            .run_pipeline(vec![], &cwd, CommandOpts::default())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn explains_missing_programs() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let opts = CommandOpts {
+            env: hashmap! { "PATH".to_owned() => "/bin:/usr/bin".to_owned() },
+            ..Default::default()
+        };
+        let err = runner
+            .run_with_opts(
+                ["definitely-not-a-program"],
+                AbsolutePathBuf::current_dir(),
+                opts,
+            )
+            .unwrap_err()
+            .downcast::<ProgramNotFound>()?;
+        assert_eq!("definitely-not-a-program", err.name);
+        assert_eq!(2, err.searched_dirs.len());
+
+        let err = runner
+            .run(["ls"], AbsolutePathBuf::try_new("/definitely/not/a/dir")?)
+            .unwrap_err();
+        assert!(!err.is::<ProgramNotFound>(), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn exec_with_opts_fails() -> anyhow::Result<()> {
+        // Only failures can be tested, as anything else would replace the test process.
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let opts = CommandOpts {
+            env: hashmap! { "PATH".to_owned() => "/bin:/usr/bin".to_owned() },
+            stdin: Some(b"input".to_vec()),
+            ..Default::default()
+        };
+        let err = runner
+            .exec_with_opts(CommandLine::from(["definitely-not-a-program"]), &cwd, opts)
+            .unwrap_err();
+        assert!(err.is::<ProgramNotFound>(), "{}", err);
+
+        let opts = CommandOpts {
+            pipe_stdin: true,
+            ..Default::default()
+        };
+        assert!(runner
+            .exec_with_opts(CommandLine::from(["true"]), &cwd, opts)
+            .is_err());
+
+        let opts = CommandOpts {
+            new_process_group: true,
+            ..Default::default()
+        };
+        assert!(runner
+            .exec_with_opts(CommandLine::from(["true"]), &cwd, opts)
+            .is_err());
+        let opts = CommandOpts {
+            signal_forwarding: SignalForwarding::Forward,
+            ..Default::default()
+        };
+        assert!(runner
+            .exec_with_opts(CommandLine::from(["true"]), &cwd, opts)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn runs_as_user() -> anyhow::Result<()> {
+        // Changing to the current user and group needs no privilege.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let res = DefaultCommandRunner::default().run_checked_with_opts(
+            ["/bin/sh", "-c", "id -u; id -g"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                uid: Some(uid),
+                gid: Some(gid),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(format!("{}\n{}\n", uid, gid), res.stdout()?);
+        Ok(())
+    }
+
+    #[test]
+    fn sets_nice() -> anyhow::Result<()> {
+        // Lowering the priority needs no privilege. Field 19 of `stat` is the nice value.
+        let res = DefaultCommandRunner::default().run_checked_with_opts(
+            ["/bin/sh", "-c", "cut -d ' ' -f 19 /proc/self/stat"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                nice: Some(19),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("19\n", res.stdout()?);
+        Ok(())
+    }
+
+    #[test]
+    fn sets_umask() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked_with_opts(
+            ["/bin/sh", "-c", "umask; touch created"],
+            AbsolutePath::try_new(temp.path())?,
+            CommandOpts {
+                umask: Some(0o027),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("0027\n", res.stdout()?);
+        let mode = std::fs::metadata(temp.path().join("created"))?
+            .permissions()
+            .mode();
+        assert_eq!(0o640, mode & 0o777);
+        Ok(())
+    }
+
+    #[test]
+    fn uses_stdin_file() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let input = AbsolutePathBuf::try_new(temp.path().join("input"))?;
+        std::fs::write(&input, "x".repeat(1 << 20))?;
+        let runner = DefaultCommandRunner::default();
+        let stdout = runner
+            .run_checked_with_opts(
+                ["wc", "-c"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    stdin_file: Some(input.clone()),
+                    ..Default::default()
+                },
+            )?
+            .stdout()?;
+        assert_eq!("1048576", stdout.trim());
+
+        let err = runner
+            .run_with_opts(
+                ["cat"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    stdin: Some(b"also".to_vec()),
+                    stdin_file: Some(input),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("`stdin_file`"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn sets_env_vars() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let stdout = runner
+            .run_checked_with_opts(
+                ["env"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    env: hashmap! { "FOO".to_owned() => "BAR".to_owned() },
+                    ..Default::default()
+                },
+            )?
+            .stdout()?;
+
+        assert!(stdout.contains("FOO"));
+        Ok(())
+    }
+
+    #[test]
+    fn sets_groups() -> anyhow::Result<()> {
+        // Changing groups requires privileges that we may not have.
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(());
+        }
+        let runner = DefaultCommandRunner::default();
+        let stdout = runner
+            .run_checked_with_opts(
+                ["id", "-G"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    groups: Some(vec![12345]),
+                    ..Default::default()
+                },
+            )?
+            .stdout()?;
+
+        assert!(stdout.split_whitespace().any(|g| g == "12345"));
+        Ok(())
+    }
+
+    #[test]
+    fn limits_output() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked_with_opts(
+            ["/bin/sh", "-c", "head -c 100000 /dev/zero; echo err >&2"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                max_output_bytes: Some(10),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(10, res.output.stdout.len());
+        assert!(res.stdout_truncated());
+        assert_eq!(100000, res.stdout_bytes_read());
+        assert_eq!("err\n", res.stderr()?);
+        assert!(!res.stderr_truncated());
+        Ok(())
+    }
+
+    #[test]
+    fn counts_bytes_read() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked(
+            ["/bin/sh", "-c", "printf 12345; printf 123 >&2"],
+            AbsolutePathBuf::current_dir(),
+        )?;
+
+        assert_eq!(5, res.stdout_bytes_read());
+        assert_eq!(3, res.stderr_bytes_read());
+        Ok(())
+    }
+
+    #[test]
+    fn run_checked_codes() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let res = runner.run_checked_codes(
+            ["/bin/sh", "-c", "exit 1"],
+            &cwd,
+            CommandOpts::default(),
+            &[0, 1],
+        )?;
+        assert_eq!(Some(1), res.status.code());
+
+        let err = runner
+            .run_checked_codes(
+                ["/bin/sh", "-c", "exit 2"],
+                &cwd,
+                CommandOpts::default(),
+                &[0, 1],
+            )
+            .unwrap_err();
+        assert_eq!(
+            Some(2),
+            err.downcast::<CommandFailed>()?.result.status.code()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn throttles_reads() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let start = Instant::now();
+        let res = runner.run_checked_with_opts(
+            ["head", "-c", "300", "/dev/zero"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                read_throttle: Some(BytesPerSecond(1000)),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(300, res.stdout.len());
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_output_file() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let log = temp.path().join("out.log");
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked_with_opts(
+            [
+                "/bin/sh",
+                "-c",
+                "for i in 1 2 3 4 5 6; do echo line$i; sleep 0.05; done",
+            ],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                stdout_capture: Some(StdioCapture::RotatingFile {
+                    path: log.clone(),
+                    max_size: 12,
+                    keep: 2,
+                }),
+                ..Default::default()
+            },
+        )?;
+
+        assert!(res.stdout.is_empty());
+        assert_eq!(36, res.stdout_bytes_read());
+        let read = |name: &str| std::fs::read_to_string(temp.path().join(name));
+        assert_eq!("line5\nline6\n", read("out.log")?);
+        assert_eq!("line3\nline4\n", read("out.log.1")?);
+        assert_eq!("line1\nline2\n", read("out.log.2")?);
+        assert!(!temp.path().join("out.log.3").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn shebang_fallback() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let script = temp.path().join("script");
+        std::fs::write(&script, "#!/bin/sh -e\necho hello $1\n")?;
+        let script = script.to_string_lossy().into_owned();
+        let cwd = AbsolutePath::try_new(temp.path())?;
+
+        let runner = DefaultCommandRunner::default();
+        assert!(runner.run([script.as_str(), "world"], cwd).is_err());
+
+        let runner = DefaultCommandRunner::default().shebang_fallback(true);
+        let res = runner.run_checked([script.as_str(), "world"], cwd)?;
+        assert_eq!("hello world\n", res.stdout()?);
+        let res = runner.run_checked(["./script", "there"], cwd)?;
+        assert_eq!("hello there\n", res.stdout()?);
+
+        std::fs::write(temp.path().join("not_a_script"), "echo hello\n")?;
+        assert!(runner.run(["./not_a_script"], cwd).is_err());
+
+        let mut cli = CommandLine::from(["./script"]);
+        cli.push_secret("hunter2");
+        let fallback = runner
+            .shebang_command(&cli, cwd, &CommandOpts::default())?
+            .expect("a shebang");
+        let script = temp.path().join("script").to_string_lossy().into_owned();
+        assert_eq!(["/bin/sh", "-e", script.as_str(), "hunter2"], *fallback);
+        assert_eq!(format!("/bin/sh -e {} ****", script), fallback.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn describes_bytes() {
+        assert_eq!(r#""a\nb""#, super::describe_bytes(b"a\nb", 512));
+        assert_eq!(
+            format!("{:?}... (1 more bytes)", "x".repeat(512)),
+            super::describe_bytes("x".repeat(513).as_bytes(), 512)
+        );
+    }
+
+    #[test]
+    fn describes_secret_stdin() {
+        assert_eq!(r#""hunter2""#, super::describe_stdin(b"hunter2", false));
+        assert_eq!("7 secret bytes", super::describe_stdin(b"hunter2", true));
+    }
+
+    #[test]
+    fn pipeline_keeps_stderr_separate() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let stages = |capture_stderr: bool| {
+            vec![
+                (
+                    CommandLine::from(["printf", "a\\nb\\n"]),
+                    CommandOpts::default(),
+                ),
+                (
+                    CommandLine::from(["/bin/sh", "-c", "echo oops >&2; tr a-z A-Z"]),
+                    CommandOpts {
+                        capture_stderr,
+                        ..Default::default()
+                    },
+                ),
+                (CommandLine::from(["sort", "-r"]), CommandOpts::default()),
+            ]
+        };
+
+        let results = runner.run_pipeline_stages(stages(true), &cwd)?;
+        assert_eq!(3, results.len());
+        assert!(results.iter().all(|r| r.status.success()));
+        assert_eq!("", results[0].stdout()?);
+        assert_eq!("", results[1].stdout()?);
+        assert_eq!("B\nA\n", results[2].stdout()?);
+        assert_eq!(b"oops\n".to_vec(), results[1].stderr);
+        assert!(results[2].stderr.is_empty());
+
+        let results = runner.run_pipeline_stages(stages(false), &cwd)?;
+        assert_eq!("B\nA\n", results[2].stdout()?);
+        assert!(results[1].stderr.is_empty());
+
+        let mut bad = stages(true);
+        bad[1].1.stdin = Some(b"nope".to_vec());
+        assert!(runner.run_pipeline_stages(bad, &cwd).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_times_out() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let start = Instant::now();
+        let commands = vec![
+            CommandLine::from(["sleep", "10"]),
+            CommandLine::from(["cat"]),
+        ];
+        let opts = CommandOpts {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let err = runner.run_pipeline(commands, &cwd, opts).unwrap_err();
+        assert!(err.is::<CommandTimeout>());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let stages = vec![
+            (
+                CommandLine::from(["/bin/sh", "-c", "echo out; echo err >&2"]),
+                CommandOpts {
+                    capture_stderr: true,
+                    capture_interleaved: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                CommandLine::from(["/bin/sh", "-c", "cat; echo err >&2"]),
+                CommandOpts {
+                    capture_stderr: true,
+                    capture_interleaved: true,
+                    ..Default::default()
+                },
+            ),
+        ];
+        let results = runner.run_pipeline_stages(stages, &cwd)?;
+        assert_eq!(
+            [(Stream::Stderr, b"err\n".to_vec())],
+            results[0].interleaved()
+        );
+        let interleaved = results[1].interleaved();
+        assert!(interleaved.contains(&(Stream::Stdout, b"out\n".to_vec())));
+        assert!(interleaved.contains(&(Stream::Stderr, b"err\n".to_vec())));
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_kills_started_stages() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        // A duration that no other test sleeps for, to find the first stage by.
+        let sleep = CommandLine::from(["sleep", "1000.271828"]);
+        let is_running = || -> anyhow::Result<bool> {
+            for entry in std::fs::read_dir("/proc")? {
+                let cmdline = std::fs::read(entry?.path().join("cmdline")).unwrap_or_default();
+                if cmdline == b"sleep\01000.271828\0" {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+
+        let stages = vec![
+            (sleep.clone(), CommandOpts::default()),
+            (
+                CommandLine::from(["definitely-not-a-program"]),
+                CommandOpts::default(),
+            ),
+        ];
+        assert!(runner.run_pipeline_stages(stages, &cwd).is_err());
+        assert!(!is_running()?);
+
+        let stages = vec![
+            (sleep, CommandOpts::default()),
+            (
+                CommandLine::from(["cat"]),
+                CommandOpts {
+                    stdin: Some(b"nope".to_vec()),
+                    ..Default::default()
+                },
+            ),
+        ];
+        assert!(runner.run_pipeline_stages(stages, &cwd).is_err());
+        assert!(!is_running()?);
+
+        let commands = vec![
+            CommandLine::from(["sleep", "1000.271828"]),
+            CommandLine::from(["definitely-not-a-program"]),
+        ];
+        assert!(runner
+            .run_pipeline(commands, &cwd, CommandOpts::default())
+            .is_err());
+        assert!(!is_running()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompresses_gzip() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"hello from gzip")?;
+        let res = DefaultCommandRunner::default().run_checked_with_opts(
+            ["cat"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                stdin: Some(encoder.finish()?),
+                decompress_stdout: Some(crate::Decompression::Gzip),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("hello from gzip", res.stdout()?);
+        assert_eq!(15, res.stdout_bytes_read());
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decompresses_zstd() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let opts = CommandOpts {
+            stdin: Some(zstd::encode_all(&b"hello from zstd"[..], 0)?),
+            decompress_stdout: Some(crate::Decompression::Zstd),
+            ..Default::default()
+        };
+        let res = runner.run_checked_with_opts(["cat"], AbsolutePathBuf::current_dir(), opts)?;
+        assert_eq!("hello from zstd", res.stdout()?);
+
+        let opts = CommandOpts {
+            stdin: Some(b"not compressed".to_vec()),
+            decompress_stdout: Some(crate::Decompression::Zstd),
+            ..Default::default()
+        };
+        assert!(runner
+            .run_with_opts(["cat"], AbsolutePathBuf::current_dir(), opts)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn posix_spawn() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let opts = CommandOpts {
+            spawn_method: SpawnMethod::PosixSpawn,
+            ..Default::default()
+        };
+        let res = runner.run_checked_with_opts(
+            ["echo", "spawned"],
+            AbsolutePathBuf::current_dir(),
+            opts.clone(),
+        )?;
+        assert_eq!("spawned\n", res.stdout()?);
+
+        assert!(runner
+            .run_with_opts(
+                ["echo", "spawned"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    groups: Some(vec![]),
+                    ..opts.clone()
+                },
+            )
+            .is_err());
+        assert!(runner
+            .run_with_opts(
+                ["definitely-not-a-real-program"],
+                AbsolutePathBuf::current_dir(),
+                opts,
+            )
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn run_events() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let events: Vec<_> = runner
+            .run_events(
+                [
+                    "/bin/sh",
+                    "-c",
+                    "echo out; sleep 0.05; echo err >&2; exit 3",
+                ],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts::default(),
+            )?
+            .collect();
+
+        assert_eq!(4, events.len(), "{:?}", events);
+        assert!(matches!(events[0], CommandEvent::Started { pid } if pid > 0));
+        assert_eq!(CommandEvent::Stdout(b"out\n".to_vec()), events[1]);
+        assert_eq!(CommandEvent::Stderr(b"err\n".to_vec()), events[2]);
+        assert!(matches!(&events[3], CommandEvent::Exited(status) if status.code() == Some(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn run_foreground() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        assert!(runner.run_foreground(["true"], &cwd)?.success());
+        assert_eq!(
+            Some(3),
+            runner
+                .run_foreground(["/bin/sh", "-c", "exit 3"], &cwd)?
+                .code()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn env_after_script() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let env = runner.env_after_script(
+            "echo noise; export ACTIVATED='yes\nreally'; unset HOME",
+            &cwd,
+        )?;
+        assert_eq!(
+            Some("yes\nreally"),
+            env.get("ACTIVATED").map(String::as_str)
+        );
+        assert!(!env.contains_key("HOME"));
+        assert!(env.contains_key("PATH"));
+
+        assert!(runner.env_after_script("false", &cwd).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn log_label() {
+        assert_eq!("", CommandOpts::default().log_label());
+        let opts = CommandOpts {
+            label: Some("fetching deps".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!("[fetching deps] ", opts.log_label());
+    }
+
+    #[test]
+    fn log_level() {
+        let opts = CommandOpts::default();
+        assert_eq!(log::Level::Info, opts.log_level(log::Level::Info));
+        let opts = CommandOpts {
+            quiet: true,
+            ..Default::default()
+        };
+        assert_eq!(log::Level::Trace, opts.log_level(log::Level::Info));
+        assert_eq!(log::Level::Trace, opts.log_level(log::Level::Debug));
+    }
+
+    #[test]
+    fn captures_to_temp_file() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked_with_opts(
+            ["/bin/sh", "-c", "echo out; echo err >&2"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                stdout_capture: Some(StdioCapture::TempFile),
+                ..Default::default()
+            },
+        )?;
+
+        assert!(res.stdout.is_empty());
+        assert_eq!(b"err\n".to_vec(), res.stderr);
+        assert!(res.stderr_file().is_none());
+        let file = res.stdout_file().expect("a temp file");
+        let path = file.path().to_owned();
+        let mut contents = String::new();
+        file.as_file().read_to_string(&mut contents)?;
+        assert_eq!("out\n", contents);
+        file.as_file().seek(SeekFrom::Start(0))?;
+
+        drop(res);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn with_default_cwd() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        std::fs::write(temp.path().join("test_file"), "contents")?;
+
+        let runner = DefaultCommandRunner::default();
+        assert_eq!(AbsolutePathBuf::current_dir(), runner.default_cwd());
+
+        let cwd = AbsolutePathBuf::try_from(temp.path().to_path_buf())?;
+        let runner = runner.with_default_cwd(cwd.clone());
+        assert_eq!(cwd, runner.default_cwd());
+        let out = runner.run_checked_here(["ls", "-1"])?;
+        assert_eq!("test_file", out.stdout()?.trim());
+        assert!(runner.run_here(["ls", "missing"])?.status.code() != Some(0));
+        let handle = runner.spawn_here(["ls", "-1"], CommandOpts::default())?;
+        assert_eq!("test_file", handle.wait()?.stdout()?.trim());
+        Ok(())
+    }
+
+    #[test]
+    fn merges_stderr_into_stdout() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let command = ["/bin/sh", "-c", "echo out; echo err >&2; echo out2"];
+        let res = runner.run_checked_with_opts(
+            command,
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                merge_stderr_into_stdout: true,
+                ..Default::default()
+            },
+        )?;
+        assert!(res.is_merged());
+        assert_eq!("out\nerr\nout2\n", res.stdout()?);
+        assert_eq!("", res.stderr()?);
+
+        let res = runner.run_checked(command, AbsolutePathBuf::current_dir())?;
+        assert!(!res.is_merged());
+        assert_eq!("out\nout2\n", res.stdout()?);
+        assert_eq!("err\n", res.stderr()?);
+
+        let err = runner
+            .run_with_opts(
+                command,
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    merge_stderr_into_stdout: true,
+                    stderr_capture: Some(StdioCapture::TempFile),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot both be set"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn captures_interleaved() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        // The sleeps make sure that each chunk is read before the next one is written.
+        let command = [
+            "/bin/sh",
+            "-c",
+            "echo out; sleep 0.1; echo err >&2; sleep 0.1; echo out2",
+        ];
+        let res = runner.run_checked_with_opts(
+            command,
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                capture_interleaved: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("out\nout2\n", res.stdout()?);
+        assert_eq!("err\n", res.stderr()?);
+        assert_eq!(
+            vec![
+                (Stream::Stdout, b"out\n".to_vec()),
+                (Stream::Stderr, b"err\n".to_vec()),
+                (Stream::Stdout, b"out2\n".to_vec()),
+            ],
+            res.interleaved()
+        );
+
+        let res = runner.run_checked(command, AbsolutePathBuf::current_dir())?;
+        assert!(res.interleaved().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn measures_duration() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked(["sleep", "0.1"], AbsolutePathBuf::current_dir())?;
+
+        assert!(res.duration() >= Duration::from_millis(100));
+        assert!(res.expect_within(Duration::from_secs(60)).is_ok());
+        assert!(res.expect_within(Duration::from_millis(10)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn captures_on_failure() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let opts = CommandOpts {
+            capture_on_failure: true,
+            ..Default::default()
+        };
+        let res = runner.run_with_opts(
+            ["/bin/sh", "-c", "echo out; echo err >&2"],
+            AbsolutePathBuf::current_dir(),
+            opts.clone(),
+        )?;
+        assert!(res.status.success());
+        assert!(res.stdout.is_empty());
+        assert!(res.stderr.is_empty());
+        assert_eq!(4, res.stdout_bytes_read());
+
+        let err = runner
+            .run_checked_with_opts(
+                ["/bin/sh", "-c", "echo out; echo err >&2; exit 2"],
+                AbsolutePathBuf::current_dir(),
+                opts,
+            )
+            .unwrap_err()
+            .downcast::<CommandFailed>()?;
+        assert_eq!("out\n", err.result.stdout()?);
+        assert_eq!("err\n", err.result.stderr()?);
+        Ok(())
+    }
+
+    #[test]
+    fn times_out() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let started = Instant::now();
+        let err = runner
+            .run_with_opts(
+                ["/bin/sh", "-c", "echo partial; exec sleep 10"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    timeout: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err()
+            .downcast::<CommandTimeout>()?;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!("/bin/sh", err.program);
+        assert!(err.elapsed >= Duration::from_millis(100));
+        assert_eq!(Some(libc::SIGTERM), err.result.status.signal());
+        assert_eq!("partial\n", err.result.stdout()?);
+
+        let res = runner.run_with_opts(
+            ["echo", "fast"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                timeout: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!("fast\n", res.stdout()?);
+        Ok(())
     }
-}
 
-impl Deref for ExecutionResult {
-    type Target = Output;
+    #[test]
+    fn times_out_process_group() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let started = Instant::now();
+        // Without a process group, the backgrounded `sleep` would keep stdout open after the
+        // shell is terminated.
+        let err = runner
+            .run_with_opts(
+                ["/bin/sh", "-c", "sleep 30 & echo $!; wait"],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    timeout: Some(Duration::from_millis(200)),
+                    new_process_group: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err()
+            .downcast::<CommandTimeout>()?;
+        assert!(started.elapsed() < Duration::from_secs(5));
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        let stat = format!("/proc/{}/stat", err.result.stdout()?.trim());
+        if let Ok(stat) = std::fs::read_to_string(stat) {
+            assert!(
+                stat.contains(") Z "),
+                "the backgrounded `sleep` is running: {}",
+                stat
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn times_out_process_group_ignoring_terminate() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let started = Instant::now();
+        // The shell exits on SIGTERM, but the backgrounded `sleep` ignores it, and would keep
+        // stdout open unless the group is killed after the grace period.
+        let err = runner
+            .run_with_opts(
+                [
+                    "/bin/sh",
+                    "-c",
+                    "(trap '' TERM; exec sleep 30) & echo $!; wait",
+                ],
+                AbsolutePathBuf::current_dir(),
+                CommandOpts {
+                    timeout: Some(Duration::from_millis(200)),
+                    new_process_group: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err()
+            .downcast::<CommandTimeout>()?;
+        assert!(started.elapsed() < Duration::from_secs(15));
+        assert_eq!(Some(libc::SIGTERM), err.result.status.signal());
+
+        let stat = format!("/proc/{}/stat", err.result.stdout()?.trim());
+        if let Ok(stat) = std::fs::read_to_string(stat) {
+            assert!(
+                stat.contains(") Z "),
+                "the backgrounded `sleep` is running: {}",
+                stat
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn run_paged() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let cwd = AbsolutePath::try_new(temp.path())?;
+        let runner = DefaultCommandRunner::default();
+
+        // Whether or not stdout is a terminal, stdout is not captured.
+        let res = runner.run_paged(["/bin/sh", "-c", "echo hi; exit 3"], cwd)?;
+        assert_eq!(Some(3), res.status.code());
+        assert!(res.stdout.is_empty());
+
+        let res = runner.run_through_pager(
+            CommandLine::from(["/bin/sh", "-c", "echo paged; exit 3"]),
+            cwd,
+            CommandLine::from(["/bin/sh", "-c", "echo $LESS > env; cat > paged"]),
+        )?;
+        assert_eq!(Some(3), res.status.code());
+        assert_eq!(
+            "paged\n",
+            std::fs::read_to_string(temp.path().join("paged"))?
+        );
+        assert_eq!("FRX\n", std::fs::read_to_string(temp.path().join("env"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn measures_time_to_first_output() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let res = runner.run_checked(
+            ["/bin/sh", "-c", "sleep 0.1; echo ready; sleep 0.2"],
+            AbsolutePathBuf::current_dir(),
+        )?;
+
+        let latency = res.time_to_first_output().unwrap();
+        assert!(latency >= Duration::from_millis(100));
+        assert!(latency < res.duration());
+        assert!(res
+            .expect_first_output_within(Duration::from_secs(60))
+            .is_ok());
+        assert!(res
+            .expect_first_output_within(Duration::from_millis(10))
+            .is_err());
+
+        let res = runner.run_checked(["true"], AbsolutePathBuf::current_dir())?;
+        assert_eq!(None, res.time_to_first_output());
+        Ok(())
+    }
+
+    #[test]
+    fn includes_hint_on_failure() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let err = runner
+            .run_checked_with_hint(
+                ["/bin/sh", "-c", "echo not a repo >&2; exit 3"],
+                AbsolutePathBuf::current_dir(),
+                "run `git init` first",
+            )
+            .unwrap_err();
+
+        assert!(err.to_string().ends_with("\nHint: run `git init` first"));
+        let failed = err.downcast::<CommandFailed>()?;
+        assert_eq!("/bin/sh", failed.program);
+        assert_eq!(Some(3), failed.result.status.code());
+        assert_eq!(b"not a repo\n".to_vec(), failed.result.stderr);
+        Ok(())
     }
 }
 
-/// Various pieces of the environment needed to execute commands, find paths, etc.
-///
-/// A trait so that testing is easier.
-pub trait Env {
-    fn execute<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<ExecutionResult>
-    where
-        Self: Sized;
+#[cfg(test)]
+mod execution_result_tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
 
-    fn exec<T: AsRef<OsStr>>(&self, cmd: &[T]) -> anyhow::Result<()>
-    where
-        Self: Sized;
+    use paths::AbsolutePath;
 
-    fn root_systemd_path(&self) -> AbsolutePathBuf;
-    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf>;
-    fn hostname(&self) -> anyhow::Result<String>;
+    use crate::ExecutionResult;
+
+    fn result(stdout: &str, stderr: &str) -> ExecutionResult {
+        ExecutionResult::from(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        })
+    }
+
+    #[test]
+    fn exit_status_helpers() {
+        let res = ExecutionResult::from(Output {
+            status: ExitStatus::from_raw(3 << 8),
+            stdout: vec![],
+            stderr: vec![],
+        });
+        assert_eq!(
+            (Some(3), None, false),
+            (res.code(), res.signal(), res.success())
+        );
+
+        let res = ExecutionResult::from(Output {
+            status: ExitStatus::from_raw(libc::SIGKILL),
+            stdout: vec![],
+            stderr: vec![],
+        });
+        assert_eq!(
+            (None, Some(libc::SIGKILL), false),
+            (res.code(), res.signal(), res.success())
+        );
+        assert!(result("", "").success());
+    }
+
+    #[test]
+    fn lines() -> anyhow::Result<()> {
+        let res = result("M a.rs\n?? b.rs\n", "warning\n\nerror");
+        assert_eq!(vec!["M a.rs", "?? b.rs"], res.stdout_lines()?);
+        assert_eq!(vec!["warning", "", "error"], res.stderr_lines()?);
+        assert!(result("", "").stdout_lines()?.is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json() -> anyhow::Result<()> {
+        let res = result(r#"{"name": "foo", "version": 2}"#, "");
+        let parsed: serde_json::Value = res.json()?;
+        assert_eq!(serde_json::json!({"name": "foo", "version": 2}), parsed);
+        let err = result("not json", "")
+            .json::<serde_json::Value>()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains(r#""not json""#), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn stderr_and_combined() -> anyhow::Result<()> {
+        let res = result("out\n", "err\n");
+        assert_eq!("err\n", res.stderr()?);
+        assert_eq!("out\nerr\n", res.combined()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn expect_stderr_matches() {
+        let res = result("", "error: unknown flag `--frob`\n");
+        assert!(res.expect_stderr_matches(r"unknown flag `--\w+`").is_ok());
+        let err = res
+            .expect_stderr_matches("^warning")
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("error: unknown flag `--frob`\n"), "{}", err);
+        assert!(res.expect_stderr_matches("(").is_err());
+    }
+
+    #[test]
+    fn assert_matches_file() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let golden = temp.path().join("golden.txt");
+        std::fs::write(&golden, "a\nb\nc\n")?;
+        let golden = AbsolutePath::try_new(&golden)?;
+
+        assert!(result("a\nb\nc\n", "").assert_matches_file(golden).is_ok());
+        let err = result("a\nB\nc\n", "")
+            .assert_matches_file(golden)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(" a\n-b\n+B\n c\n"), "{}", err);
+        let err = result("a\nb\nc", "")
+            .assert_matches_file(golden)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("trailing newlines"), "{}", err);
+        assert!(result("", "")
+            .assert_matches_file(AbsolutePath::try_new(&temp.path().join("missing.txt"))?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn numbered_lines() -> anyhow::Result<()> {
+        let res = result("first\n\nthird\n", "");
+        assert_eq!(
+            vec![(1, "first"), (2, ""), (3, "third")],
+            res.numbered_lines()?.collect::<Vec<_>>()
+        );
+
+        let res = ExecutionResult::from(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: vec![0xff],
+            stderr: vec![],
+        });
+        assert!(res.numbered_lines().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn map_code() {
+        #[derive(Debug, PartialEq)]
+        enum Rsync {
+            Success,
+            PartialTransfer,
+            Other,
+        }
+        let to_rsync = |code: Option<i32>| match code {
+            Some(0) => Rsync::Success,
+            Some(23) => Rsync::PartialTransfer,
+            _ => Rsync::Other,
+        };
+        let with_status = |raw| {
+            ExecutionResult::from(Output {
+                status: ExitStatus::from_raw(raw),
+                stdout: vec![],
+                stderr: vec![],
+            })
+        };
+
+        assert_eq!(Rsync::Success, with_status(0).map_code(to_rsync));
+        assert_eq!(
+            Rsync::PartialTransfer,
+            with_status(23 << 8).map_code(to_rsync)
+        );
+        // Killed by SIGKILL
+        assert_eq!(Rsync::Other, with_status(9).map_code(to_rsync));
+    }
 }
 
 #[cfg(test)]
-mod default_runner_tests {
+mod test_runner_tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    use std::time::Duration;
+
     use maplit::hashmap;
-    use paths::AbsolutePath;
     use paths::AbsolutePathBuf;
 
+    use crate::test::CommandMatcher;
+    use crate::test::Invocation;
+    use crate::test::TestCommandRunner;
+    use crate::BatchesFailed;
+    use crate::CommandLine;
     use crate::CommandOpts;
     use crate::CommandRunner;
-    use crate::DefaultCommandRunner;
+    use crate::ProgramNotFound;
+    use crate::RetryPolicy;
+
+    fn runner_with_history() -> anyhow::Result<TestCommandRunner> {
+        let runner = TestCommandRunner::with_results([(0, ""), (0, ""), (0, "")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        runner.run(["git", "fetch"], &cwd)?;
+        runner.run(["git", "status"], &cwd)?;
+        runner.run(["git", "push"], &cwd)?;
+        Ok(runner)
+    }
 
     #[test]
-    fn sets_cwd_correctly() -> anyhow::Result<()> {
-        let temp = tempfile::TempDir::new()?;
-        let runner = DefaultCommandRunner::default();
-        std::fs::write(temp.path().join("test_file"), "contents")?;
+    fn records_timeout() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        let timeout = Some(Duration::from_secs(1));
+        runner.run_with_opts(
+            ["sleep", "10"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                timeout,
+                ..Default::default()
+            },
+        )?;
+        let issued = runner.issued_commands.read().unwrap();
+        assert_eq!(timeout, issued[0].timeout());
+        assert_eq!(
+            Invocation::new(
+                CommandLine::from(["sleep", "10"]),
+                AbsolutePathBuf::current_dir()
+            )
+            .with_timeout(timeout),
+            issued[0]
+        );
+        Ok(())
+    }
 
-        let out = runner.run_checked(["ls", "-1", "."], AbsolutePath::try_new(temp.path())?)?;
-        assert_eq!("test_file", out.stdout()?.trim());
+    fn output(stdout: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: vec![],
+        }
+    }
 
-        let out = runner.run(["ls", "-1", "."], AbsolutePath::try_new(temp.path())?)?;
-        assert_eq!("test_file", out.stdout()?.trim());
+    #[test]
+    fn expect_matches_commands() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "queued")])?;
+        runner.expect(CommandMatcher::program("git"), output("git"));
+        runner.expect(CommandMatcher::exact(["ls", "-l"]), output("ls"));
+        runner.expect(
+            CommandMatcher::predicate(|cli| cli.contains(&"--json".to_owned())),
+            output("json"),
+        );
+        let cwd = AbsolutePathBuf::current_dir();
 
-        let out = runner.run_with_opts(
-            ["ls", "-1", "."],
-            AbsolutePath::try_new(temp.path())?,
-            CommandOpts::default(),
-        )?;
-        assert_eq!("test_file", out.stdout()?.trim());
+        assert_eq!("json", runner.run(["tool", "--json"], &cwd)?.stdout()?);
+        assert_eq!("ls", runner.run(["ls", "-l"], &cwd)?.stdout()?);
+        assert_eq!("queued", runner.run(["ls"], &cwd)?.stdout()?);
+        assert_eq!("git", runner.run(["git", "status"], &cwd)?.stdout()?);
+        assert!(runner.expected_outputs.read().unwrap().is_empty());
         Ok(())
     }
 
     #[test]
-    fn excludes_env_vars() -> anyhow::Result<()> {
-        let runner = DefaultCommandRunner::default();
-        let stdout = runner
-            .run_checked(["env"], AbsolutePathBuf::current_dir())?
-            .stdout()?;
+    fn into_result() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "synced"), (1 << 8, "conflict")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let res = runner.run(["git", "pull"], &cwd)?.into_result()?;
+        assert_eq!("synced", res.stdout()?);
+        assert_eq!(b"synced".to_vec(), res.output().stdout);
 
-        assert!(stdout.contains("CARGO_MANIFEST_DIR"));
-
-        let runner = DefaultCommandRunner::ignoring_env(&["CARGO_MANIFEST_DIR"]);
-        let stdout = runner
-            .run_checked(["env"], AbsolutePathBuf::current_dir())?
-            .stdout()?;
+        let err = runner
+            .run(["git", "pull"], &cwd)?
+            .into_result()
+            .unwrap_err();
+        assert_eq!("git", err.program);
+        assert_eq!("conflict", err.result.stdout()?);
+        Ok(())
+    }
 
-        assert!(!stdout.contains("CARGO_MANIFEST_DIR"));
+    #[test]
+    fn fails_commands() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "ran")])?;
+        runner.fail_matching(
+            CommandMatcher::program("kubectl"),
+            std::io::Error::from(std::io::ErrorKind::NotFound).into(),
+        );
+        runner.fail_next(anyhow::anyhow!("spawn failed"));
+        let cwd = AbsolutePathBuf::current_dir();
 
-        let runner = DefaultCommandRunner::allowing_env(&["CWD", "CARGO_MANIFEST_DIR"]);
-        let stdout = runner
-            .run_checked(["env"], AbsolutePathBuf::current_dir())?
-            .stdout()?;
+        let err = runner.run(["ls"], &cwd).unwrap_err();
+        assert_eq!("spawn failed", err.to_string());
+        let err = runner.run(["kubectl", "get", "pods"], &cwd).unwrap_err();
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+        assert_eq!(
+            "ran",
+            runner.run(["kubectl", "get", "pods"], &cwd)?.stdout()?
+        );
+        assert_eq!(3, runner.invocation_count());
+        Ok(())
+    }
 
-        assert!(stdout.contains("CARGO_MANIFEST_DIR"));
+    #[test]
+    fn reports_duration() -> anyhow::Result<()> {
+        let runner =
+            TestCommandRunner::with_results([(0, "")])?.with_duration(Duration::from_secs(90));
+        let cwd = AbsolutePathBuf::current_dir();
+        let res = runner.run(["make"], &cwd)?;
+        assert_eq!(Duration::from_secs(90), res.duration());
+        assert!(res.expect_within(Duration::from_secs(60)).is_err());
 
-        let runner = DefaultCommandRunner::allowing_env(&["CWD"]);
-        let stdout = runner
-            .run_checked(["env"], AbsolutePathBuf::current_dir())?
-            .stdout()?;
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        assert!(runner.run(["make"], &cwd)?.duration() < Duration::from_secs(1));
+        Ok(())
+    }
 
-        assert!(!stdout.contains("CARGO_MANIFEST_DIR"));
+    #[test]
+    fn running_dry_is_an_error() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        runner.run(["ls"], &cwd)?;
 
+        let err = runner.run(["ls", "-l"], &cwd).unwrap_err();
+        assert!(err.to_string().contains("`ls -l`"), "{}", err);
+        assert!(err.to_string().contains("0 outputs remained"), "{}", err);
+        assert_eq!(2, runner.issued_commands.read().unwrap().len());
         Ok(())
     }
 
     #[test]
-    fn uses_stdin() -> anyhow::Result<()> {
-        let runner = DefaultCommandRunner::default();
-        let stdout = runner
-            .run_checked_with_opts(
-                ["cat"],
+    fn xargs() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "a"), (1 << 8, "b"), (0, "c")])?;
+        let items = ["1", "2", "3", "4", "5"].map(str::to_owned);
+        let err = runner
+            .xargs(
+                CommandLine::from(["rm", "-f"]),
+                items.into_iter(),
                 AbsolutePathBuf::current_dir(),
-                CommandOpts {
-                    stdin: Some("TESTING".as_bytes().to_vec()),
-                    ..Default::default()
-                },
-            )?
-            .stdout()?;
+                2,
+            )
+            .unwrap_err();
+        assert_eq!("1 of 3 batches of `rm` failed", err.to_string());
+        assert_eq!(3, err.downcast::<BatchesFailed>()?.results.len());
+        runner.assert_order(&[
+            CommandLine::from(["rm", "-f", "1", "2"]),
+            CommandLine::from(["rm", "-f", "3", "4"]),
+            CommandLine::from(["rm", "-f", "5"]),
+        ]);
 
-        assert_eq!("TESTING", stdout);
+        let runner = TestCommandRunner::new();
+        let cwd = AbsolutePathBuf::current_dir();
+        let base = CommandLine::from(["rm"]);
+        assert!(runner
+            .xargs(base.clone(), std::iter::empty(), &cwd, 2)?
+            .is_empty());
+        assert!(runner.xargs(base, std::iter::empty(), &cwd, 0).is_err());
+        runner.assert_nothing_ran();
+
+        // Only one batch has a result, so the second one cannot be run, and the third is not
+        // tried.
+        let runner = TestCommandRunner::with_results([(1 << 8, "a")])?;
+        let items = ["1", "2", "3"].map(str::to_owned);
+        let err = runner
+            .xargs(CommandLine::from(["rm"]), items.into_iter(), &cwd, 1)
+            .unwrap_err();
+        assert!(!err.is::<BatchesFailed>(), "{}", err);
+        runner.assert_order(&[
+            CommandLine::from(["rm", "1"]),
+            CommandLine::from(["rm", "2"]),
+        ]);
+        assert_eq!(2, runner.issued_commands.read().unwrap().len());
         Ok(())
     }
 
     #[test]
-    fn sets_env_vars() -> anyhow::Result<()> {
-        let runner = DefaultCommandRunner::default();
-        let stdout = runner
-            .run_checked_with_opts(
-                ["env"],
-                AbsolutePathBuf::current_dir(),
-                CommandOpts {
-                    env: hashmap! { "FOO".to_owned() => "BAR".to_owned() },
-                    ..Default::default()
-                },
-            )?
-            .stdout()?;
+    fn assertion_helpers() -> anyhow::Result<()> {
+        let runner = runner_with_history()?;
+        assert_eq!(3, runner.invocation_count());
+        assert_eq!(
+            Some(CommandLine::from(["git", "status"])),
+            runner.nth_invocation(1).map(|i| (*i).clone())
+        );
+        assert_eq!(None, runner.nth_invocation(3));
+        runner.assert_ran("git");
+        runner.assert_ran_in_order(&["git", "git"]);
+        Ok(())
+    }
 
-        assert!(stdout.contains("FOO"));
+    #[test]
+    #[should_panic(expected = "Expected programs to be run in order")]
+    fn assert_ran_panics() {
+        runner_with_history().unwrap().assert_ran("cargo");
+    }
+
+    #[test]
+    fn stubs_vars() {
+        let runner = TestCommandRunner::new().with_var("HOME", "/home/test");
+        assert_eq!(Some("/home/test".to_owned()), runner.var("HOME"));
+        assert_eq!(None, runner.var("PATH"));
+    }
+
+    #[test]
+    fn run_pipeline() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(1 << 8, "b\na\n"), (0, "a\nb\n")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let commands = vec![CommandLine::from(["ls"]), CommandLine::from(["sort"])];
+        let opts = CommandOpts {
+            pipefail: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            "a\nb\n",
+            runner.run_pipeline(commands, &cwd, opts)?.stdout()?
+        );
+        runner.assert_order(&[CommandLine::from(["ls"]), CommandLine::from(["sort"])]);
+        assert_eq!(2, runner.issued_commands.read().unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn run_with_retries() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(1 << 8, ""), (128 << 8, ""), (0, "ok")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let res =
+            runner.run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)?;
+        assert_eq!("ok", res.stdout()?);
+        assert_eq!(3, runner.issued_commands.read().unwrap().len());
+
+        let runner = TestCommandRunner::with_results([(1 << 8, ""), (1 << 8, ""), (0, "")])?;
+        let policy = RetryPolicy::new(2, Duration::ZERO);
+        let res =
+            runner.run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)?;
+        assert_eq!(Some(1), res.code());
+        assert_eq!(2, runner.issued_commands.read().unwrap().len());
+
+        let runner = TestCommandRunner::with_results([(1 << 8, ""), (0, "")])?;
+        let policy = RetryPolicy::new(3, Duration::ZERO).retry_if(|res| res.code() == Some(128));
+        let res =
+            runner.run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)?;
+        assert_eq!(Some(1), res.code());
+        assert_eq!(1, runner.issued_commands.read().unwrap().len());
+
+        // Errors are retried, but not a missing program, unless the policy says otherwise.
+        let runner = TestCommandRunner::with_results([(0, "ok")])?;
+        runner.fail_next(anyhow::anyhow!("transient"));
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let res =
+            runner.run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)?;
+        assert_eq!("ok", res.stdout()?);
+        let not_found = || ProgramNotFound {
+            name: "git".to_owned(),
+            searched_dirs: vec![],
+        };
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        runner.fail_next(not_found().into());
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let err = runner
+            .run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)
+            .unwrap_err();
+        assert!(err.is::<ProgramNotFound>(), "{}", err);
+        assert_eq!(1, runner.issued_commands.read().unwrap().len());
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        runner.fail_next(not_found().into());
+        let policy = RetryPolicy::new(3, Duration::ZERO).retry_errors_if(|_| true);
+        runner.run_with_retries(["git", "fetch"], &cwd, CommandOpts::default(), policy)?;
+        assert_eq!(2, runner.issued_commands.read().unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn with_signal() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_signal(libc::SIGSEGV, "partial").and_signal(9, "");
+        let cwd = AbsolutePathBuf::current_dir();
+
+        let res = runner.run(["crashy"], &cwd)?;
+        assert_eq!(Some(libc::SIGSEGV), res.status.signal());
+        assert_eq!(None, res.status.code());
+        assert!(!res.status.core_dumped());
+        assert_eq!("partial", res.stdout()?);
+        assert_eq!(Some(9), runner.run(["crashy"], &cwd)?.status.signal());
+        Ok(())
+    }
+
+    #[test]
+    fn records_user() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, ""), (0, "")])?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let opts = CommandOpts {
+            uid: Some(1000),
+            gid: Some(100),
+            ..Default::default()
+        };
+        runner.run_with_opts(["make", "install"], &cwd, opts)?;
+
+        let invocation = runner.nth_invocation(0).unwrap();
+        assert_eq!(
+            (Some(1000), Some(100)),
+            (invocation.uid(), invocation.gid())
+        );
+        assert_eq!(
+            Invocation::new(CommandLine::from(["make", "install"]), cwd.clone())
+                .with_user(Some(1000), Some(100)),
+            invocation
+        );
+
+        let opts = CommandOpts {
+            nice: Some(10),
+            ..Default::default()
+        };
+        runner.run_with_opts(["make", "clean"], &cwd, opts)?;
+        assert_eq!(Some(10), runner.nth_invocation(1).unwrap().nice());
+        Ok(())
+    }
+
+    #[test]
+    fn records_env() -> anyhow::Result<()> {
+        let runner = TestCommandRunner::with_results([(0, "")])?;
+        let env = hashmap! { "GIT_AUTHOR_NAME".to_owned() => "Me".to_owned() };
+        runner.run_with_opts(
+            ["git", "commit"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                env: env.clone(),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(&env, runner.issued_commands.read().unwrap()[0].env());
+        Ok(())
+    }
+
+    #[test]
+    fn assert_order_allows_interleaved_commands() -> anyhow::Result<()> {
+        let runner = runner_with_history()?;
+        runner.assert_order(&[
+            CommandLine::from(["git", "fetch"]),
+            CommandLine::from(["git", "push"]),
+        ]);
+        runner.assert_order(&[]);
         Ok(())
     }
+
+    #[test]
+    #[should_panic(expected = "Expected commands to be issued in order")]
+    fn assert_order_fails_on_wrong_order() {
+        let runner = runner_with_history().unwrap();
+        runner.assert_order(&[
+            CommandLine::from(["git", "push"]),
+            CommandLine::from(["git", "fetch"]),
+        ]);
+    }
+
+    #[test]
+    fn assert_nothing_ran() {
+        TestCommandRunner::new().assert_nothing_ran();
+    }
+
+    #[test]
+    #[should_panic(expected = "git status")]
+    fn assert_nothing_ran_lists_commands() {
+        runner_with_history().unwrap().assert_nothing_ran();
+    }
 }
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// A reusable bundle of environment settings for a [`crate::DefaultCommandRunner`].
+///
+/// A profile like "hermetic build" can be defined once, and applied to runners with
+/// [`crate::DefaultCommandRunner::with_profile`], rather than repeating the same filters and
+/// variables wherever a runner is created.
+///
+/// The environment of a command is this process' environment, filtered per
+/// [`Self::ignoring`] or [`Self::allowing`], then with [`Self::with_var`] applied, and finally
+/// with [`crate::CommandOpts::env`] applied on top.
+///
+/// ```
+/// use commands::DefaultCommandRunner;
+/// use commands::EnvProfile;
+///
+/// let hermetic = EnvProfile::new()
+///     .allowing(&["PATH", "HOME"])
+///     .with_var("LC_ALL", "C");
+/// let runner = DefaultCommandRunner::default().with_profile(hermetic);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvProfile {
+    pub(crate) ignored: Option<HashSet<String>>,
+    pub(crate) allowed: Option<HashSet<String>>,
+    pub(crate) vars: HashMap<String, String>,
+}
+
+impl EnvProfile {
+    /// A profile that passes through all of this process' environment.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass through everything but `ignored`. This replaces any previous filter.
+    pub fn ignoring<S: ToString>(mut self, ignored: &[S]) -> Self {
+        self.ignored = Some(ignored.iter().map(S::to_string).collect());
+        self.allowed = None;
+        self
+    }
+
+    /// Pass through only `allowed`. This replaces any previous filter.
+    pub fn allowing<S: ToString>(mut self, allowed: &[S]) -> Self {
+        self.allowed = Some(allowed.iter().map(S::to_string).collect());
+        self.ignored = None;
+        self
+    }
+
+    /// Set `name` to `value` for every command, regardless of the filter.
+    pub fn with_var<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// The environment that this profile gives commands, from `vars`.
+    pub(crate) fn apply<I: Iterator<Item = (String, String)>>(
+        &self,
+        vars: I,
+    ) -> HashMap<String, String> {
+        let mut env: HashMap<_, _> = if let Some(ignored) = self.ignored.as_ref() {
+            vars.filter(|(name, _)| !ignored.contains(name.as_str()))
+                .collect()
+        } else if let Some(allowed) = self.allowed.as_ref() {
+            vars.filter(|(name, _)| allowed.contains(name.as_str()))
+                .collect()
+        } else {
+            vars.collect()
+        };
+        env.extend(self.vars.clone());
+        env
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use maplit::hashmap;
+
+    use crate::EnvProfile;
+
+    fn vars() -> impl Iterator<Item = (String, String)> {
+        [("PATH", "/bin"), ("HOME", "/home/me"), ("GIT_DIR", ".git")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+    }
+
+    #[test]
+    fn applies_filters_and_vars() {
+        assert_eq!(3, EnvProfile::new().apply(vars()).len());
+        assert_eq!(
+            hashmap! {
+                "PATH".to_owned() => "/bin".to_owned(),
+                "HOME".to_owned() => "/home/me".to_owned(),
+            },
+            EnvProfile::new().ignoring(&["GIT_DIR"]).apply(vars())
+        );
+        assert_eq!(
+            hashmap! {
+                "PATH".to_owned() => "/bin".to_owned(),
+                "LC_ALL".to_owned() => "C".to_owned(),
+            },
+            EnvProfile::new()
+                .ignoring(&["PATH"])
+                .allowing(&["PATH"])
+                .with_var("LC_ALL", "C")
+                .apply(vars())
+        );
+    }
+}
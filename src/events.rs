@@ -0,0 +1,62 @@
+use std::process::ExitStatus;
+use std::sync::mpsc::Receiver;
+
+use crate::CommandHandle;
+use crate::Stream;
+
+/// Something that happened while running a command, from
+/// [`crate::DefaultCommandRunner::run_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandEvent {
+    /// The command was started. This is always the first event.
+    Started { pid: u32 },
+    /// The command wrote this to stdout.
+    Stdout(Vec<u8>),
+    /// The command wrote this to stderr.
+    Stderr(Vec<u8>),
+    /// The command exited. This is always the last event.
+    Exited(ExitStatus),
+}
+
+/// The events of a running command, in the order they happened.
+pub(crate) struct CommandEvents {
+    pid: Option<u32>,
+    rx: Receiver<(Stream, Vec<u8>)>,
+    handle: Option<CommandHandle>,
+}
+
+impl CommandEvents {
+    pub(crate) fn new(handle: CommandHandle, rx: Receiver<(Stream, Vec<u8>)>) -> Self {
+        Self {
+            pid: Some(handle.pid()),
+            rx,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Iterator for CommandEvents {
+    type Item = CommandEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pid) = self.pid.take() {
+            return Some(CommandEvent::Started { pid });
+        }
+        match self.rx.recv() {
+            Ok((Stream::Stdout, chunk)) => Some(CommandEvent::Stdout(chunk)),
+            Ok((Stream::Stderr, chunk)) => Some(CommandEvent::Stderr(chunk)),
+            // Both streams are closed, so all that is left is for the command to exit.
+            Err(_) => {
+                let handle = self.handle.take()?;
+                let program = handle.program().to_owned();
+                match handle.wait() {
+                    Ok(res) => Some(CommandEvent::Exited(res.status)),
+                    Err(e) => {
+                        log::error!("Could not wait for `{}`: {:#}", program, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Typed helpers for the git queries that come up most often.
+
+use paths::AbsolutePath;
+
+use crate::CommandRunner;
+
+/// Typed wrappers around common git commands, available on every [`CommandRunner`].
+///
+/// Each helper runs git with [`CommandRunner::run_checked`] in `repo`, and parses its
+/// single line of output.
+pub trait GitCommandRunner: CommandRunner {
+    /// Get the full hash of the commit that `HEAD` points at.
+    fn git_rev_parse_head<P: AsRef<AbsolutePath>>(&self, repo: P) -> anyhow::Result<String> {
+        let res = self.run_checked(["git", "rev-parse", "HEAD"], repo)?;
+        let hash = single_line(&res.stdout()?)?;
+        if hash.len() < 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!(
+                "`git rev-parse HEAD` returned `{}`, which is not a commit hash",
+                hash
+            ));
+        }
+        Ok(hash)
+    }
+
+    /// Get the name of the branch that is checked out.
+    ///
+    /// This fails if `HEAD` is detached, rather than returning `HEAD`.
+    fn git_current_branch<P: AsRef<AbsolutePath>>(&self, repo: P) -> anyhow::Result<String> {
+        let res = self.run_checked(["git", "rev-parse", "--abbrev-ref", "HEAD"], repo)?;
+        let branch = single_line(&res.stdout()?)?;
+        if branch == "HEAD" {
+            return Err(anyhow::anyhow!(
+                "HEAD is detached, so there is no current branch"
+            ));
+        }
+        Ok(branch)
+    }
+}
+
+impl<R: CommandRunner> GitCommandRunner for R {}
+
+fn single_line(stdout: &str) -> anyhow::Result<String> {
+    let mut lines = stdout.lines();
+    match (lines.next(), lines.next()) {
+        (Some(line), None) if !line.trim().is_empty() => Ok(line.trim().to_owned()),
+        _ => Err(anyhow::anyhow!(
+            "Expected a single line of output from git, got `{}`",
+            stdout
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CommandLine;
+    use crate::GitCommandRunner;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn rev_parse_head() -> anyhow::Result<()> {
+        let cwd = AbsolutePathBuf::current_dir();
+        let runner = TestCommandRunner::with_results([
+            (0, format!("{}\n", HASH)),
+            (0, "not a hash\n".to_owned()),
+            (0, format!("{}\n{}\n", HASH, HASH)),
+        ])?;
+
+        assert_eq!(HASH, runner.git_rev_parse_head(&cwd)?);
+        assert!(runner.git_rev_parse_head(&cwd).is_err());
+        assert!(runner.git_rev_parse_head(&cwd).is_err());
+        runner.assert_order(&[CommandLine::from(["git", "rev-parse", "HEAD"])]);
+        Ok(())
+    }
+
+    #[test]
+    fn current_branch() -> anyhow::Result<()> {
+        let cwd = AbsolutePathBuf::current_dir();
+        let runner = TestCommandRunner::with_results([(0, "main\n"), (0, "HEAD\n"), (0, "")])?;
+
+        assert_eq!("main", runner.git_current_branch(&cwd)?);
+        assert!(runner.git_current_branch(&cwd).is_err());
+        assert!(runner.git_current_branch(&cwd).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,703 @@
+use std::io::Read;
+use std::io::Write;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::ChildStdout;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+
+use crate::capture::Captured;
+use crate::capture::StdinWriter;
+use crate::capture::StreamReader;
+use crate::capture::StreamSink;
+use crate::capture::Transcript;
+use crate::signals;
+use crate::signals::ForwardedGroup;
+use crate::signals::SignalForwarding;
+use crate::CommandTimeout;
+use crate::ExecutionResult;
+use crate::Stream;
+
+/// How long [`CommandHandle::wait_any`] sleeps between checking on its commands.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a command that timed out gets to exit after `SIGTERM`, before it is killed.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// A command that was started with [`crate::DefaultCommandRunner::spawn`] or
+/// [`crate::DefaultCommandRunner::spawn_streaming`].
+///
+/// Captured output is read on background threads while the command runs, so it never
+/// blocks on a full pipe, and the complete output is available once it has exited.
+///
+/// Dropping the handle does not kill or wait for the command, unless it was spawned with
+/// [`crate::CommandOpts::kill_on_drop`].
+pub struct CommandHandle {
+    program: String,
+    started: Instant,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdin_writer: Option<StdinWriter>,
+    stdout: Option<StreamReader>,
+    stderr: Option<StreamReader>,
+    merged: bool,
+    /// Whether to signal the command's whole process group when stopping it, rather than
+    /// just the command, per [`crate::CommandOpts::new_process_group`].
+    signal_group: bool,
+    /// Whether to kill the command if this is dropped before it was waited on.
+    kill_on_drop: bool,
+    /// Whether to log completion at trace level, per [`crate::CommandOpts::quiet`].
+    quiet: bool,
+    /// What both streams' readers append to, per [`crate::CommandOpts::capture_interleaved`].
+    transcript: Option<Transcript>,
+    forwarded_signals: Option<ForwardedGroup>,
+}
+
+impl CommandHandle {
+    pub(crate) fn new(
+        program: String,
+        mut child: Child,
+        stdin: Option<Vec<u8>>,
+        stdout: StreamSink,
+        stderr: StreamSink,
+    ) -> Self {
+        let started = Instant::now();
+        let (stdin, stdin_writer) = match (child.stdin.take(), stdin) {
+            (Some(pipe), Some(bytes)) => (None, Some(StdinWriter::spawn(pipe, bytes))),
+            (pipe, _) => (pipe, None),
+        };
+        let stdout = child
+            .stdout
+            .take()
+            .map(|s| StreamReader::spawn(Stream::Stdout, s, stdout));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|s| StreamReader::spawn(Stream::Stderr, s, stderr));
+        Self {
+            program,
+            started,
+            child,
+            stdin,
+            stdin_writer,
+            stdout,
+            stderr,
+            merged: false,
+            signal_group: false,
+            kill_on_drop: false,
+            quiet: false,
+            transcript: None,
+            forwarded_signals: None,
+        }
+    }
+
+    /// Mark the command as having its stderr merged into stdout.
+    pub(crate) fn merged(mut self, merged: bool) -> Self {
+        self.merged = merged;
+        self
+    }
+
+    /// Mark the command as leading its own process group, which is signalled as a whole when
+    /// the command is stopped.
+    pub(crate) fn signal_group(mut self, signal_group: bool) -> Self {
+        self.signal_group = signal_group;
+        self
+    }
+
+    /// Kill the command if the handle is dropped before the command was waited on.
+    pub(crate) fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Log the command's completion at trace level, rather than debug.
+    pub(crate) fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Put what was appended to `transcript` in the result, as the interleaved output.
+    pub(crate) fn transcript(mut self, transcript: Option<Transcript>) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    /// Start forwarding signals to the command, per `mode`.
+    pub(crate) fn forward_signals(mut self, mode: SignalForwarding) -> anyhow::Result<Self> {
+        self.forwarded_signals = signals::forward_to(self.child.id() as i32, mode)?;
+        Ok(self)
+    }
+
+    /// Stop forwarding signals that this process receives to the command, if they were per
+    /// [`crate::CommandOpts::signal_forwarding`].
+    ///
+    /// The command stays in its own process group, so it no longer gets any of them.
+    pub fn stop_forwarding_signals(&mut self) {
+        self.forwarded_signals = None;
+    }
+
+    /// The program that this command is running.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// The OS-assigned process id of the command.
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// The command's stdin, if it was spawned with [`crate::CommandOpts::pipe_stdin`].
+    pub fn stdin(&mut self) -> Option<&mut ChildStdin> {
+        self.stdin.as_mut()
+    }
+
+    /// Write `line` and a newline to the command's stdin, and flush it.
+    ///
+    /// This is useful to drive interactive commands like REPLs, which wait for a full line
+    /// before responding. This fails if the command was not spawned with
+    /// [`crate::CommandOpts::pipe_stdin`], or if stdin was closed.
+    pub fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let stdin = self.stdin.as_mut().ok_or_else(|| {
+            anyhow::anyhow!("stdin of `{}` is not open for writing", self.program)
+        })?;
+        stdin.write_all(line.as_bytes())?;
+        stdin.write_all(b"\n")?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Close the command's stdin, so that it sees the end of its input.
+    pub fn close_stdin(&mut self) {
+        self.stdin = None;
+    }
+
+    /// Forcefully kill the command with `SIGKILL`.
+    ///
+    /// If it was started with [`crate::CommandOpts::new_process_group`], its whole process
+    /// group is killed, including any of its children. The command still has to be waited on
+    /// to collect its exit status and output.
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        if self.signal_group {
+            self.signal(libc::SIGKILL)
+        } else {
+            Ok(self.child.kill()?)
+        }
+    }
+
+    /// Send `signal` to the command, or to its process group per [`Self::signal_group`].
+    fn signal(&mut self, signal: libc::c_int) -> anyhow::Result<()> {
+        // Once the command has been reaped, its pid (and so its group id) may be reused.
+        if self.child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        let pid = self.child.id() as i32;
+        let target = if self.signal_group { -pid } else { pid };
+        if unsafe { libc::kill(target, signal) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Whether anything is left in the command's process group, even once the command itself
+    /// has exited.
+    ///
+    /// The group's id stays reserved while anything is in it, so it is not reused for another
+    /// group before this sees that it is empty.
+    fn group_alive(&self) -> bool {
+        unsafe { libc::kill(-(self.child.id() as i32), 0) == 0 }
+    }
+
+    /// Kill what is left in the command's process group once the command itself has exited.
+    fn kill_group(&self) {
+        // This only fails if the group emptied since it was checked, which is what was wanted.
+        unsafe { libc::kill(-(self.child.id() as i32), libc::SIGKILL) };
+    }
+
+    /// Check whether the command has exited, without blocking.
+    ///
+    /// The command still has to be waited on to collect its output.
+    pub fn try_wait(&mut self) -> anyhow::Result<Option<ExitStatus>> {
+        Ok(self.child.try_wait()?)
+    }
+
+    /// Wait for the command to exit, and collect its output.
+    ///
+    /// Stdin is closed before waiting, so that the command is not blocked reading it.
+    pub fn wait(mut self) -> anyhow::Result<ExecutionResult> {
+        self.close_stdin();
+        let status = self.child.wait()?;
+        self.finish(status)
+    }
+
+    /// Like [`Self::wait`], but if `timeout` is set and the command is still running that long
+    /// after it was started, stop it and fail with a [`CommandTimeout`].
+    ///
+    /// The command is sent `SIGTERM` first, and `SIGKILL` if it has not exited shortly after.
+    /// Unless it has its own process group per [`crate::CommandOpts::new_process_group`],
+    /// only the command itself is signalled, so any of its children that keep its output open
+    /// delay this until they exit.
+    pub(crate) fn wait_with_timeout(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<ExecutionResult> {
+        let deadline = match timeout {
+            Some(timeout) => self.started + timeout,
+            None => return self.wait(),
+        };
+        self.close_stdin();
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                return self.finish(status);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        if let Some(status) = self.child.try_wait()? {
+            return self.finish(status);
+        }
+
+        let elapsed = self.started.elapsed();
+        log::info!("Terminating `{}` after {:?}", self.program, elapsed);
+        self.signal(libc::SIGTERM)?;
+        let grace_deadline = Instant::now() + TIMEOUT_GRACE;
+        let status = loop {
+            // Others in the group may ignore SIGTERM and keep output open after the command
+            // itself exits, so the whole group has to be gone.
+            let status = self.child.try_wait()?;
+            if let Some(status) = status.filter(|_| !self.signal_group || !self.group_alive()) {
+                break status;
+            }
+            if Instant::now() >= grace_deadline {
+                log::info!(
+                    "Killing `{}`, which did not exit after SIGTERM",
+                    self.program
+                );
+                match status {
+                    Some(status) => {
+                        self.kill_group();
+                        break status;
+                    }
+                    None => {
+                        self.kill()?;
+                        break self.child.wait()?;
+                    }
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        let program = self.program.clone();
+        let result = self.finish(status)?;
+        Err(CommandTimeout {
+            program,
+            elapsed,
+            result,
+        }
+        .into())
+    }
+
+    /// Wait until the first of `handles` exits.
+    ///
+    /// Returns the index of the command that finished first, its result, and the handles for
+    /// the commands that are still running, in their original order. The caller is
+    /// responsible for killing or waiting on those commands.
+    ///
+    /// Output of every command is read on background threads as it is produced, so the result
+    /// for the winner contains all of its output, not just what was produced after it exited.
+    /// This fails if `handles` is empty.
+    pub fn wait_any(
+        mut handles: Vec<CommandHandle>,
+    ) -> anyhow::Result<(usize, ExecutionResult, Vec<CommandHandle>)> {
+        if handles.is_empty() {
+            return Err(anyhow::anyhow!("No commands were provided to wait on"));
+        }
+        loop {
+            let mut finished = None;
+            for (i, handle) in handles.iter_mut().enumerate() {
+                if let Some(status) = handle.child.try_wait()? {
+                    finished = Some((i, status));
+                    break;
+                }
+            }
+            if let Some((i, status)) = finished {
+                let res = handles.remove(i).finish(status)?;
+                return Ok((i, res, handles));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn finish(mut self, status: ExitStatus) -> anyhow::Result<ExecutionResult> {
+        // The command has been reaped, so its pid (and so its group id) may be reused.
+        self.forwarded_signals = None;
+        // Join every thread before looking at any errors, so that none are left running.
+        let stdin = self.stdin_writer.take().map_or(Ok(()), StdinWriter::join);
+        let stdout = join_output(self.stdout.take());
+        let stderr = join_output(self.stderr.take());
+        let context = || format!("Failed to collect the output of `{}`", self.program);
+        stdin.with_context(context)?;
+        let stdout = stdout.with_context(context)?;
+        let stderr = stderr.with_context(context)?;
+        let level = if self.quiet {
+            log::Level::Trace
+        } else {
+            log::Level::Debug
+        };
+        log::log!(
+            level,
+            "Completed `{}` with exit status `{}`",
+            self.program,
+            status
+        );
+        Ok(ExecutionResult {
+            stdout_bytes_read: stdout.bytes_read,
+            stderr_bytes_read: stderr.bytes_read,
+            stdout_truncated: stdout.truncated,
+            stderr_truncated: stderr.truncated,
+            duration: self.started.elapsed(),
+            time_to_first_output: stdout
+                .first_read
+                .map(|t| t.saturating_duration_since(self.started)),
+            stdout_file: stdout.temp_file.map(Arc::new),
+            stderr_file: stderr.temp_file.map(Arc::new),
+            merged: self.merged,
+            program: self.program.clone(),
+            // Both readers have been joined, so nothing else has the transcript anymore.
+            interleaved: self
+                .transcript
+                .take()
+                .map(|t| std::mem::take(&mut *t.lock().unwrap()))
+                .unwrap_or_default(),
+            ..ExecutionResult::from(Output {
+                status,
+                stdout: stdout.bytes,
+                stderr: stderr.bytes,
+            })
+        })
+    }
+}
+
+impl Drop for CommandHandle {
+    fn drop(&mut self) {
+        // Only commands that have not been reaped are killed, as their pid may be reused.
+        if !self.kill_on_drop || !matches!(self.child.try_wait(), Ok(None)) {
+            return;
+        }
+        log::info!("Killing `{}`, as its handle was dropped", self.program);
+        if let Err(e) = self.kill().and_then(|()| Ok(self.child.wait()?)) {
+            log::warn!("Could not kill `{}`: {}", self.program, e);
+        }
+    }
+}
+
+/// The stdout of a command that was started with
+/// [`crate::DefaultCommandRunner::spawn_stdout_reader`], to read from directly while the command
+/// runs in the background.
+///
+/// Once stdout has been read to the end, dropping this waits for the command to exit, so that
+/// it is reaped. If this is dropped before that, stdout is closed, and the command is left to
+/// exit on its own (typically from `SIGPIPE`), unless it was spawned with
+/// [`crate::CommandOpts::kill_on_drop`].
+pub struct ChildStdoutReader {
+    stdout: Option<ChildStdout>,
+    handle: Option<CommandHandle>,
+    eof: bool,
+}
+
+impl ChildStdoutReader {
+    pub(crate) fn new(stdout: ChildStdout, handle: CommandHandle) -> Self {
+        Self {
+            stdout: Some(stdout),
+            handle: Some(handle),
+            eof: false,
+        }
+    }
+
+    /// The handle of the command, e.g. to get its pid or kill it.
+    pub fn handle(&mut self) -> &mut CommandHandle {
+        self.handle
+            .as_mut()
+            .expect("only taken when this is consumed")
+    }
+
+    /// Close stdout, and wait for the command to exit.
+    ///
+    /// The result has no stdout, as that was read from this instead.
+    pub fn wait(mut self) -> anyhow::Result<ExecutionResult> {
+        self.stdout = None;
+        self.handle
+            .take()
+            .expect("only taken when this is consumed")
+            .wait()
+    }
+}
+
+impl Read for ChildStdoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = match self.stdout.as_mut() {
+            Some(stdout) => stdout.read(buf)?,
+            None => 0,
+        };
+        if n == 0 && !buf.is_empty() {
+            self.eof = true;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildStdoutReader {
+    fn drop(&mut self) {
+        self.stdout = None;
+        if let Some(handle) = self.handle.take().filter(|_| self.eof) {
+            let program = handle.program().to_owned();
+            if let Err(e) = handle.wait() {
+                log::warn!("Could not wait for `{}`: {}", program, e);
+            }
+        }
+    }
+}
+
+fn join_output(reader: Option<StreamReader>) -> anyhow::Result<Captured> {
+    match reader {
+        Some(reader) => reader.join(),
+        None => Ok(Captured::default()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::io::Write;
+    use std::process::Command;
+    use std::process::Stdio;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    use paths::AbsolutePathBuf;
+
+    use crate::capture::StreamSink;
+    use crate::CommandHandle;
+    use crate::CommandOpts;
+    use crate::DefaultCommandRunner;
+    use crate::Stream;
+    use crate::StreamingOpts;
+
+    #[test]
+    fn spawn_and_wait() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let handle = runner.spawn(
+            ["cat"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                stdin: Some(b"TESTING".to_vec()),
+                ..Default::default()
+            },
+        )?;
+        assert!(handle.pid() > 0);
+
+        let res = handle.wait()?;
+        assert!(res.status.success());
+        assert_eq!("TESTING", res.stdout()?);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_any_returns_first_finished() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let slow = runner.spawn(["sleep", "10"], &cwd, CommandOpts::default())?;
+        let fast = runner.spawn(["echo", "fast"], &cwd, CommandOpts::default())?;
+
+        let (i, res, remaining) = CommandHandle::wait_any(vec![slow, fast])?;
+        assert_eq!(1, i);
+        assert_eq!("fast\n", res.stdout()?);
+        assert_eq!(1, remaining.len());
+
+        for mut handle in remaining {
+            handle.kill()?;
+            assert!(!handle.wait()?.status.success());
+        }
+
+        assert!(CommandHandle::wait_any(vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_stdout() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let mut reader = runner.spawn_stdout_reader(
+            ["/bin/sh", "-c", "echo out; echo err >&2"],
+            &cwd,
+            CommandOpts::default(),
+        )?;
+        let mut stdout = String::new();
+        reader.read_to_string(&mut stdout)?;
+        assert_eq!("out\n", stdout);
+        let res = reader.wait()?;
+        assert!(res.status.success());
+        assert_eq!("", res.stdout()?);
+        assert_eq!("err\n", res.stderr()?);
+
+        let mut reader =
+            runner.spawn_stdout_reader(["echo", "hi"], &cwd, CommandOpts::default())?;
+        let pid = reader.handle().pid() as i32;
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        drop(reader);
+        // The command was reaped, so there is no such process anymore.
+        assert_ne!(0, unsafe { libc::kill(pid, 0) });
+        Ok(())
+    }
+
+    #[test]
+    fn kill_on_drop() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+        let handle = runner.spawn(
+            ["sleep", "10"],
+            &cwd,
+            CommandOpts {
+                kill_on_drop: true,
+                ..Default::default()
+            },
+        )?;
+        let pid = handle.pid() as i32;
+        drop(handle);
+        // The command was killed and reaped, so there is no such process anymore.
+        assert_ne!(0, unsafe { libc::kill(pid, 0) });
+
+        let handle = runner.spawn(
+            ["true"],
+            &cwd,
+            CommandOpts {
+                kill_on_drop: true,
+                ..Default::default()
+            },
+        )?;
+        assert!(handle.wait()?.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn write_line_flushes() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let mut handle = runner.spawn(
+            ["head", "-n", "1"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                pipe_stdin: true,
+                ..Default::default()
+            },
+        )?;
+
+        handle.write_line("first")?;
+        // `head` exits after the first line, even though stdin is still open.
+        let (_, res, _) = CommandHandle::wait_any(vec![handle])?;
+        assert_eq!("first\n", res.stdout()?);
+
+        let mut handle = runner.spawn(
+            ["true"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+        )?;
+        assert!(handle.write_line("ignored").is_err());
+        handle.wait()?;
+        Ok(())
+    }
+
+    struct PanickingWriter;
+
+    impl Write for PanickingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            panic!("out of memory");
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reader_panic_is_an_error() -> anyhow::Result<()> {
+        let child = Command::new("echo")
+            .arg("hello")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = StreamSink {
+            echo: Some(Box::new(PanickingWriter)),
+            ..Default::default()
+        };
+        let handle = CommandHandle::new(
+            "echo".to_owned(),
+            child,
+            None,
+            stdout,
+            StreamSink::retained(),
+        );
+
+        let err = format!("{:#}", handle.wait().unwrap_err());
+        assert!(err.contains("`echo`"), "{}", err);
+        assert!(err.contains("stdout panicked: out of memory"), "{}", err);
+        Ok(())
+    }
+
+    fn collect(rx: Receiver<(Stream, Vec<u8>)>, stream: Stream) -> (usize, Vec<u8>) {
+        let chunks: Vec<_> = rx.iter().filter(|(s, _)| *s == stream).collect();
+        let bytes = chunks.iter().flat_map(|(_, c)| c.clone()).collect();
+        (chunks.len(), bytes)
+    }
+
+    #[test]
+    fn spawn_streaming_flushes_final_batch() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let (handle, rx) = runner.spawn_streaming(
+            [
+                "/bin/sh",
+                "-c",
+                "for i in 1 2 3; do echo $i; sleep 0.01; done",
+            ],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+            StreamingOpts {
+                flush_interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!((1, b"1\n2\n3\n".to_vec()), collect(rx, Stream::Stdout));
+        let res = handle.wait()?;
+        assert!(res.stdout.is_empty());
+        assert_eq!(6, res.stdout_bytes_read());
+        Ok(())
+    }
+
+    #[test]
+    fn spawn_streaming_flushes_by_size() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let (handle, rx) = runner.spawn_streaming(
+            ["/bin/sh", "-c", "echo out; sleep 0.05; echo err >&2"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+            StreamingOpts {
+                flush_interval: Duration::from_secs(60),
+                flush_bytes: 1,
+                channel_capacity: 1,
+            },
+        )?;
+
+        let chunks: Vec<_> = rx.iter().collect();
+        assert_eq!(
+            vec![
+                (Stream::Stdout, b"out\n".to_vec()),
+                (Stream::Stderr, b"err\n".to_vec())
+            ],
+            chunks
+        );
+        assert!(handle.wait()?.status.success());
+        Ok(())
+    }
+}
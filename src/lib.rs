@@ -1,18 +1,82 @@
+mod adapters;
+#[cfg(feature = "tokio")]
+mod async_runner;
+mod caching;
+mod capture;
 mod command_line;
+mod command_template;
+mod diff;
+mod dry_run;
+mod env_profile;
+mod events;
+#[cfg(feature = "git")]
+mod git;
+mod handle;
+mod record_replay;
+mod restart;
+mod retry;
 mod runner;
+mod signals;
+mod sudo;
+mod sudo_fallback;
+mod supervise;
+mod trace;
 
 // re-exported because it's needed for things in [`CommandRunner`]
 pub use paths;
 
+pub use crate::adapters::AsCommandRunner;
+pub use crate::adapters::AsEnv;
+#[cfg(feature = "tokio")]
+pub use crate::async_runner::AsyncCommandRunner;
+#[cfg(feature = "tokio")]
+pub use crate::async_runner::DefaultAsyncCommandRunner;
+pub use crate::caching::CachingCommandRunner;
+pub use crate::capture::BytesPerSecond;
+pub use crate::capture::Decompression;
+pub use crate::capture::Stream;
+pub use crate::capture::StreamingOpts;
 pub use crate::command_line::CommandLine;
+pub use crate::command_line::CommandLineBuilder;
+pub use crate::command_line::CommandLineError;
+pub use crate::command_line::ProgramNotFound;
+#[doc(hidden)]
+pub use crate::command_template::format_command_line as __format_command_line;
+pub use crate::command_template::CommandTemplate;
+pub use crate::dry_run::DryRunCommandRunner;
+pub use crate::env_profile::EnvProfile;
+pub use crate::events::CommandEvent;
+#[cfg(feature = "git")]
+pub use crate::git::GitCommandRunner;
+pub use crate::handle::ChildStdoutReader;
+pub use crate::handle::CommandHandle;
+pub use crate::record_replay::RecordReplayCommandRunner;
+pub use crate::record_replay::RecordReplayMode;
+pub use crate::restart::RestartPolicy;
+pub use crate::restart::RestartsExhausted;
+pub use crate::restart::SupervisedProcess;
+pub use crate::retry::RetryPolicy;
+pub use crate::runner::BatchesFailed;
+pub use crate::runner::CommandFailed;
 pub use crate::runner::CommandOpts;
 pub use crate::runner::CommandRunner;
+pub use crate::runner::CommandTimeout;
 pub use crate::runner::DefaultCommandRunner;
+pub use crate::runner::DefaultCommandRunnerBuilder;
+pub use crate::runner::Env;
 pub use crate::runner::ExecutionResult;
 pub use crate::runner::MissingHomeError;
+pub use crate::runner::SpawnMethod;
 pub use crate::runner::StdioCapture;
+pub use crate::signals::SignalForwarding;
+pub use crate::sudo::SudoCommandRunner;
+pub use crate::sudo_fallback::SudoFallbackCommandRunner;
+pub use crate::supervise::SupervisedOutcome;
+pub use crate::supervise::SupervisionOpts;
+pub use crate::trace::TracingExecCommandRunner;
 
 pub mod test {
+    pub use crate::runner::test::CommandMatcher;
     pub use crate::runner::test::Invocation;
     pub use crate::runner::test::TestCommandRunner;
 }
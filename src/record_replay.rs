@@ -0,0 +1,424 @@
+use std::borrow::Cow;
+use std::io::Seek;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::sync::Arc;
+
+use itertools::Itertools;
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+use tempfile::NamedTempFile;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+use crate::StdioCapture;
+
+/// Whether a [`RecordReplayCommandRunner`] runs commands, or replays their recorded results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordReplayMode {
+    /// Always run commands, and (re-)record their results.
+    Record,
+    /// Never run commands. Running a command that has no recording is an error.
+    Replay,
+    /// Replay commands that have a recording, and run and record the others.
+    RecordMissing,
+}
+
+/// A [`CommandRunner`] that records the results of commands to a fixtures directory, so
+/// that they can be replayed later without running anything.
+///
+/// This makes slow integration tests fast and deterministic. Recordings are keyed on the
+/// command line and working directory, so the working directory should be the same between
+/// runs for a recording to be found (e.g. not a random temp dir). Each recording is a
+/// directory under the fixtures directory with the exit status, stdout, and stderr in
+/// separate files, plus a description of the command, which is checked on replay.
+///
+/// Output is recorded verbatim, and replayed exactly as it was recorded, including anything
+/// that differs between runs, like timestamps. Re-recording such a command changes its
+/// fixture, so code under test should not depend on those parts of the output. The options
+/// that commands are run with are not part of the key, though output that was captured with
+/// [`StdioCapture::TempFile`] is recorded, and replayed into a new temp file if the replayed
+/// command asks for one. Pipelines are recorded as a whole, keyed on all of their commands.
+/// Arguments added with [`CommandLine::push_secret`] are redacted in the description, and
+/// are left out of the key, so that fixture names say nothing about them either. A command is
+/// replayed whatever its secrets are, e.g. the recording of a login with one password is
+/// replayed for a login with another.
+#[derive(Debug)]
+pub struct RecordReplayCommandRunner<R: CommandRunner> {
+    inner: R,
+    fixtures: PathBuf,
+    mode: RecordReplayMode,
+}
+
+impl<R: CommandRunner> RecordReplayCommandRunner<R> {
+    pub fn new<P: AsRef<AbsolutePath>>(inner: R, fixtures: P, mode: RecordReplayMode) -> Self {
+        let fixtures: &Path = fixtures.as_ref().as_ref();
+        Self {
+            inner,
+            fixtures: fixtures.to_path_buf(),
+            mode,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Replay the result of `commands`, which are piped together, or get it with `run` and
+    /// record it, per [`Self::mode`].
+    fn run_recorded<'a, I, F>(
+        &self,
+        commands: I,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+        run: F,
+    ) -> anyhow::Result<ExecutionResult>
+    where
+        I: IntoIterator<Item = &'a CommandLine>,
+        F: FnOnce(CommandOpts) -> anyhow::Result<ExecutionResult>,
+    {
+        let commands: Vec<_> = commands.into_iter().collect();
+        // Fixtures only ever show command lines redacted, as they are typically checked in.
+        let description = format!(
+            "{}\n{}\n",
+            commands
+                .iter()
+                .map(|c| c.to_redacted_shell_string())
+                .join(" | "),
+            cwd
+        );
+        let dir = self
+            .fixtures
+            .join(format!("{:016x}", stable_hash(&description)));
+        let replay = match self.mode {
+            RecordReplayMode::Record => false,
+            RecordReplayMode::Replay => true,
+            RecordReplayMode::RecordMissing => dir.exists(),
+        };
+        if replay {
+            log::debug!(
+                "Replaying `{}` from `{}`",
+                commands.iter().join(" | "),
+                dir.display()
+            );
+            self.replay(&dir, &description, &opts)
+        } else {
+            let res = run(opts)?;
+            self.record(&dir, &description, &res)?;
+            Ok(res)
+        }
+    }
+
+    fn replay(
+        &self,
+        dir: &Path,
+        description: &str,
+        opts: &CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        if !dir.exists() {
+            return Err(anyhow::anyhow!(
+                "No recording found in `{}` for command:\n{}",
+                dir.display(),
+                description
+            ));
+        }
+        let recorded_description = std::fs::read_to_string(dir.join("command"))?;
+        if recorded_description != description {
+            return Err(anyhow::anyhow!(
+                "Recording in `{}` is for a different command:\n{}",
+                dir.display(),
+                recorded_description
+            ));
+        }
+        let status = std::fs::read_to_string(dir.join("status"))?
+            .trim()
+            .parse()?;
+        let mut res = ExecutionResult::from(Output {
+            status: ExitStatus::from_raw(status),
+            stdout: std::fs::read(dir.join("stdout"))?,
+            stderr: std::fs::read(dir.join("stderr"))?,
+        });
+        if opts.stdout_capture == Some(StdioCapture::TempFile) {
+            res.stdout_file = Some(replayed_temp_file(&mut res.output.stdout)?);
+        }
+        if opts.stderr_capture == Some(StdioCapture::TempFile) {
+            res.stderr_file = Some(replayed_temp_file(&mut res.output.stderr)?);
+        }
+        Ok(res)
+    }
+
+    fn record(&self, dir: &Path, description: &str, res: &ExecutionResult) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("command"), description)?;
+        std::fs::write(dir.join("status"), res.status.into_raw().to_string())?;
+        std::fs::write(
+            dir.join("stdout"),
+            recorded_output(res.stdout_file(), &res.stdout)?,
+        )?;
+        std::fs::write(
+            dir.join("stderr"),
+            recorded_output(res.stderr_file(), &res.stderr)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// The output of one stream, which is in `file` rather than `bytes` if it was captured with
+/// [`StdioCapture::TempFile`].
+fn recorded_output<'a>(
+    file: Option<&NamedTempFile>,
+    bytes: &'a [u8],
+) -> anyhow::Result<Cow<'a, [u8]>> {
+    match file {
+        Some(file) => Ok(Cow::Owned(std::fs::read(file.path())?)),
+        None => Ok(Cow::Borrowed(bytes)),
+    }
+}
+
+/// Move replayed output into a temp file, as [`StdioCapture::TempFile`] would have.
+fn replayed_temp_file(bytes: &mut Vec<u8>) -> anyhow::Result<Arc<NamedTempFile>> {
+    let mut file = NamedTempFile::new()?;
+    file.write_all(&std::mem::take(bytes))?;
+    file.rewind()?;
+    Ok(Arc::new(file))
+}
+
+/// A 64 bit FNV-1a hash, used rather than [`std::hash::Hash`] so that fixture names are
+/// stable between compiler versions.
+fn stable_hash(description: &str) -> u64 {
+    description.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl<R: CommandRunner> CommandRunner for RecordReplayCommandRunner<R> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        self.run_recorded([&command_line], cwd, opts, |opts| {
+            self.inner.run_inner(command_line.clone(), cwd, opts)
+        })
+    }
+
+    /// Pipelines are recorded as a whole, and only the final command's result is recorded.
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let cwd = cwd.as_ref();
+        self.run_recorded(&commands, cwd, opts, |opts| {
+            self.inner.run_pipeline(commands.clone(), cwd, opts)
+        })
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        match self.mode {
+            RecordReplayMode::Replay => Err(anyhow::anyhow!(
+                "Cannot exec `{}` while replaying commands",
+                command_line
+            )),
+            _ => self.inner.exec_with_opts(command_line, cwd, opts),
+        }
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.inner.default_cwd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use paths::AbsolutePath;
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::DefaultCommandRunner;
+    use crate::RecordReplayCommandRunner;
+    use crate::RecordReplayMode;
+    use crate::StdioCapture;
+
+    #[test]
+    fn records_and_replays() -> anyhow::Result<()> {
+        let fixtures = tempfile::TempDir::new()?;
+        let fixtures = AbsolutePath::try_new(fixtures.path())?;
+        let cwd = AbsolutePathBuf::current_dir();
+
+        let inner = TestCommandRunner::with_results([(0, "recorded"), (3 << 8, "other")])?;
+        let runner = RecordReplayCommandRunner::new(inner, fixtures, RecordReplayMode::Record);
+        assert_eq!("recorded", runner.run(["git", "log"], &cwd)?.stdout()?);
+        assert_eq!(Some(3), runner.run(["git", "status"], &cwd)?.status.code());
+
+        // Nothing is queued up in the inner runner, so this would fail if anything was run.
+        let runner = RecordReplayCommandRunner::new(
+            TestCommandRunner::new(),
+            fixtures,
+            RecordReplayMode::Replay,
+        );
+        assert_eq!("recorded", runner.run(["git", "log"], &cwd)?.stdout()?);
+        let res = runner.run(["git", "status"], &cwd)?;
+        assert_eq!(Some(3), res.status.code());
+        assert_eq!("other", res.stdout()?);
+        assert!(runner.run(["git", "diff"], &cwd).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn redacts_secrets() -> anyhow::Result<()> {
+        let fixtures = tempfile::TempDir::new()?;
+        let fixtures = AbsolutePath::try_new(fixtures.path())?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let command_line = |secret: &str| {
+            let mut cli = CommandLine::from(["login", "--password"]);
+            cli.push_secret(secret);
+            cli
+        };
+
+        let inner = TestCommandRunner::with_results([(0, "recorded")])?;
+        let runner = RecordReplayCommandRunner::new(inner, fixtures, RecordReplayMode::Record);
+        runner.run(command_line("hunter2"), &cwd)?;
+        for entry in std::fs::read_dir(fixtures)? {
+            let description = std::fs::read_to_string(entry?.path().join("command"))?;
+            assert!(description.starts_with("login --password '****'\n"));
+        }
+
+        let runner = RecordReplayCommandRunner::new(
+            TestCommandRunner::new(),
+            fixtures,
+            RecordReplayMode::Replay,
+        );
+        assert_eq!(
+            "recorded",
+            runner.run(command_line("hunter2"), &cwd)?.stdout()?
+        );
+        // Secrets are not part of the key, so this still has a recording.
+        assert_eq!(
+            "recorded",
+            runner.run(command_line("other"), &cwd)?.stdout()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn records_temp_files() -> anyhow::Result<()> {
+        let fixtures = tempfile::TempDir::new()?;
+        let fixtures = AbsolutePath::try_new(fixtures.path())?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let opts = || CommandOpts {
+            stdout_capture: Some(StdioCapture::TempFile),
+            ..Default::default()
+        };
+
+        let runner = RecordReplayCommandRunner::new(
+            DefaultCommandRunner::default(),
+            fixtures,
+            RecordReplayMode::Record,
+        );
+        let res = runner.run_with_opts(["printf", "hello"], &cwd, opts())?;
+        assert_eq!("", res.stdout()?);
+
+        let runner = RecordReplayCommandRunner::new(
+            TestCommandRunner::new(),
+            fixtures,
+            RecordReplayMode::Replay,
+        );
+        let res = runner.run_with_opts(["printf", "hello"], &cwd, opts())?;
+        assert_eq!("", res.stdout()?);
+        let file = res.stdout_file().expect("a temp file");
+        assert_eq!("hello", std::fs::read_to_string(file.path())?);
+        let res = runner.run(["printf", "hello"], &cwd)?;
+        assert_eq!("hello", res.stdout()?);
+        assert!(res.stdout_file().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn records_pipelines() -> anyhow::Result<()> {
+        let fixtures = tempfile::TempDir::new()?;
+        let fixtures = AbsolutePath::try_new(fixtures.path())?;
+        let cwd = AbsolutePathBuf::current_dir();
+        let commands = || {
+            vec![
+                CommandLine::from(["yes"]),
+                CommandLine::from(["head", "-1"]),
+            ]
+        };
+
+        // `yes` never exits on its own, so this only finishes if the inner runner runs the
+        // commands concurrently.
+        let runner = RecordReplayCommandRunner::new(
+            DefaultCommandRunner::default(),
+            fixtures,
+            RecordReplayMode::Record,
+        );
+        let res = runner.run_pipeline(commands(), &cwd, CommandOpts::default())?;
+        assert_eq!("y\n", res.stdout()?);
+
+        let runner = RecordReplayCommandRunner::new(
+            TestCommandRunner::new(),
+            fixtures,
+            RecordReplayMode::Replay,
+        );
+        let res = runner.run_pipeline(commands(), &cwd, CommandOpts::default())?;
+        assert_eq!("y\n", res.stdout()?);
+        assert!(runner.run(["yes"], &cwd).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn records_missing() -> anyhow::Result<()> {
+        let fixtures = tempfile::TempDir::new()?;
+        let fixtures = AbsolutePath::try_new(fixtures.path())?;
+        let cwd = AbsolutePathBuf::current_dir();
+
+        let inner = TestCommandRunner::with_results([(0, "first"), (0, "second")])?;
+        let runner =
+            RecordReplayCommandRunner::new(inner, fixtures, RecordReplayMode::RecordMissing);
+        assert_eq!("first", runner.run(["date"], &cwd)?.stdout()?);
+        assert_eq!("first", runner.run(["date"], &cwd)?.stdout()?);
+        assert_eq!("second", runner.run(["date", "-u"], &cwd)?.stdout()?);
+        assert_eq!(2, runner.inner().issued_commands.read().unwrap().len());
+        Ok(())
+    }
+}
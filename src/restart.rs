@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::DefaultCommandRunner;
+use crate::ExecutionResult;
+
+/// When and how quickly a [`SupervisedProcess`] is restarted after it crashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// How many times to restart the command before giving up.
+    pub max_restarts: usize,
+    /// How long to wait before the first restart. This doubles for each restart after it.
+    pub initial_backoff: Duration,
+    /// The longest to wait before any restart, or `Duration::MAX` for no limit.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The error returned when a [`SupervisedProcess`] crashed more often than its
+/// [`RestartPolicy`] allows.
+#[derive(thiserror::Error, Debug)]
+#[error("`{program}` crashed with status `{}` after {restarts} restarts", .result.status)]
+pub struct RestartsExhausted {
+    pub program: String,
+    pub restarts: usize,
+    /// The result of the last run.
+    pub result: ExecutionResult,
+}
+
+type RestartCallback<'a> = Box<dyn FnMut(usize, &ExecutionResult) + 'a>;
+
+/// Keeps a long-running helper command running, restarting it with backoff when it crashes.
+///
+/// A crash is any exit that is not successful, i.e. a non-zero exit code or being terminated
+/// by a signal. A clean exit with code 0 means that the command is done, so it is not
+/// restarted, and [`Self::run`] returns its result. Each run uses [`CommandOpts`] as
+/// given, so consider not capturing the output of commands that run for a long time.
+pub struct SupervisedProcess<'a> {
+    command_line: CommandLine,
+    cwd: AbsolutePathBuf,
+    opts: CommandOpts,
+    policy: RestartPolicy,
+    on_restart: Option<RestartCallback<'a>>,
+}
+
+impl<'a> SupervisedProcess<'a> {
+    pub fn new<P: AsRef<AbsolutePath>, C: Into<CommandLine>>(
+        command_line: C,
+        cwd: P,
+        opts: CommandOpts,
+        policy: RestartPolicy,
+    ) -> Self {
+        Self {
+            command_line: command_line.into(),
+            cwd: AbsolutePathBuf::from(cwd.as_ref()),
+            opts,
+            policy,
+            on_restart: None,
+        }
+    }
+
+    /// Call `f` before each restart, with the restart's 1-based number, and the result of
+    /// the run that crashed.
+    pub fn on_restart<F: FnMut(usize, &ExecutionResult) + 'a>(mut self, f: F) -> Self {
+        self.on_restart = Some(Box::new(f));
+        self
+    }
+
+    /// Run the command with `runner` until it exits cleanly, restarting it whenever it
+    /// crashes.
+    ///
+    /// This blocks until the command exits cleanly, or fails with [`RestartsExhausted`] once
+    /// it has crashed after the last restart that the policy allows. Failing to spawn the
+    /// command is not a crash, and is returned right away.
+    pub fn run(&mut self, runner: &DefaultCommandRunner) -> anyhow::Result<ExecutionResult> {
+        let program = self.command_line.program()?.to_owned();
+        let mut backoff = self.policy.initial_backoff;
+        let mut restarts = 0;
+        loop {
+            let result = runner
+                .spawn(self.command_line.clone(), &self.cwd, self.opts.clone())?
+                .wait()?;
+            if result.status.success() {
+                return Ok(result);
+            }
+            if restarts == self.policy.max_restarts {
+                return Err(RestartsExhausted {
+                    program,
+                    restarts,
+                    result,
+                }
+                .into());
+            }
+            restarts += 1;
+            log::warn!(
+                "`{}` crashed with status `{}`, restarting it in {:?} ({} of {})",
+                program,
+                result.status,
+                backoff,
+                restarts,
+                self.policy.max_restarts
+            );
+            if let Some(on_restart) = self.on_restart.as_mut() {
+                on_restart(restarts, &result);
+            }
+            std::thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2).min(self.policy.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use paths::AbsolutePath;
+    use paths::AbsolutePathBuf;
+
+    use crate::CommandOpts;
+    use crate::DefaultCommandRunner;
+    use crate::RestartPolicy;
+    use crate::RestartsExhausted;
+    use crate::SupervisedProcess;
+
+    fn policy() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn restarts_until_clean_exit() -> anyhow::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let mut restarts = vec![];
+        let res = SupervisedProcess::new(
+            [
+                "/bin/sh",
+                "-c",
+                "[ -f crashed ] && exit 0; touch crashed; kill -9 $$",
+            ],
+            AbsolutePath::try_new(temp.path())?,
+            CommandOpts::default(),
+            policy(),
+        )
+        .on_restart(|i, res| restarts.push((i, res.signal())))
+        .run(&DefaultCommandRunner::default())?;
+
+        assert!(res.success());
+        assert_eq!(vec![(1, Some(libc::SIGKILL))], restarts);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_after_max_restarts() -> anyhow::Result<()> {
+        let mut restarts = 0;
+        let err = SupervisedProcess::new(
+            ["/bin/sh", "-c", "exit 3"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+            policy(),
+        )
+        .on_restart(|_, _| restarts += 1)
+        .run(&DefaultCommandRunner::default())
+        .unwrap_err()
+        .downcast::<RestartsExhausted>()?;
+
+        assert_eq!(2, restarts);
+        assert_eq!(2, err.restarts);
+        assert_eq!(Some(3), err.result.code());
+        Ok(())
+    }
+}
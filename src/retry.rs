@@ -0,0 +1,82 @@
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ExecutionResult;
+use crate::ProgramNotFound;
+
+type RetryPredicate = Arc<dyn Fn(&ExecutionResult) -> bool + Send + Sync>;
+type RetryErrorPredicate = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// When and how often [`crate::CommandRunner::run_with_retries`] runs a command again, for
+/// commands that can fail transiently, like `git fetch`.
+///
+/// By default, a command is retried whenever it exits unsuccessfully. Errors from running
+/// the command at all, e.g. [`crate::CommandTimeout`], are retried too, except for
+/// [`ProgramNotFound`], which running the command again does not fix.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// How many times to run the command in total, including the first attempt.
+    pub max_attempts: usize,
+    /// How long to wait between attempts.
+    pub backoff: Duration,
+    retry_if: RetryPredicate,
+    retry_errors_if: RetryErrorPredicate,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize, backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            retry_if: Arc::new(|res| !res.success()),
+            retry_errors_if: Arc::new(|e| !e.is::<ProgramNotFound>()),
+        }
+    }
+
+    /// Only retry results that `f` returns true for, e.g. exit codes that mean a network
+    /// error, rather than all unsuccessful ones.
+    pub fn retry_if<F: Fn(&ExecutionResult) -> bool + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.retry_if = Arc::new(f);
+        self
+    }
+
+    /// Only retry errors from running the command that `f` returns true for, e.g. to not
+    /// retry [`crate::CommandTimeout`], rather than all but [`ProgramNotFound`].
+    pub fn retry_errors_if<F: Fn(&anyhow::Error) -> bool + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.retry_errors_if = Arc::new(f);
+        self
+    }
+
+    /// Whether the command should be run again after it gave `res`.
+    pub fn should_retry(&self, res: &ExecutionResult) -> bool {
+        (self.retry_if)(res)
+    }
+
+    /// Whether the command should be run again after running it failed with `e`.
+    pub fn should_retry_error(&self, e: &anyhow::Error) -> bool {
+        (self.retry_errors_if)(e)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(1))
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,205 @@
+//! Forwarding signals that this process receives to the commands that it runs.
+
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Once;
+
+/// How signals that are sent to this process reach a command, per
+/// [`crate::CommandOpts::signal_forwarding`].
+///
+/// Every mode but [`Self::Inherit`] starts the command in a new process group, so that signals
+/// a terminal sends to this process' group (e.g. `SIGINT` on Ctrl-C) do not reach it directly.
+/// A command in its own group is in the background as far as the terminal is concerned, so it
+/// is stopped if it tries to read from the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignalForwarding {
+    /// The command shares this process' group, so it gets the same signals from the terminal
+    /// as this process does. This is what happens without any configuration.
+    #[default]
+    Inherit,
+    /// The command gets none of this process' signals, e.g. for a server that should keep
+    /// running after the tool that started it is interrupted.
+    Ignore,
+    /// `SIGINT`, `SIGTERM`, `SIGHUP`, and `SIGQUIT` that this process receives are sent on to
+    /// the command's process group.
+    Forward,
+    /// Like [`Self::Forward`], but `SIGINT` is sent on as `SIGTERM`, so that the command is
+    /// asked to shut down gracefully when this process is interrupted.
+    InterruptAsTerminate,
+}
+
+impl SignalForwarding {
+    pub(crate) fn new_process_group(self) -> bool {
+        self != SignalForwarding::Inherit
+    }
+}
+
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+/// How many commands can have signals forwarded to them at once.
+const MAX_GROUPS: usize = 64;
+
+// These are read from the signal handler, so they are fixed-size atomics rather than anything
+// that needs a lock or allocation. Each slot in `GROUPS` is empty (0), a group to forward to
+// as is (its pgid), or a group to send `SIGINT` to as `SIGTERM` (its negated pgid).
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_GROUP: AtomicI32 = AtomicI32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FLAGS: AtomicI32 = AtomicI32::new(0);
+static GROUPS: [AtomicI32; MAX_GROUPS] = [NO_GROUP; MAX_GROUPS];
+// The `sa_sigaction` and `sa_flags` of the handlers that were installed before ours.
+static PREVIOUS_HANDLERS: [AtomicUsize; FORWARDED_SIGNALS.len()] =
+    [NO_HANDLER; FORWARDED_SIGNALS.len()];
+static PREVIOUS_FLAGS: [AtomicI32; FORWARDED_SIGNALS.len()] = [NO_FLAGS; FORWARDED_SIGNALS.len()];
+static INSTALL: Once = Once::new();
+
+/// Keeps forwarding signals to a process group until it is dropped.
+#[derive(Debug)]
+pub(crate) struct ForwardedGroup(usize);
+
+impl Drop for ForwardedGroup {
+    fn drop(&mut self) {
+        GROUPS[self.0].store(0, Ordering::SeqCst);
+    }
+}
+
+/// Start forwarding signals to the process group `pgid`, per `mode`.
+///
+/// Returns `None` if `mode` does not forward signals.
+pub(crate) fn forward_to(
+    pgid: i32,
+    mode: SignalForwarding,
+) -> anyhow::Result<Option<ForwardedGroup>> {
+    let entry = match mode {
+        SignalForwarding::Inherit | SignalForwarding::Ignore => return Ok(None),
+        SignalForwarding::Forward => pgid,
+        SignalForwarding::InterruptAsTerminate => -pgid,
+    };
+    install_handlers()?;
+    for (slot, group) in GROUPS.iter().enumerate() {
+        if group
+            .compare_exchange(0, entry, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return Ok(Some(ForwardedGroup(slot)));
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Cannot forward signals to more than {} commands at once",
+        MAX_GROUPS
+    ))
+}
+
+fn install_handlers() -> anyhow::Result<()> {
+    let mut res = Ok(());
+    INSTALL.call_once(|| {
+        for (i, signal) in FORWARDED_SIGNALS.iter().enumerate() {
+            // SAFETY: `sigaction` is plain data, for which all zeroes is valid.
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = handle as libc::sighandler_t;
+            // `SA_SIGINFO` so that `handle` gets what it needs to chain to handlers that also
+            // use it, and `SA_RESTART`, like `signal`, so that other threads are not
+            // interrupted.
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            // SAFETY: `handle` only uses async-signal-safe functions and atomics, and both
+            // structs are valid for the call.
+            let ret = unsafe {
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(*signal, &action, &mut previous)
+            };
+            if ret != 0 {
+                res = Err(std::io::Error::last_os_error().into());
+                return;
+            }
+            PREVIOUS_FLAGS[i].store(previous.sa_flags, Ordering::SeqCst);
+            PREVIOUS_HANDLERS[i].store(previous.sa_sigaction, Ordering::SeqCst);
+        }
+    });
+    res
+}
+
+/// Forward `signal` to every registered group. If there are none, behave as this process
+/// would have without forwarding.
+extern "C" fn handle(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let mut forwarded = false;
+    for group in GROUPS.iter() {
+        let entry = group.load(Ordering::SeqCst);
+        if entry != 0 {
+            let pgid = entry.abs();
+            let sent = if signal == libc::SIGINT && entry < 0 {
+                libc::SIGTERM
+            } else {
+                signal
+            };
+            // SAFETY: `kill` is async-signal-safe.
+            unsafe { libc::kill(-pgid, sent) };
+            forwarded = true;
+        }
+    }
+    if forwarded {
+        return;
+    }
+    let (previous, flags) = FORWARDED_SIGNALS
+        .iter()
+        .position(|s| *s == signal)
+        .map(|i| {
+            (
+                PREVIOUS_HANDLERS[i].load(Ordering::SeqCst),
+                PREVIOUS_FLAGS[i].load(Ordering::SeqCst),
+            )
+        })
+        .unwrap_or((libc::SIG_DFL, 0));
+    if previous == libc::SIG_DFL {
+        // SAFETY: `sigaction` and `raise` are async-signal-safe. The default action for all of
+        // the forwarded signals is to terminate, which happens once this handler returns.
+        unsafe {
+            let mut default: libc::sigaction = std::mem::zeroed();
+            default.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(signal, &default, std::ptr::null_mut());
+            libc::raise(signal);
+        }
+    } else if previous != libc::SIG_IGN && flags & libc::SA_SIGINFO != 0 {
+        // SAFETY: `previous` was installed with `SA_SIGINFO` for this signal, so it takes the
+        // same arguments as this handler.
+        let previous: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            unsafe { std::mem::transmute(previous) };
+        previous(signal, info, context);
+    } else if previous != libc::SIG_IGN {
+        // SAFETY: `previous` was installed as a plain handler for this signal.
+        let previous: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(previous) };
+        previous(signal);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use paths::AbsolutePathBuf;
+
+    use crate::CommandOpts;
+    use crate::DefaultCommandRunner;
+    use crate::SignalForwarding;
+
+    #[test]
+    fn ignore_uses_new_process_group() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let mut handle = runner.spawn(
+            ["sleep", "10"],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts {
+                signal_forwarding: SignalForwarding::Ignore,
+                ..Default::default()
+            },
+        )?;
+        // SAFETY: `getpgid` has no preconditions.
+        let pgid = unsafe { libc::getpgid(handle.pid() as libc::pid_t) };
+        assert_eq!(handle.pid() as libc::pid_t, pgid);
+        handle.kill()?;
+        handle.wait()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+
+/// A [`CommandRunner`] that runs every command with `sudo`.
+///
+/// This is meant to wrap a runner for the subset of commands that need elevated privileges,
+/// while the rest are run unprivileged with the unwrapped runner. `sudo` resets the
+/// environment by default, so any [`CommandOpts::env`] is passed through `env` on the command
+/// line instead, as [`crate::SudoFallbackCommandRunner`] does. The variables are redacted in
+/// logs, but anyone who can list processes (e.g. with `ps`) can see them while the command
+/// runs, so secrets are better passed some other way, like through a file.
+#[derive(Debug)]
+pub struct SudoCommandRunner<R: CommandRunner> {
+    inner: R,
+    sudo: PathBuf,
+    non_interactive: bool,
+}
+
+impl<R: CommandRunner> SudoCommandRunner<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            sudo: PathBuf::from("sudo"),
+            non_interactive: false,
+        }
+    }
+
+    /// Use the `sudo` at `path`, e.g. for installs outside of `PATH`.
+    pub fn with_sudo_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sudo = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Pass `-n` to `sudo`, so that commands fail instead of prompting for a password.
+    pub fn non_interactive(mut self, non_interactive: bool) -> Self {
+        self.non_interactive = non_interactive;
+        self
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn elevated(&self, command_line: &CommandLine, opts: &CommandOpts) -> CommandLine {
+        let mut elevated = CommandLine::from([self.sudo.to_string_lossy()]);
+        if self.non_interactive {
+            elevated.push("-n");
+        }
+        elevated.extend(command_line.with_env_map_prefix(&opts.env));
+        elevated
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for SudoCommandRunner<R> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let elevated = self.elevated(&command_line, &opts);
+        self.inner.run_inner(elevated, cwd, opts)
+    }
+
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let elevated = commands
+            .iter()
+            .map(|command_line| self.elevated(command_line, &opts))
+            .collect();
+        self.inner.run_pipeline(elevated, cwd, opts)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        let elevated = self.elevated(&command_line, &opts);
+        self.inner.exec_with_opts(elevated, cwd, opts)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.inner.default_cwd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use maplit::hashmap;
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::SudoCommandRunner;
+
+    #[test]
+    fn prefixes_sudo() -> anyhow::Result<()> {
+        let cwd = AbsolutePathBuf::current_dir();
+        let inner = TestCommandRunner::with_results([(0, ""); 5])?;
+        let runner = SudoCommandRunner::new(inner);
+        runner.run(["systemctl", "restart", "foo"], &cwd)?;
+        let opts = CommandOpts {
+            env: hashmap! {
+                "FOO".to_owned() => "bar".to_owned(),
+                "BAR".to_owned() => "baz".to_owned(),
+                "QUX".to_owned() => "quux".to_owned(),
+            },
+            ..Default::default()
+        };
+        runner.run_with_opts(["ls"], &cwd, opts)?;
+        let runner = runner.with_sudo_path("/opt/bin/sudo").non_interactive(true);
+        runner.run(["ls"], &cwd)?;
+        let commands = vec![CommandLine::from(["ls"]), CommandLine::from(["wc", "-l"])];
+        runner.run_pipeline(commands, &cwd, CommandOpts::default())?;
+
+        let issued = runner.inner().issued_commands.read().unwrap();
+        assert_eq!(["sudo", "systemctl", "restart", "foo"], **issued[0]);
+        assert_eq!(
+            ["sudo", "env", "BAR=baz", "FOO=bar", "QUX=quux", "ls"],
+            **issued[1]
+        );
+        assert_eq!("sudo env **** **** **** ls", issued[1].to_string());
+        assert_eq!(["/opt/bin/sudo", "-n", "ls"], **issued[2]);
+        assert_eq!(["/opt/bin/sudo", "-n", "ls"], **issued[3]);
+        assert_eq!(["/opt/bin/sudo", "-n", "wc", "-l"], **issued[4]);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use std::io::ErrorKind;
+
+use itertools::Itertools;
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+
+/// A [`CommandRunner`] that re-runs commands with `sudo` if they fail for lack of permission.
+///
+/// A command is retried once, prefixed with `sudo`, if it could not be started because
+/// permission was denied (`EACCES`), if it exits with one of the configured exit codes, or if
+/// its stderr contains one of the configured patterns. By default no exit codes are
+/// configured, and the patterns are the messages for `EACCES` and `EPERM`. Stderr can only be
+/// matched if the command's stderr is captured.
+///
+/// This is opt-in for a reason: `sudo` may prompt for a password on the terminal, and the
+/// retried command runs with elevated privileges. `sudo` resets the environment by default,
+/// so any [`CommandOpts::env`] is passed through `env` on the command line instead, where it
+/// is redacted in logs but visible to anyone who can list processes. Commands that were
+/// `exec`'d are never retried. Pipelines are retried as a whole, with every command in them
+/// elevated, based on the result of the final command.
+#[derive(Debug)]
+pub struct SudoFallbackCommandRunner<R: CommandRunner> {
+    inner: R,
+    sudo: CommandLine,
+    exit_codes: Vec<i32>,
+    stderr_patterns: Vec<String>,
+}
+
+impl<R: CommandRunner> SudoFallbackCommandRunner<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            sudo: CommandLine::from(["sudo"]),
+            exit_codes: vec![],
+            stderr_patterns: vec![
+                "Permission denied".to_owned(),
+                "Operation not permitted".to_owned(),
+            ],
+        }
+    }
+
+    /// Use a different prefix to elevate privileges, e.g. `["sudo", "-n"]` to fail instead of
+    /// prompting for a password.
+    pub fn with_sudo<C: Into<CommandLine>>(mut self, sudo: C) -> Self {
+        self.sudo = sudo.into();
+        self
+    }
+
+    /// Retry commands that exit with any of these codes.
+    pub fn with_exit_codes(mut self, exit_codes: &[i32]) -> Self {
+        self.exit_codes = exit_codes.to_vec();
+        self
+    }
+
+    /// Retry commands whose stderr contains any of these strings.
+    pub fn with_stderr_patterns<S: ToString>(mut self, patterns: &[S]) -> Self {
+        self.stderr_patterns = patterns.iter().map(S::to_string).collect();
+        self
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn elevated(&self, command_line: &CommandLine, opts: &CommandOpts) -> CommandLine {
+        let mut elevated = self.sudo.clone();
+        elevated.extend(command_line.with_env_map_prefix(&opts.env));
+        elevated
+    }
+
+    fn should_retry(&self, res: &anyhow::Result<ExecutionResult>) -> bool {
+        match res {
+            Ok(res) if res.status.success() => false,
+            Ok(res) => {
+                let stderr = String::from_utf8_lossy(&res.stderr);
+                res.status
+                    .code()
+                    .is_some_and(|code| self.exit_codes.contains(&code))
+                    || self.stderr_patterns.iter().any(|p| stderr.contains(p))
+            }
+            Err(e) => e
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == ErrorKind::PermissionDenied),
+        }
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for SudoFallbackCommandRunner<R> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let res = self
+            .inner
+            .run_inner(command_line.clone(), cwd, opts.clone());
+        if !self.should_retry(&res) {
+            return res;
+        }
+        log::warn!(
+            "{}`{}` failed for lack of permission, retrying with `{}`",
+            opts.log_label(),
+            command_line,
+            self.sudo
+        );
+        let elevated = self.elevated(&command_line, &opts);
+        self.inner.run_inner(elevated, cwd, opts)
+    }
+
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let cwd = cwd.as_ref();
+        let res = self.inner.run_pipeline(commands.clone(), cwd, opts.clone());
+        if !self.should_retry(&res) {
+            return res;
+        }
+        log::warn!(
+            "{}Pipeline `{}` failed for lack of permission, retrying with `{}`",
+            opts.log_label(),
+            commands.iter().join(" | "),
+            self.sudo
+        );
+        let elevated = commands
+            .iter()
+            .map(|command_line| self.elevated(command_line, &opts))
+            .collect();
+        self.inner.run_pipeline(elevated, cwd, opts)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        self.inner.exec_with_opts(command_line, cwd, opts)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.inner.default_cwd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::SudoFallbackCommandRunner;
+
+    fn output(code: i32, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: vec![],
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn retries_with_sudo() -> anyhow::Result<()> {
+        let cwd = AbsolutePathBuf::current_dir();
+        let inner = TestCommandRunner::new();
+        inner.outputs.write().unwrap().extend([
+            output(1, "rm: cannot remove 'x': Permission denied"),
+            output(0, ""),
+            output(1, "rm: cannot remove 'x': No such file or directory"),
+            output(0, ""),
+            output(77, ""),
+            output(0, ""),
+            output(0, ""),
+            output(1, "wc: Permission denied"),
+            output(0, ""),
+            output(0, ""),
+        ]);
+        let runner = SudoFallbackCommandRunner::new(inner).with_exit_codes(&[77]);
+
+        assert!(runner.run(["rm", "x"], &cwd)?.status.success());
+        assert!(!runner.run(["rm", "x"], &cwd)?.status.success());
+        assert!(runner.run(["ls"], &cwd)?.status.success());
+        assert!(runner.run(["ls"], &cwd)?.status.success());
+        let commands = vec![CommandLine::from(["cat", "x"]), CommandLine::from(["wc"])];
+        let res = runner.run_pipeline(commands, &cwd, CommandOpts::default())?;
+        assert!(res.status.success());
+
+        let issued = runner.inner().issued_commands.read().unwrap();
+        let issued: Vec<_> = issued.iter().map(|i| i.to_vec()).collect();
+        assert_eq!(
+            vec![
+                vec!["rm", "x"],
+                vec!["sudo", "rm", "x"],
+                vec!["rm", "x"],
+                vec!["ls"],
+                vec!["ls"],
+                vec!["sudo", "ls"],
+                vec!["cat", "x"],
+                vec!["wc"],
+                vec!["sudo", "cat", "x"],
+                vec!["sudo", "wc"],
+            ],
+            issued
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::CommandHandle;
+use crate::ExecutionResult;
+use crate::Stream;
+
+/// How often limits are checked while a supervised command is quiet.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits for [`crate::DefaultCommandRunner::run_supervised`].
+///
+/// Every limit is optional, and the first one to be hit ends the command.
+#[derive(Debug, Clone, Default)]
+pub struct SupervisionOpts {
+    /// Kill the command if it is still running after this long.
+    pub timeout: Option<Duration>,
+    /// Kill the command once it has written more than this many bytes to stdout and stderr
+    /// combined.
+    pub max_output_bytes: Option<usize>,
+    /// Kill the command once this is set to `true`, e.g. from another thread.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// Why a supervised command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisedOutcome {
+    /// The command exited on its own.
+    Completed,
+    /// The command was killed because it ran for longer than [`SupervisionOpts::timeout`].
+    TimedOut,
+    /// The command was killed because it wrote more than
+    /// [`SupervisionOpts::max_output_bytes`].
+    OutputLimitExceeded,
+    /// The command was killed because [`SupervisionOpts::cancel`] was set.
+    Cancelled,
+}
+
+/// Watch over `handle` until it exits or hits one of the limits in `opts`, passing every
+/// chunk of output from `rx` to `on_output`.
+pub(crate) fn supervise<F: FnMut(Stream, &[u8])>(
+    mut handle: CommandHandle,
+    rx: Receiver<(Stream, Vec<u8>)>,
+    opts: &SupervisionOpts,
+    mut on_output: F,
+) -> anyhow::Result<(SupervisedOutcome, ExecutionResult)> {
+    let deadline = opts.timeout.map(|timeout| Instant::now() + timeout);
+    let mut output_bytes = 0;
+    let mut streams_open = true;
+    let outcome = loop {
+        if streams_open {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok((stream, chunk)) => {
+                    output_bytes += chunk.len();
+                    on_output(stream, &chunk);
+                    if opts.max_output_bytes.is_some_and(|max| output_bytes > max) {
+                        break SupervisedOutcome::OutputLimitExceeded;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => streams_open = false,
+            }
+        } else if handle.try_wait()?.is_some() {
+            break SupervisedOutcome::Completed;
+        } else {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        if opts
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+        {
+            break SupervisedOutcome::Cancelled;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break SupervisedOutcome::TimedOut;
+        }
+    };
+
+    if outcome != SupervisedOutcome::Completed {
+        log::info!("Killing `{}`: {:?}", handle.program(), outcome);
+        handle.kill()?;
+    }
+    // Output that was already read is still handed over, so that it is not lost.
+    for (stream, chunk) in rx.iter() {
+        on_output(stream, &chunk);
+    }
+    Ok((outcome, handle.wait()?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use paths::AbsolutePathBuf;
+
+    use crate::CommandOpts;
+    use crate::DefaultCommandRunner;
+    use crate::Stream;
+    use crate::SupervisedOutcome;
+    use crate::SupervisionOpts;
+
+    fn run(
+        shell: &str,
+        supervision: SupervisionOpts,
+    ) -> anyhow::Result<(SupervisedOutcome, Vec<u8>, crate::ExecutionResult)> {
+        let mut streamed = vec![];
+        let (outcome, res) = DefaultCommandRunner::default().run_supervised(
+            ["/bin/sh", "-c", shell],
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+            supervision,
+            |stream, chunk| {
+                if stream == Stream::Stdout {
+                    streamed.extend_from_slice(chunk)
+                }
+            },
+        )?;
+        Ok((outcome, streamed, res))
+    }
+
+    #[test]
+    fn completes() -> anyhow::Result<()> {
+        let (outcome, streamed, res) = run(
+            "echo out; echo err >&2",
+            SupervisionOpts {
+                timeout: Some(Duration::from_secs(60)),
+                max_output_bytes: Some(1000),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::Completed, outcome);
+        assert_eq!(b"out\n".to_vec(), streamed);
+        assert_eq!("out\n", res.stdout()?);
+        assert_eq!(b"err\n".to_vec(), res.stderr);
+        assert!(res.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn times_out_with_partial_output() -> anyhow::Result<()> {
+        let start = Instant::now();
+        let (outcome, streamed, res) = run(
+            "echo started; exec sleep 10",
+            SupervisionOpts {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::TimedOut, outcome);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(b"started\n".to_vec(), streamed);
+        assert_eq!("started\n", res.stdout()?);
+        assert!(!res.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn kills_children_holding_output() -> anyhow::Result<()> {
+        let start = Instant::now();
+        // `sleep` keeps stdout open for as long as it runs, even once the shell is killed.
+        let (outcome, streamed, res) = run(
+            "echo started; sleep 10; echo done",
+            SupervisionOpts {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::TimedOut, outcome);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(b"started\n".to_vec(), streamed);
+        assert!(!res.status.success());
+
+        let start = Instant::now();
+        let (outcome, _, _) = run(
+            "sleep 10; echo done",
+            SupervisionOpts {
+                cancel: Some(Arc::new(AtomicBool::new(true))),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::Cancelled, outcome);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn stops_at_output_limit() -> anyhow::Result<()> {
+        let (outcome, streamed, res) = run(
+            "exec yes",
+            SupervisionOpts {
+                max_output_bytes: Some(1000),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::OutputLimitExceeded, outcome);
+        assert!(streamed.len() > 1000);
+        assert_eq!(streamed.len(), res.stdout.len());
+        Ok(())
+    }
+
+    #[test]
+    fn cancels() -> anyhow::Result<()> {
+        let (outcome, _, res) = run(
+            "exec sleep 10",
+            SupervisionOpts {
+                cancel: Some(Arc::new(AtomicBool::new(true))),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(SupervisedOutcome::Cancelled, outcome);
+        assert!(!res.status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn samples_output() -> anyhow::Result<()> {
+        let runner = DefaultCommandRunner::default();
+        let cwd = AbsolutePathBuf::current_dir();
+
+        let (outcome, res) = runner.sample_output(
+            ["/bin/sh", "-c", "echo first; exec sleep 10"],
+            &cwd,
+            CommandOpts::default(),
+            6,
+            Duration::from_secs(60),
+        )?;
+        assert_eq!(SupervisedOutcome::OutputLimitExceeded, outcome);
+        assert_eq!("first\n", res.stdout()?);
+
+        let (outcome, res) = runner.sample_output(
+            ["/bin/sh", "-c", "echo first; exec sleep 10"],
+            &cwd,
+            CommandOpts::default(),
+            1000,
+            Duration::from_millis(200),
+        )?;
+        assert_eq!(SupervisedOutcome::TimedOut, outcome);
+        assert_eq!("first\n", res.stdout()?);
+        Ok(())
+    }
+}
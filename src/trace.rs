@@ -0,0 +1,173 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use paths::AbsolutePath;
+use paths::AbsolutePathBuf;
+
+use crate::CommandLine;
+use crate::CommandOpts;
+use crate::CommandRunner;
+use crate::ExecutionResult;
+
+/// A [`CommandRunner`] that runs every command under a tracer like `strace`, to help debug
+/// commands that fail mysteriously.
+///
+/// Each command is run as `<tracer> <output> <command...>`, where the tracer defaults to
+/// `strace -f -o`, so the trace is written to `output` instead of the command's stderr.
+/// The command's own stdin, stdout, and stderr are otherwise left alone, although the
+/// exit status is the tracer's (`strace` exits with the status of the traced command).
+///
+/// Every command overwrites the trace in `output`, so this is mostly useful when wrapping
+/// the one command that is being debugged. The commands of a pipeline run at the same time,
+/// so each is traced to its own file instead, `output.0` for the first one and so on.
+#[derive(Debug)]
+pub struct TracingExecCommandRunner<R: CommandRunner> {
+    inner: R,
+    tracer: CommandLine,
+    output: PathBuf,
+}
+
+impl<R: CommandRunner> TracingExecCommandRunner<R> {
+    pub fn new<P: AsRef<AbsolutePath>>(inner: R, output: P) -> Self {
+        let output: &Path = output.as_ref().as_ref();
+        Self {
+            inner,
+            tracer: CommandLine::from(["strace", "-f", "-o"]),
+            output: output.to_path_buf(),
+        }
+    }
+
+    /// Use a different tracer, e.g. `["ltrace", "-f", "-o"]`.
+    ///
+    /// The tracer must accept the path to write the trace to as its final argument before
+    /// the command to trace.
+    pub fn with_tracer<C: Into<CommandLine>>(mut self, tracer: C) -> Self {
+        self.tracer = tracer.into();
+        self
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn traced(&self, command_line: CommandLine, label: &str) -> CommandLine {
+        self.traced_to(command_line, label, &self.output)
+    }
+
+    fn traced_to(&self, command_line: CommandLine, label: &str, output: &Path) -> CommandLine {
+        log::info!(
+            "Tracing {}`{}` to `{}`",
+            label,
+            command_line,
+            output.display()
+        );
+        let mut traced = self.tracer.clone_with([output.to_string_lossy()]);
+        traced.extend(command_line);
+        traced
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for TracingExecCommandRunner<R> {
+    fn run_inner(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let traced = self.traced(command_line, &opts.log_label());
+        self.inner.run_inner(traced, cwd, opts)
+    }
+
+    fn run_pipeline<P: AsRef<AbsolutePath>>(
+        &self,
+        commands: Vec<CommandLine>,
+        cwd: P,
+        opts: CommandOpts,
+    ) -> anyhow::Result<ExecutionResult> {
+        let label = opts.log_label();
+        let traced = commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command_line)| {
+                let mut output = self.output.clone().into_os_string();
+                output.push(format!(".{}", i));
+                self.traced_to(command_line, &label, Path::new(&output))
+            })
+            .collect();
+        self.inner.run_pipeline(traced, cwd, opts)
+    }
+
+    fn exec_with_opts(
+        &self,
+        command_line: CommandLine,
+        cwd: &AbsolutePath,
+        opts: CommandOpts,
+    ) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        let traced = self.traced(command_line, &opts.log_label());
+        self.inner.exec_with_opts(traced, cwd, opts)
+    }
+
+    fn root_systemd_path(&self) -> AbsolutePathBuf {
+        self.inner.root_systemd_path()
+    }
+
+    fn user_systemd_path(&self) -> anyhow::Result<AbsolutePathBuf> {
+        self.inner.user_systemd_path()
+    }
+
+    fn hostname(&self) -> anyhow::Result<String> {
+        self.inner.hostname()
+    }
+
+    fn username(&self) -> anyhow::Result<String> {
+        self.inner.username()
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        self.inner.var(name)
+    }
+
+    fn default_cwd(&self) -> AbsolutePathBuf {
+        self.inner.default_cwd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use paths::AbsolutePathBuf;
+
+    use crate::test::TestCommandRunner;
+    use crate::CommandLine;
+    use crate::CommandOpts;
+    use crate::CommandRunner;
+    use crate::TracingExecCommandRunner;
+
+    #[test]
+    fn prefixes_tracer() -> anyhow::Result<()> {
+        let output = AbsolutePathBuf::try_new("/tmp/trace.txt")?;
+        let inner = TestCommandRunner::with_results([(0, ""); 4])?;
+        let runner = TracingExecCommandRunner::new(inner, &output);
+        runner.run(["git", "status"], AbsolutePathBuf::current_dir())?;
+        let runner = runner.with_tracer(["ltrace", "-o"]);
+        runner.run(["ls"], AbsolutePathBuf::current_dir())?;
+        let commands = vec![CommandLine::from(["ls"]), CommandLine::from(["wc"])];
+        runner.run_pipeline(
+            commands,
+            AbsolutePathBuf::current_dir(),
+            CommandOpts::default(),
+        )?;
+
+        let issued = runner.inner().issued_commands.read().unwrap();
+        assert_eq!(
+            ["strace", "-f", "-o", "/tmp/trace.txt", "git", "status"],
+            **issued[0]
+        );
+        assert_eq!(["ltrace", "-o", "/tmp/trace.txt", "ls"], **issued[1]);
+        assert_eq!(["ltrace", "-o", "/tmp/trace.txt.0", "ls"], **issued[2]);
+        assert_eq!(["ltrace", "-o", "/tmp/trace.txt.1", "wc"], **issued[3]);
+        Ok(())
+    }
+}
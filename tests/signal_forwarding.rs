@@ -0,0 +1,70 @@
+//! Tests that send signals to this process, so they run in their own test binary, where no
+//! other test can be forwarding signals at the same time.
+
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use commands::paths::AbsolutePathBuf;
+use commands::CommandOpts;
+use commands::DefaultCommandRunner;
+use commands::SignalForwarding;
+use commands::StreamingOpts;
+
+static HANGUP_SIGNO: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn record_hangup(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // SAFETY: The kernel passes a valid `siginfo_t` to handlers installed with `SA_SIGINFO`.
+    HANGUP_SIGNO.store(unsafe { (*info).si_signo }, Ordering::SeqCst);
+}
+
+#[test]
+fn forwards_and_chains_signals() -> anyhow::Result<()> {
+    // Install a handler that needs its `siginfo_t` before forwarding is first set up, so that
+    // forwarding has to chain to it.
+    // SAFETY: `sigaction` is plain data, for which all zeroes is valid, and `record_hangup`
+    // only uses atomics.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = record_hangup as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(
+            0,
+            libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+        );
+    }
+
+    let runner = DefaultCommandRunner::default();
+    let (handle, rx) = runner.spawn_streaming(
+        [
+            "/bin/sh",
+            "-c",
+            "trap 'echo term; exit 0' TERM; echo ready; while :; do sleep 0.01; done",
+        ],
+        AbsolutePathBuf::current_dir(),
+        CommandOpts {
+            signal_forwarding: SignalForwarding::InterruptAsTerminate,
+            ..Default::default()
+        },
+        StreamingOpts {
+            flush_interval: Duration::from_millis(1),
+            ..Default::default()
+        },
+    )?;
+    let (_, ready) = rx.recv()?;
+    assert_eq!(b"ready\n".to_vec(), ready);
+
+    // This process survives, as the signal is forwarded rather than handled normally.
+    // SAFETY: `raise` has no preconditions.
+    unsafe { libc::raise(libc::SIGINT) };
+    let (_, term) = rx.recv()?;
+    assert_eq!(b"term\n".to_vec(), term);
+    assert!(handle.wait()?.status.success());
+
+    // Nothing is forwarded to anymore, so this goes to the previous handler.
+    // SAFETY: `raise` has no preconditions.
+    unsafe { libc::raise(libc::SIGHUP) };
+    assert_eq!(libc::SIGHUP, HANGUP_SIGNO.load(Ordering::SeqCst));
+    Ok(())
+}